@@ -41,7 +41,7 @@ impl BufCache {
         let block_no = address / 128;
         let data = self.translation_layer.read(block_no);
         for (index, page) in data.iter().enumerate() {
-            self.put_data(address + index as u32, page);
+            self.put_data(block_no * 128 + index as u32, page);
         }
         self.get_data(address).unwrap()
     }
@@ -77,6 +77,15 @@ impl BufCache {
         self.translation_layer.write_block_direct(block_no, data);
     }
 
+    /// Flush pending writes to disk
+    /// params:
+    /// ()
+    /// return:
+    /// ()
+    pub fn sync(&mut self) {
+        self.translation_layer.flush();
+    }
+
     /// Erase block
     /// params:
     /// dev - device number
@@ -111,6 +120,10 @@ impl BufCache {
         self.translation_layer.set_max_block_no(max_block_no);
     }
 
+    pub fn get_spare_block_num(&self) -> u32 {
+        self.translation_layer.get_spare_block_num()
+    }
+
     pub fn init_translation_layer(&mut self) {
         self.translation_layer.init();
     }
@@ -174,5 +187,12 @@ mod test {
         cache.erase(0, 0);
         let data = cache.read(0, 100);
         assert_eq!(data, [0; 4096]);
+
+        // a miss loads the whole block without touching its neighbours
+        cache.write(0, 130, [2; 4096]);
+        cache.remove_data(100);
+        cache.read(0, 100);
+        assert_eq!(cache.get_data(0), Some([0; 4096]));
+        assert_eq!(cache.get_data(130), Some([2; 4096]));
     }
 }
\ No newline at end of file
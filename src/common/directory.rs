@@ -1,34 +1,26 @@
 use crate::inode::inode;
 use crate::inode::inode_manager;
 
+// Max file name length stored in a directory entry
+pub const DIRECTORY_NAME_MAX: usize = 10;
+
+// Size of a directory entry, 4 bytes ino and the name
+pub const DIRECTORY_ENTRY_SIZE: usize = 4 + DIRECTORY_NAME_MAX;
+
 // Look for a directory entry in a directory
 pub fn dir_lookup(inode: &inode_manager::InodeLink, name: String) -> Option<(u32, usize)> {
-    if inode.borrow().file_type != inode::InodeFileType::Directory {
-        return None;
-    }
-    let mut buf = vec![];
-    if inode.borrow_mut().read_all(&mut buf) == 0 {
-        return None;
-    }
-    let iter = DirectoryParser::new(&buf);
-    for (i, entry) in iter.enumerate() {
-        if entry.ino == 0 {
-            continue;
-        }
-        if entry.file_name == name {
-            return Some((entry.ino, i));
-        }
-    }
-    None
+    lookup(&mut inode.write().unwrap(), &name)
 }
 
 // Write a new directory entry (name, ino) into the directory inode.
 pub fn dir_link(inode: &mut inode_manager::InodeLink, ino: u32, name: String) -> bool {
-    if dir_lookup(&inode, name.clone()).is_some() {
+    // the directory stays locked from lookup to write, so concurrent links can't take one slot
+    let mut dir = inode.write().unwrap();
+    if lookup(&mut dir, &name).is_some() {
         return false;
     }
     let mut buf = vec![];
-    if inode.borrow_mut().read_all(&mut buf) == 0 {
+    if dir.read_all(&mut buf) == 0 {
         return false;
     }
     let iter = DirectoryParser::new(&buf);
@@ -45,16 +37,17 @@ pub fn dir_link(inode: &mut inode_manager::InodeLink, ino: u32, name: String) ->
         ino,
     };
     let buf = DirectoryParser::encode(&entry).unwrap();
-    inode.borrow_mut().write((index * per_size) as u32, per_size as u32, &buf)
+    dir.write((index * per_size) as u64, per_size as u64, &buf)
 }
 
 // Delete a directory entry (name, ino) into the directory inode.
 pub fn dir_unlink(inode: &mut inode_manager::InodeLink, ino: u32, name: String) -> bool {
-    if !dir_lookup(&inode, name.clone()).is_some() {
+    let mut dir = inode.write().unwrap();
+    if lookup(&mut dir, &name).is_none() {
         return false;
     }
     let mut buf = vec![];
-    if inode.borrow_mut().read_all(&mut buf) == 0 {
+    if dir.read_all(&mut buf) == 0 {
         return false;
     }
     let iter = DirectoryParser::new(&buf);
@@ -70,7 +63,32 @@ pub fn dir_unlink(inode: &mut inode_manager::InodeLink, ino: u32, name: String)
     if index == len {
         return false;
     }
-    inode.borrow_mut().truncate((index * per_size) as u32, per_size as u32)
+    dir.truncate((index * per_size) as u64, per_size as u64)
+}
+
+// Check whether a directory holds nothing but "." and "..".
+pub fn dir_is_empty(inode: &inode_manager::InodeLink) -> bool {
+    inode.read().unwrap().size <= (2 * DIRECTORY_ENTRY_SIZE) as u64
+}
+
+fn lookup(dir: &mut inode::Inode, name: &str) -> Option<(u32, usize)> {
+    if dir.file_type != inode::InodeFileType::Directory {
+        return None;
+    }
+    let mut buf = vec![];
+    if dir.read_all(&mut buf) == 0 {
+        return None;
+    }
+    let iter = DirectoryParser::new(&buf);
+    for (i, entry) in iter.enumerate() {
+        if entry.ino == 0 {
+            continue;
+        }
+        if entry.file_name == name {
+            return Some((entry.ino, i));
+        }
+    }
+    None
 }
 
 #[derive(PartialEq, Debug)]
@@ -95,7 +113,7 @@ impl DirectoryParser {
             count: 0,
             data: data.clone(),
             len: data.len(),
-            per_size: 14, // 4字节 ino 10字节 name
+            per_size: DIRECTORY_ENTRY_SIZE,
         }
     }
     
@@ -161,15 +179,16 @@ mod test {
     
     #[test]
     fn test_dirlookup() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         let stat = inode::InodeStat {
             file_type: inode::InodeFileType::Directory,
-            ino: link.as_ref().unwrap().borrow().ino,
+            ino: link.as_ref().unwrap().read().unwrap().ino,
             size: 0,
             uid: 100,
             gid: 44,
+            rdev: 0,
             ref_cnt: 0,
             n_link: 1,
             mode: 0,
@@ -177,7 +196,7 @@ mod test {
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
         };
-        link.as_ref().unwrap().borrow_mut().modify_stat(stat);
+        link.as_ref().unwrap().write().unwrap().modify_stat(stat);
         dir_link(link.as_mut().unwrap(), 10, "test1.txt".to_string());
         dir_link(link.as_mut().unwrap(), 11, "test2.txt".to_string());
         dir_link(link.as_mut().unwrap(), 12, "test3.txt".to_string());
@@ -189,15 +208,16 @@ mod test {
 
     #[test]
     fn test_dirlink() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         let stat = inode::InodeStat {
             file_type: inode::InodeFileType::Directory,
-            ino: link.as_ref().unwrap().borrow().ino,
+            ino: link.as_ref().unwrap().read().unwrap().ino,
             size: 0,
             uid: 100,
             gid: 44,
+            rdev: 0,
             ref_cnt: 0,
             n_link: 1,
             mode: 0,
@@ -205,18 +225,72 @@ mod test {
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
         };
-        link.as_ref().unwrap().borrow_mut().modify_stat(stat);
+        link.as_ref().unwrap().write().unwrap().modify_stat(stat);
         dir_link(link.as_mut().unwrap(), 10, "test.txt".to_string());
         let mut buf = vec![];
-        link.as_ref().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_ref().unwrap().write().unwrap().read_all(&mut buf);
         let entry = DirectoryParser::decode(&buf).unwrap();
         assert_eq!(entry.ino, 10);
         assert_eq!(entry.file_name, "test.txt".to_string());
         dir_unlink(link.as_mut().unwrap(), 10, "test.txt".to_string());
-        link.as_ref().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_ref().unwrap().write().unwrap().read_all(&mut buf);
         assert_eq!(buf.len(), 0);
     }
 
+    #[test]
+    fn test_dir_is_empty() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let mut link = inode_manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        let mut stat = link.read().unwrap().get_stat();
+        stat.file_type = inode::InodeFileType::Directory;
+        link.write().unwrap().modify_stat(stat);
+        dir_link(&mut link, ino, ".".to_string());
+        dir_link(&mut link, 1, "..".to_string());
+        assert!(dir_is_empty(&link));
+        dir_link(&mut link, 10, "test.txt".to_string());
+        assert!(!dir_is_empty(&link));
+        dir_unlink(&mut link, 10, "test.txt".to_string());
+        assert!(dir_is_empty(&link));
+    }
+
+    #[test]
+    fn test_concurrent_link() {
+        let inode_manager = std::sync::Arc::new(inode_manager::InodeManager::new());
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc().unwrap();
+        let mut stat = link.read().unwrap().get_stat();
+        stat.file_type = inode::InodeFileType::Directory;
+        link.write().unwrap().modify_stat(stat);
+        let ino = link.read().unwrap().ino;
+        // each link looks up and writes under one lock, so no two names take the same slot
+        let handles = (0..4u32).map(|thread| {
+            let inode_manager = std::sync::Arc::clone(&inode_manager);
+            std::thread::spawn(move || {
+                for index in 0..4u32 {
+                    let mut dir = inode_manager.i_get(ino).unwrap();
+                    assert!(dir_link(&mut dir, 100 + thread * 4 + index, format!("t{}_{}", thread, index)));
+                    inode_manager.i_put(dir);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let mut slots = vec![];
+        for thread in 0..4u32 {
+            for index in 0..4u32 {
+                let (entry_ino, slot) = dir_lookup(&link, format!("t{}_{}", thread, index)).unwrap();
+                assert_eq!(entry_ino, 100 + thread * 4 + index);
+                slots.push(slot);
+            }
+        }
+        slots.sort();
+        slots.dedup();
+        assert_eq!(slots.len(), 16);
+    }
+
     #[test]
     fn test_directory_parser() {
         let mut data = vec![];
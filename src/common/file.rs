@@ -21,11 +21,11 @@ pub struct FileStat {
     pub ino: u32,
     pub file_type: FileType,
     pub n_link: u8,
-    pub size: u32,
+    pub size: u64,
 }
 
 pub struct File {
-    pub off: u32,
+    pub off: u64,
     pub ref_cnt: u8,
     pub read_able: u8,
     pub writeable: u8,
@@ -37,7 +37,7 @@ impl File {
     // Get metadata about file f.
     pub fn file_stat(&self) -> Option<FileStat> {
         if self.fd_type == FileDescriptorType::INODE || self.fd_type == FileDescriptorType::DEVICE {
-            let inode_stat = self.inode.as_ref().unwrap().borrow().get_stat();
+            let inode_stat = self.inode.as_ref().unwrap().read().unwrap().get_stat();
             return Some(File::transfer_inode_stat_to_stat(inode_stat));
         }
         None
@@ -51,9 +51,9 @@ impl File {
             return -1;
         }
         if self.fd_type == FileDescriptorType::INODE {
-            count = self.inode.as_ref().unwrap().borrow_mut().read(self.off, len, buf);
+            count = self.inode.as_ref().unwrap().write().unwrap().read(self.off, len as u64, buf) as i32;
             if count > 0 {
-                self.off += count as u32;
+                self.off += count as u64;
             }
         }
         count
@@ -66,9 +66,9 @@ impl File {
             return -1;
         }
         if self.fd_type == FileDescriptorType::INODE {
-            let res = self.inode.as_ref().unwrap().borrow_mut().write(self.off, len, &buf);
+            let res = self.inode.as_ref().unwrap().write().unwrap().write(self.off, len as u64, &buf);
             if res {
-                self.off += len;
+                self.off += len as u64;
                 ret = len as i32;
             } else {
                 ret = -1;
@@ -107,6 +107,7 @@ impl File {
             size: stat.size,
             uid: 0,
             gid: 0,
+            rdev: 0,
             ref_cnt: 0,
             n_link: stat.n_link,
             mode: 0,
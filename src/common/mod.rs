@@ -1,4 +1,5 @@
 pub mod path;
 pub mod file;
 pub mod directory;
+pub mod snapshot;
 pub mod file_table;
\ No newline at end of file
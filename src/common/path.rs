@@ -57,7 +57,7 @@ pub fn name_x(i_manager: &mut inode_manager::InodeManager, path: String, name: &
             break;
         }
         (*path, *name) = res.unwrap();
-        if ip.borrow().file_type != inode::InodeFileType::Directory {
+        if ip.read().unwrap().file_type != inode::InodeFileType::Directory {
             return None;
         }
         if name_i_parent && path == "" {
@@ -107,7 +107,7 @@ mod test {
     #[test]
     fn test_name_x() {
         let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         inode_manager.i_alloc();
         inode_manager.i_alloc();
@@ -118,10 +118,11 @@ mod test {
         inode_manager.i_alloc();
         let stat = inode::InodeStat {
             file_type: inode::InodeFileType::Directory,
-            ino: link.as_ref().unwrap().borrow().ino,
+            ino: link.as_ref().unwrap().read().unwrap().ino,
             size: 0,
             uid: 100,
             gid: 44,
+            rdev: 0,
             ref_cnt: 0,
             n_link: 1,
             mode: 0,
@@ -129,17 +130,18 @@ mod test {
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
         };
-        link.as_ref().unwrap().borrow_mut().modify_stat(stat);
+        link.as_ref().unwrap().write().unwrap().modify_stat(stat);
         directory::dir_link(link.as_mut().unwrap(), 2, "test1.txt".to_string());
         directory::dir_link(link.as_mut().unwrap(), 3, "home".to_string());
         directory::dir_link(link.as_mut().unwrap(), 4, "test3.txt".to_string());
         let mut link = inode_manager.i_get(3);
         let stat = inode::InodeStat {
             file_type: inode::InodeFileType::Directory,
-            ino: link.as_ref().unwrap().borrow().ino,
+            ino: link.as_ref().unwrap().read().unwrap().ino,
             size: 0,
             uid: 100,
             gid: 44,
+            rdev: 0,
             ref_cnt: 0,
             n_link: 1,
             mode: 0,
@@ -147,7 +149,7 @@ mod test {
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
         };
-        link.as_ref().unwrap().borrow_mut().modify_stat(stat);
+        link.as_ref().unwrap().write().unwrap().modify_stat(stat);
         directory::dir_link(link.as_mut().unwrap(), 5, "test4.txt".to_string());
         directory::dir_link(link.as_mut().unwrap(), 6, "a.rs".to_string());
         directory::dir_link(link.as_mut().unwrap(), 7, "test5.txt".to_string());
@@ -155,9 +157,9 @@ mod test {
         let mut name = "".to_string();
         let link = name_x(&mut inode_manager, "/home/a.rs".to_string(), &mut name, false);
         assert_eq!(name, "".to_string());
-        assert_eq!(link.as_ref().unwrap().borrow().ino, 6);
+        assert_eq!(link.as_ref().unwrap().read().unwrap().ino, 6);
         let link = name_x(&mut inode_manager, "/home/a.rs".to_string(), &mut name, true);
         assert_eq!(name, "a.rs".to_string());
-        assert_eq!(link.as_ref().unwrap().borrow().ino, 3);
+        assert_eq!(link.as_ref().unwrap().read().unwrap().ino, 3);
     }
 }
\ No newline at end of file
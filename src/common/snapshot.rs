@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use crate::inode::inode;
+use crate::inode::inode_manager;
+use crate::common::directory;
+
+// Name of the hidden directory under the root holding one directory per snapshot
+pub const SNAPSHOT_DIR_NAME: &str = ".snapshots";
+
+// Freeze the tree under root as a new snapshot named name in the snapshot directory.
+// Fails with EEXIST if the name is taken and ENOSPC if the tree needs more inodes than are free.
+pub fn snapshot_create(manager: &mut inode_manager::InodeManager, root: u32, snapshot_dir: u32, name: String) -> Result<u32, i32> {
+    let mut parent = manager.i_get(snapshot_dir).unwrap();
+    if directory::dir_lookup(&parent, name.clone()).is_some() {
+        manager.i_put(parent);
+        return Err(libc::EEXIST);
+    }
+    let mut copies = HashMap::new();
+    let ino = match snapshot_copy(manager, root, snapshot_dir, &mut copies) {
+        Some(ino) => ino,
+        None => {
+            // directories whose copy was not finished still list the live inodes, which release skips
+            let mut released = HashSet::new();
+            for copy in copies.into_values() {
+                snapshot_release(manager, copy, &mut released);
+            }
+            manager.i_put(parent);
+            return Err(libc::ENOSPC);
+        },
+    };
+    directory::dir_link(&mut parent, ino, name);
+    let mut parent_inode = parent.write().unwrap();
+    let mut stat = parent_inode.get_stat();
+    stat.n_link += 1;
+    parent_inode.modify_stat(stat);
+    drop(parent_inode);
+    manager.i_put(parent);
+    Ok(ino)
+}
+
+// Delete a snapshot, pages only referenced by it become dirty for GC.
+pub fn snapshot_delete(manager: &mut inode_manager::InodeManager, snapshot_dir: u32, name: String) -> bool {
+    let mut parent = manager.i_get(snapshot_dir).unwrap();
+    let ino = match directory::dir_lookup(&parent, name.clone()) {
+        Some((ino, _)) => ino,
+        None => {
+            manager.i_put(parent);
+            return false;
+        },
+    };
+    if !directory::dir_unlink(&mut parent, ino, name) {
+        manager.i_put(parent);
+        return false;
+    }
+    let mut parent_inode = parent.write().unwrap();
+    let mut stat = parent_inode.get_stat();
+    stat.n_link -= 1;
+    parent_inode.modify_stat(stat);
+    drop(parent_inode);
+    manager.i_put(parent);
+    snapshot_release(manager, ino, &mut HashSet::new());
+    true
+}
+
+fn snapshot_copy(manager: &mut inode_manager::InodeManager, ino: u32, snapshot_dir: u32, copies: &mut HashMap<u32, u32>) -> Option<u32> {
+    // hard links map to the same copy
+    if let Some(copy) = copies.get(&ino) {
+        return Some(*copy);
+    }
+    let copy = manager.core_manager.lock().unwrap().snapshot_inode(ino)?;
+    copies.insert(ino, copy);
+    let inode = manager.i_get(copy).unwrap();
+    if inode.read().unwrap().file_type == inode::InodeFileType::Directory {
+        let mut buf = vec![];
+        inode.write().unwrap().read_all(&mut buf);
+        let mut data = vec![];
+        let mut dropped = 0;
+        for mut entry in directory::DirectoryParser::new(&buf) {
+            if entry.ino == 0 {
+                continue;
+            }
+            // snapshots never contain older snapshots
+            if entry.ino == snapshot_dir {
+                dropped += 1;
+                continue;
+            }
+            entry.ino = match snapshot_copy(manager, entry.ino, snapshot_dir, copies) {
+                Some(copy) => copy,
+                None => {
+                    manager.i_put(inode);
+                    return None;
+                },
+            };
+            data.append(&mut directory::DirectoryParser::encode(&entry).unwrap());
+        }
+        let mut copy_inode = inode.write().unwrap();
+        copy_inode.truncate_to_end(0);
+        copy_inode.write(0, data.len() as u64, &data);
+        if dropped > 0 {
+            let mut stat = copy_inode.get_stat();
+            stat.n_link -= dropped;
+            copy_inode.modify_stat(stat);
+        }
+        drop(copy_inode);
+    }
+    manager.i_put(inode);
+    Some(copy)
+}
+
+fn snapshot_release(manager: &mut inode_manager::InodeManager, ino: u32, released: &mut HashSet<u32>) {
+    if !released.insert(ino) || !manager.core_manager.lock().unwrap().is_frozen_inode(ino) {
+        return;
+    }
+    let inode = manager.i_get(ino).unwrap();
+    if inode.read().unwrap().file_type == inode::InodeFileType::Directory {
+        let mut buf = vec![];
+        inode.write().unwrap().read_all(&mut buf);
+        for entry in directory::DirectoryParser::new(&buf) {
+            if entry.ino == 0 || entry.file_name == "." || entry.file_name == ".." {
+                continue;
+            }
+            snapshot_release(manager, entry.ino, released);
+        }
+    }
+    inode.write().unwrap().delete();
+    manager.i_put(inode);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_dir(manager: &mut inode_manager::InodeManager, parent: Option<u32>) -> u32 {
+        let mut inode = manager.i_alloc().unwrap();
+        let ino = inode.read().unwrap().ino;
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.file_type = inode::InodeFileType::Directory;
+        stat.n_link = 2;
+        inode.write().unwrap().modify_stat(stat);
+        directory::dir_link(&mut inode, ino, ".".to_string());
+        if let Some(parent) = parent {
+            directory::dir_link(&mut inode, parent, "..".to_string());
+            let mut parent_inode = manager.i_get(parent).unwrap();
+            directory::dir_link(&mut parent_inode, ino, format!("d{}", ino));
+            manager.i_put(parent_inode);
+        }
+        manager.i_put(inode);
+        ino
+    }
+
+    #[test]
+    fn basics() {
+        let mut manager = inode_manager::InodeManager::new();
+        manager.core_manager.lock().unwrap().mount();
+        let root = make_dir(&mut manager, None);
+        let snapshot_dir = make_dir(&mut manager, Some(root));
+        let dir = make_dir(&mut manager, Some(root));
+        let mut file = manager.i_alloc().unwrap();
+        let ino = file.read().unwrap().ino;
+        let mut stat = file.read().unwrap().get_stat();
+        stat.n_link = 1;
+        file.write().unwrap().modify_stat(stat);
+        file.write().unwrap().write(0, 8192, &vec![1; 8192]);
+        let mut dir_inode = manager.i_get(dir).unwrap();
+        directory::dir_link(&mut dir_inode, ino, "f".to_string());
+
+        let copy = snapshot_create(&mut manager, root, snapshot_dir, "s1".to_string()).unwrap();
+        assert_eq!(snapshot_create(&mut manager, root, snapshot_dir, "s1".to_string()), Err(libc::EEXIST));
+        let copy_inode = manager.i_get(copy).unwrap();
+        assert!(directory::dir_lookup(&copy_inode, format!("d{}", snapshot_dir)).is_none());
+        let (copy_dir, _) = directory::dir_lookup(&copy_inode, format!("d{}", dir)).unwrap();
+        assert_ne!(copy_dir, dir);
+        let copy_dir_inode = manager.i_get(copy_dir).unwrap();
+        assert_eq!(directory::dir_lookup(&copy_dir_inode, "..".to_string()).unwrap().0, copy);
+        let (copy_file, _) = directory::dir_lookup(&copy_dir_inode, "f".to_string()).unwrap();
+        assert!(manager.core_manager.lock().unwrap().is_frozen_inode(copy_file));
+
+        // the live file changes, the snapshot keeps the old pages
+        file.write().unwrap().write(0, 10, &vec![2; 10]);
+        directory::dir_unlink(&mut dir_inode, ino, "f".to_string());
+        let copy_file_inode = manager.i_get(copy_file).unwrap();
+        let mut buf = vec![];
+        copy_file_inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, vec![1; 8192]);
+        let v_address = copy_file_inode.read().unwrap().data[0].address;
+        assert_eq!(manager.core_manager.lock().unwrap().get_data_owners(v_address), vec![copy_file]);
+        manager.i_put(copy_file_inode);
+        manager.i_put(copy_dir_inode);
+        manager.i_put(copy_inode);
+
+        assert!(snapshot_delete(&mut manager, snapshot_dir, "s1".to_string()));
+        assert!(!snapshot_delete(&mut manager, snapshot_dir, "s1".to_string()));
+        assert!(!manager.core_manager.lock().unwrap().is_frozen_inode(copy_file));
+        assert_eq!(file.write().unwrap().read(0, 10, &mut buf), 10);
+        assert_eq!(buf, vec![2; 10]);
+        manager.i_put(dir_inode);
+        manager.i_put(file);
+    }
+}
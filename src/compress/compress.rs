@@ -1,13 +1,62 @@
 use crate::compress::huffman;
+use crate::compress::lz;
 use crate::compress::snappy;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum CompressType {
     Huffman,
     Snappy,
+    Lz,
     None,
 }
 
+// Extended attribute holding the compression policy of a file or directory
+pub const COMPRESS_XATTR: &str = "user.wondfs.compress";
+
+// Extensions of formats that are compressed already
+pub const COMPRESSED_EXTENSIONS: [&str; 24] = [
+    "7z", "apk", "avi", "br", "bz2", "deb", "docx", "flac", "gif", "gz", "jar", "jpeg",
+    "jpg", "lz4", "mkv", "mov", "mp3", "mp4", "png", "rar", "webp", "xz", "zip", "zst",
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CompressPolicy {
+    Off,
+    Fast,
+    Max,
+}
+
+impl CompressPolicy {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            b"off" => Some(CompressPolicy::Off),
+            b"fast" => Some(CompressPolicy::Fast),
+            b"max" => Some(CompressPolicy::Max),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompressPolicy::Off => "off",
+            CompressPolicy::Fast => "fast",
+            CompressPolicy::Max => "max",
+        }
+    }
+}
+
+pub fn is_compressed_extension(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            COMPRESSED_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        },
+        _ => false,
+    }
+}
+
+// Codecs whose ratio is this close to the best one are ranked by speed
+pub const COMPRESS_SCORE_SLACK: u8 = 5;
+
 pub trait Compress {
     fn decode(&mut self, bytes: &[u8]) -> Vec<u8>;
     fn encode(&mut self, bytes: &[u8]) -> Vec<u8>;
@@ -18,6 +67,7 @@ pub struct CompressManager {
     pub effect_snappy: u8,
     pub huffman: huffman::HuffmanCodec,
     pub snappy: snappy::Snappy,
+    pub lz: lz::LzCodec,
 }
 
 impl CompressManager {
@@ -27,6 +77,7 @@ impl CompressManager {
             effect_snappy: 0,
             huffman: huffman::HuffmanCodec::new(),
             snappy: snappy::Snappy::new(),
+            lz: lz::LzCodec::new(),
         }
     }
 
@@ -44,6 +95,9 @@ impl CompressManager {
                 CompressType::Snappy => {
                     result = self.snappy.encode(bytes);
                 },
+                CompressType::Lz => {
+                    result = self.lz.encode(bytes);
+                },
                 CompressType::None => {
                     result = bytes.to_owned();
                 },
@@ -54,6 +108,27 @@ impl CompressManager {
         (result, compress_type)
     }
 
+    pub fn encode_with_policy(&mut self, bytes: &[u8], policy: CompressPolicy) -> (Vec<u8>, CompressType) {
+        match policy {
+            CompressPolicy::Off => (bytes.to_owned(), CompressType::None),
+            // the first codec that does well enough
+            CompressPolicy::Fast => self.encode(bytes),
+            // every codec, the smallest result wins
+            CompressPolicy::Max => {
+                let mut best = (bytes.to_owned(), CompressType::None);
+                for compress_type in [CompressType::Huffman, CompressType::Snappy, CompressType::Lz] {
+                    let result = self.encode_with_type(bytes, compress_type);
+                    if CompressManager::judge_encode_effect(bytes, &result, compress_type)
+                        && (best.1 == CompressType::None || result.len() < best.0.len())
+                    {
+                        best = (result, compress_type);
+                    }
+                }
+                best
+            },
+        }
+    }
+
     pub fn encode_with_type(&mut self, bytes: &[u8], compress_type: CompressType) -> Vec<u8> {
         match compress_type {
             CompressType::Huffman => {
@@ -62,6 +137,9 @@ impl CompressManager {
             CompressType::Snappy => {
                 self.snappy.encode(bytes)
             },
+            CompressType::Lz => {
+                self.lz.encode(bytes)
+            },
             CompressType::None => {
                 bytes.to_owned()
             },
@@ -76,6 +154,9 @@ impl CompressManager {
             CompressType::Snappy => {
                 self.snappy.decode(bytes)
             },
+            CompressType::Lz => {
+                self.lz.decode(bytes)
+            },
             CompressType::None => {
                 bytes.to_owned()
             },
@@ -83,13 +164,19 @@ impl CompressManager {
     }
 
     pub fn choose_compress_type_except(&self, except: &Vec<CompressType>) -> CompressType {
-        if !except.contains(&CompressType::Huffman) {
-            return CompressType::Huffman;
-        }
-        if !except.contains(&CompressType::Snappy) {
-            return CompressType::Snappy;
-        }
-        CompressType::None
+        // untried codecs keep their initial scores so every codec gets sampled
+        let candidates = [CompressType::Huffman, CompressType::Snappy, CompressType::Lz]
+            .into_iter()
+            .filter(|compress_type| !except.contains(compress_type))
+            .collect::<Vec<CompressType>>();
+        let best = match candidates.iter().map(|compress_type| self.get_compress_type_score(*compress_type)).min() {
+            Some(best) => best,
+            None => return CompressType::None,
+        };
+        candidates.into_iter()
+            .filter(|compress_type| self.get_compress_type_score(*compress_type) <= best.saturating_add(COMPRESS_SCORE_SLACK))
+            .min_by_key(|compress_type| self.get_compress_type_efficiency(*compress_type))
+            .unwrap()
     }
 
     pub fn get_compress_type_score(&self, compress_type: CompressType) -> u8 {
@@ -100,20 +187,41 @@ impl CompressManager {
             CompressType::Snappy => {
                 self.snappy.coefficient
             },
+            CompressType::Lz => {
+                self.lz.coefficient
+            },
             CompressType::None => {
                 1
             },
         }
     }
 
+    pub fn get_compress_type_efficiency(&self, compress_type: CompressType) -> u128 {
+        match compress_type {
+            CompressType::Huffman => {
+                self.huffman.efficiency
+            },
+            CompressType::Snappy => {
+                self.snappy.efficiency
+            },
+            CompressType::Lz => {
+                self.lz.efficiency
+            },
+            CompressType::None => {
+                0
+            },
+        }
+    }
+
     pub fn judge_encode_effect(o_data: &[u8], data: &Vec<u8>, compress_type: CompressType) -> bool {
         if compress_type == CompressType::None {
             return true;
         }
-        if o_data.len() / 4096 == data.len() / 4096 {
+        // compressed data has to save at least one page
+        if o_data.len().div_ceil(4096) <= data.len().div_ceil(4096) {
             return false;
         }
-        let coeffi = o_data.len() * 100 / data.len();
+        let coeffi = data.len() * 100 / o_data.len();
         if coeffi > 90 {
             return false;
         }
@@ -131,5 +239,67 @@ mod test {
         let mut manager = CompressManager::new();
         let ret = manager.encode(&data);
         manager.decode(&ret.0, ret.1);
+
+        let data = "wondfs log line 42\n".repeat(1000).into_bytes();
+        let (encoded, compress_type) = manager.encode(&data);
+        assert!(compress_type != CompressType::None);
+        assert!(encoded.len() < data.len());
+        let data = (0..8192).map(|i| (i * 7 % 256) as u8).collect::<Vec<u8>>();
+        let (encoded, compress_type) = manager.encode(&data);
+        assert_eq!(manager.decode(&encoded, compress_type), data);
+        let data = (0..8192).map(|i| [0u8, 1, 2, 255][i % 7 % 4]).collect::<Vec<u8>>();
+        let (encoded, compress_type) = manager.encode(&data);
+        assert!(compress_type != CompressType::None);
+        assert_eq!(manager.decode(&encoded, compress_type), data);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn adaptive() {
+        let mut manager = CompressManager::new();
+        manager.huffman.coefficient = 60;
+        manager.snappy.coefficient = 10;
+        manager.snappy.efficiency = 50;
+        manager.lz.coefficient = 12;
+        manager.lz.efficiency = 5;
+        assert_eq!(manager.choose_compress_type_except(&vec![]), CompressType::Lz);
+        assert_eq!(manager.choose_compress_type_except(&vec![CompressType::Lz]), CompressType::Snappy);
+        manager.lz.coefficient = 40;
+        assert_eq!(manager.choose_compress_type_except(&vec![]), CompressType::Snappy);
+        let all = vec![CompressType::Huffman, CompressType::Snappy, CompressType::Lz];
+        assert_eq!(manager.choose_compress_type_except(&all), CompressType::None);
+
+        // encoding records scores, a codec that did well gets picked again
+        let mut manager = CompressManager::new();
+        let data = "wondfs log line 42\n".repeat(1000).into_bytes();
+        for _ in 0..4 {
+            let (encoded, compress_type) = manager.encode(&data);
+            assert_eq!(manager.decode(&encoded, compress_type), data);
+        }
+        assert!(manager.get_compress_type_score(CompressType::Huffman) > manager.get_compress_type_score(CompressType::Lz));
+        assert!(manager.choose_compress_type_except(&vec![]) != CompressType::Huffman);
+    }
+
+    #[test]
+    fn policy() {
+        assert_eq!(CompressPolicy::parse(b"max"), Some(CompressPolicy::Max));
+        assert_eq!(CompressPolicy::parse(CompressPolicy::Fast.as_str().as_bytes()), Some(CompressPolicy::Fast));
+        assert_eq!(CompressPolicy::parse(b"on"), None);
+        assert!(is_compressed_extension("a.tar.GZ"));
+        assert!(!is_compressed_extension("a.txt"));
+        assert!(!is_compressed_extension(".zip"));
+        assert!(!is_compressed_extension("zip"));
+
+        let mut manager = CompressManager::new();
+        let data = "wondfs log line 42\n".repeat(1000).into_bytes();
+        let (encoded, compress_type) = manager.encode_with_policy(&data, CompressPolicy::Off);
+        assert_eq!((encoded, compress_type), (data.clone(), CompressType::None));
+        let (max, compress_type) = manager.encode_with_policy(&data, CompressPolicy::Max);
+        assert_eq!(manager.decode(&max, compress_type), data);
+        for compress_type in [CompressType::Huffman, CompressType::Snappy, CompressType::Lz] {
+            assert!(max.len() <= manager.encode_with_type(&data, compress_type).len());
+        }
+        let (fast, compress_type) = manager.encode_with_policy(&data, CompressPolicy::Fast);
+        assert!(compress_type != CompressType::None);
+        assert_eq!(manager.decode(&fast, compress_type), data);
+    }
+}
@@ -1,167 +1,192 @@
 use crate::compress::compress;
-use std::cell::RefCell;
-use std::collections::{HashMap, hash_map::Iter};
-use std::fmt::Display;
-use std::rc::Rc;
-use std::str;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::time::SystemTime;
 
-type RefHuffmanTree = Rc<RefCell<HuffmanTree>>;
-type Weight = u32;
+// Bytes encoded per block, every block of the stream decodes on its own
+pub const HUFFMAN_BLOCK_SIZE: usize = 4096;
 
-pub struct HuffmanTree {
-    pub value: Option<char>,
-    pub weight: Weight,
-    pub parent: Option<RefHuffmanTree>,
-    pub left: Option<RefHuffmanTree>,
-    pub right: Option<RefHuffmanTree>,
+// Longest code, so that code lengths fit the nibbles of the header
+pub const HUFFMAN_MAX_CODE_LEN: usize = 15;
+
+// Header is the data len followed by 256 code lengths packed two per byte
+pub const HUFFMAN_HEADER_SIZE: usize = 4 + 128;
+
+// Canonical Huffman code over the byte alphabet
+pub struct HuffmanTable {
+    pub lengths: [u8; 256],
+    pub codes: [u16; 256],
+    count: [u16; HUFFMAN_MAX_CODE_LEN + 1],
+    first_code: [u16; HUFFMAN_MAX_CODE_LEN + 1],
+    first_index: [u16; HUFFMAN_MAX_CODE_LEN + 1],
+    symbols: Vec<u8>,
 }
 
-impl HuffmanTree {
-    pub fn new() -> Self {
-        Self {
-            value: None,
-            weight: 0,
-            parent: None,
-            left: None,
-            right: None,
+impl HuffmanTable {
+    pub fn build(bytes: &[u8]) -> Self {
+        let mut freq = [0u32; 256];
+        for byte in bytes.iter() {
+            freq[*byte as usize] += 1;
         }
+        Self::from_lengths(Self::code_lengths(freq))
     }
 
-    pub fn build(char_weight: CharWeightMap) -> RefHuffmanTree {
-        let n = char_weight.len();
-        let total = 2 * n - 1;
-        let vec = (0..total)
-            .map(|_| Rc::new(RefCell::new(Self::new())))
-            .collect::<Vec<Rc<RefCell<HuffmanTree>>>>();
-        char_weight.iter()
-            .enumerate()
-            .into_iter()
-            .for_each(|(index, (ch, weight))| {
-                vec[index].borrow_mut().value = Some(*ch);
-                vec[index].borrow_mut().weight = *weight;
-            });
-        for index in n..total {
-            let m1 = Self::find_min(&vec[..index]).unwrap();
-            m1.borrow_mut().parent = Some(vec[index].clone());
-            let m2 = Self::find_min(&vec[..index]).unwrap();
-            m2.borrow_mut().parent = Some(vec[index].clone());
-            let w1 = m1.as_ref().borrow().weight;
-            let w2 = m2.as_ref().borrow().weight;
-            let weight = w1 + w2;
-            vec[index].borrow_mut().weight = weight;
-            vec[index].borrow_mut().left = Some(m1.clone());
-            vec[index].borrow_mut().right = Some(m2.clone());
+    pub fn from_lengths(lengths: [u8; 256]) -> Self {
+        let mut table = Self {
+            lengths,
+            codes: [0; 256],
+            count: [0; HUFFMAN_MAX_CODE_LEN + 1],
+            first_code: [0; HUFFMAN_MAX_CODE_LEN + 1],
+            first_index: [0; HUFFMAN_MAX_CODE_LEN + 1],
+            symbols: vec![],
+        };
+        // codes of one length are consecutive and ordered by symbol
+        let mut code = 0u16;
+        for len in 1..=HUFFMAN_MAX_CODE_LEN {
+            table.first_code[len] = code;
+            table.first_index[len] = table.symbols.len() as u16;
+            for (symbol, symbol_len) in lengths.iter().enumerate() {
+                if *symbol_len as usize == len {
+                    table.codes[symbol] = code;
+                    table.symbols.push(symbol as u8);
+                    table.count[len] += 1;
+                    code += 1;
+                }
+            }
+            code <<= 1;
         }
-        vec.last().unwrap().clone()
+        table
     }
 
-    fn find_min(tree_slice: &[Rc<RefCell<HuffmanTree>>]) -> Option<Rc<RefCell<HuffmanTree>>> {
-        let mut min = Weight::MAX;
-        let mut result = None;
-        for tree in tree_slice {
-            let tree_cell = tree.as_ref();
-            if tree_cell.borrow().parent.is_none() && tree_cell.borrow().weight < min {
-                min = tree_cell.borrow().weight;
-                result = Some(tree.clone());
-            }
+    pub fn encode_header(&self, len: usize, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        for pair in self.lengths.chunks(2) {
+            out.push(pair[0] << 4 | pair[1]);
         }
-        result
     }
-}
-
-pub struct CharWeightMap {
-    pub inner: HashMap<char, Weight>
-}
 
-impl CharWeightMap {
-    pub fn build() -> Self {
-        let mut map = HashMap::new();
-        for c in 0..=255 as u8 {
-            map.insert(c as char, 1);
+    pub fn decode_header(bytes: &[u8]) -> Option<(usize, Self)> {
+        if bytes.len() < HUFFMAN_HEADER_SIZE {
+            return None;
         }
-        let weights = ['e', 'E', 'a', 'A', 'i', 'I', 'r', 'R', 't', 'T', 'o', 'O', 'n', 'N', 's', 
-                                   'S', 'l', 'L', 'c', 'C', 'u', 'U', 'p', 'P', 'm', 'M', 'd', 'D', 'h', 'H',
-                                   'g', 'G', 'b', 'B', 'y', 'Y', 'f', 'F', 'v', 'V', 'w', 'W', 'k', 'K', 'x',
-                                   'X', 'z', 'Z', 'q', 'Q', 'j', 'J'];
-        for (index, c) in weights.iter().rev().enumerate() {
-            *map.get_mut(&c).unwrap() = 2 + index as u32;
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let mut lengths = [0u8; 256];
+        for (index, byte) in bytes[4..HUFFMAN_HEADER_SIZE].iter().enumerate() {
+            lengths[index * 2] = byte >> 4;
+            lengths[index * 2 + 1] = byte & 0x0f;
         }
-        Self { inner: map }
+        // a corrupt header may ask for more codes of a length than there are, no encoder writes that
+        let kraft = lengths.iter().filter(|len| **len > 0).map(|len| 1u32 << (HUFFMAN_MAX_CODE_LEN - *len as usize)).sum::<u32>();
+        if kraft > 1 << HUFFMAN_MAX_CODE_LEN {
+            return None;
+        }
+        Some((len, Self::from_lengths(lengths)))
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.len()
+    fn code_lengths(freq: [u32; 256]) -> [u8; 256] {
+        let mut freq = freq;
+        loop {
+            let lengths = Self::tree_lengths(&freq);
+            if lengths.iter().all(|len| *len as usize <= HUFFMAN_MAX_CODE_LEN) {
+                return lengths;
+            }
+            // flatten the distribution until the deepest leaf fits
+            for weight in freq.iter_mut() {
+                if *weight > 0 {
+                    *weight = weight.div_ceil(2);
+                }
+            }
+        }
     }
 
-    pub fn iter(&self) -> Iter<char, Weight> {
-        self.inner.iter()  
+    fn tree_lengths(freq: &[u32; 256]) -> [u8; 256] {
+        let mut lengths = [0u8; 256];
+        // leaves are numbered by symbol order, inner nodes follow them
+        let leaves = (0..256).filter(|symbol| freq[*symbol] > 0).collect::<Vec<usize>>();
+        if leaves.len() == 1 {
+            lengths[leaves[0]] = 1;
+            return lengths;
+        }
+        let mut heap = leaves.iter().enumerate().map(|(leaf, symbol)| {
+            Reverse((freq[*symbol] as u64, leaf))
+        }).collect::<BinaryHeap<Reverse<(u64, usize)>>>();
+        let mut parent = vec![usize::MAX; leaves.len()];
+        while heap.len() > 1 {
+            let Reverse((w1, n1)) = heap.pop().unwrap();
+            let Reverse((w2, n2)) = heap.pop().unwrap();
+            let node = parent.len();
+            parent.push(usize::MAX);
+            parent[n1] = node;
+            parent[n2] = node;
+            heap.push(Reverse((w1 + w2, node)));
+        }
+        for (leaf, symbol) in leaves.iter().enumerate() {
+            let mut depth = 0u32;
+            let mut node = leaf;
+            while parent[node] != usize::MAX {
+                node = parent[node];
+                depth += 1;
+            }
+            lengths[*symbol] = depth.min(u8::MAX as u32) as u8;
+        }
+        lengths
     }
-}
 
-pub struct HuffmanBinaryMap {
-    pub inner: HashMap<char, Vec<bool>>
-}
-
-impl HuffmanBinaryMap {
-    pub fn build(huffman_tree: RefHuffmanTree) -> Self {
-        let mut map = HashMap::new();
-        Self::tree_dfs(&Some(huffman_tree), &mut map, &mut vec![]);
-        Self { inner: map }
-    }
-    fn tree_dfs(
-        tree: &Option<RefHuffmanTree>, 
-        map: &mut HashMap<char, Vec<bool>>,
-        vec: &mut Vec<bool>
-    ) {
-        if let Some(tree) = tree {
-            let tree = tree.as_ref().borrow();
-            if let Some(ch) = tree.value {
-                map.insert(ch, vec.clone());
+    fn encode_block(&self, bytes: &[u8], out: &mut Vec<u8>) {
+        let mut block = vec![];
+        let (mut buf, mut count) = (0u32, 0u32);
+        for byte in bytes.iter() {
+            buf = buf << self.lengths[*byte as usize] | self.codes[*byte as usize] as u32;
+            count += self.lengths[*byte as usize] as u32;
+            while count >= 8 {
+                count -= 8;
+                block.push((buf >> count) as u8);
             }
-            vec.push(false);
-            Self::tree_dfs(&tree.left, map, vec);
-            let last = vec.last_mut().unwrap();
-            *last = true;
-            Self::tree_dfs(&tree.right, map, vec);
-            vec.pop();
+            buf &= (1 << count) - 1;
         }
+        if count > 0 {
+            block.push((buf << (8 - count)) as u8);
+        }
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.append(&mut block);
     }
-}
 
-impl Display for HuffmanBinaryMap {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut buf = String::new();
-        self.inner.iter()
-            .for_each(|(c, vec)| {
-                let mut bit_str = String::new();
-                vec.iter().for_each(|b| {
-                    bit_str += if *b { "1" } else { "0" }
-                });
-                buf += format!("{}:{}\n", *c as u32, bit_str).as_str();
-            });
-        f.write_str(buf.as_str())
+    fn decode_block(&self, bytes: &[u8], len: usize, out: &mut Vec<u8>) -> bool {
+        let (mut code, mut code_len) = (0u16, 0usize);
+        let mut decoded = 0;
+        for byte in bytes.iter() {
+            for shift in (0..8).rev() {
+                if decoded == len {
+                    return true;
+                }
+                code = code << 1 | (byte >> shift & 1) as u16;
+                code_len += 1;
+                if code_len > HUFFMAN_MAX_CODE_LEN {
+                    return false;
+                }
+                let offset = code.wrapping_sub(self.first_code[code_len]);
+                if code >= self.first_code[code_len] && offset < self.count[code_len] {
+                    out.push(self.symbols[(self.first_index[code_len] + offset) as usize]);
+                    decoded += 1;
+                    code = 0;
+                    code_len = 0;
+                }
+            }
+        }
+        decoded == len
     }
 }
 
 pub struct HuffmanCodec {
     pub coefficient: u8,
     pub efficiency: u128,
-    pub bit_map: HuffmanBinaryMap,
-    pub decode_map: DecodeConfig,
 }
 
 impl HuffmanCodec {
     pub fn new() -> Self {
-        let weight_map = CharWeightMap::build();
-        let tree = HuffmanTree::build(weight_map);
-        let bit_map = HuffmanBinaryMap::build(tree);
-        let decode_map = DecodeConfig::build(&format!("space:{}\ncapacity:{}\n{}", 0, 0, bit_map));
         Self {
             coefficient: 1,
             efficiency: 0,
-            bit_map,
-            decode_map,
         }
     }
 
@@ -172,105 +197,73 @@ impl HuffmanCodec {
     pub fn modify_efficiency(&mut self, effi: u128) {
         self.efficiency = 6 * effi / 10 + 4 * self.efficiency / 10;
     }
+
+    /// Decode one block of an encoded stream without decoding the blocks before it
+    /// params:
+    /// bytes - encoded stream
+    /// index - block index, HUFFMAN_BLOCK_SIZE bytes of data per block
+    /// return:
+    /// block data, none if the stream is broken or too short
+    pub fn decode_page(&self, bytes: &[u8], index: usize) -> Option<Vec<u8>> {
+        let (len, table) = HuffmanTable::decode_header(bytes)?;
+        if index * HUFFMAN_BLOCK_SIZE >= len {
+            return None;
+        }
+        let mut pos = HUFFMAN_HEADER_SIZE;
+        for _ in 0..index {
+            let block_len = u16::from_le_bytes(bytes.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+            pos += 2 + block_len;
+        }
+        let block_len = u16::from_le_bytes(bytes.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+        let block = bytes.get(pos + 2..pos + 2 + block_len)?;
+        let mut out = vec![];
+        let size = (len - index * HUFFMAN_BLOCK_SIZE).min(HUFFMAN_BLOCK_SIZE);
+        match table.decode_block(block, size, &mut out) {
+            true => Some(out),
+            false => None,
+        }
+    }
 }
 
 impl compress::Compress for HuffmanCodec {
+    // decoding stops at a broken header or block, so a corrupt stream comes back shorter than its len
     fn decode(&mut self, bytes: &[u8]) -> Vec<u8> {
-        let source = bytes.to_vec();
         let start_time = SystemTime::now();
-        let mut result = String::with_capacity(self.decode_map.capacity);
-        let bit_str = source.iter()
-            .map(|num| {
-                format!("{u8:>0width$b}", u8=num, width=8)
-            })
-            .collect::<Vec<String>>()
-            .join("");
-        let mut tmp_str = String::with_capacity(20);
-        let last_idx = bit_str.len() - self.decode_map.space as usize;
-        for (i, ch) in bit_str.char_indices() {
-            if i >= last_idx {
-                break;
-            }
-            tmp_str.push(ch);
-            if let Some(mch) = self.decode_map.get(&tmp_str) {
-                result.push(*mch);
-                tmp_str.clear();
+        let mut result = vec![];
+        if let Some((len, table)) = HuffmanTable::decode_header(bytes) {
+            let mut pos = HUFFMAN_HEADER_SIZE;
+            while result.len() < len && pos + 2 <= bytes.len() {
+                let block_len = u16::from_le_bytes(bytes[pos..pos + 2].try_into().unwrap()) as usize;
+                let block = &bytes[pos + 2..(pos + 2 + block_len).min(bytes.len())];
+                let size = (len - result.len()).min(HUFFMAN_BLOCK_SIZE);
+                if !table.decode_block(block, size, &mut result) {
+                    break;
+                }
+                pos += 2 + block_len;
             }
         }
         let end_time = SystemTime::now();
         let duration = end_time.duration_since(start_time).ok().unwrap();
         self.modify_efficiency(duration.as_micros());
-        result.into_bytes()
+        result
     }
 
-    fn encode(&mut self, bytes: &[u8]) -> Vec<u8> {        
-        let source = std::str::from_utf8(bytes).unwrap().to_string();
+    fn encode(&mut self, bytes: &[u8]) -> Vec<u8> {
         let start_time = SystemTime::now();
-        let mut result: Vec<u8> = vec![];
-        let (mut buf, mut count) = (0, 0);
-        for (_, ch) in source.char_indices() {
-            let vec = self.bit_map.inner.get(&ch).unwrap();
-            vec.iter().for_each(|b| {
-                buf <<= 1;
-                if *b { buf |= 1 }
-                count += 1;
-                if count >= 8 {
-                    result.push(buf);
-                    buf = 0;
-                    count = 0;
-                }
-            })
-        }
-        let mut space = 0u8;
-        if count != 0 {
-            space = 8 - count;
-            buf <<= space;
-            result.push(buf);
+        let table = HuffmanTable::build(bytes);
+        let mut result = vec![];
+        table.encode_header(bytes.len(), &mut result);
+        for block in bytes.chunks(HUFFMAN_BLOCK_SIZE) {
+            table.encode_block(block, &mut result);
         }
         let end_time = SystemTime::now();
         let duration = end_time.duration_since(start_time).ok().unwrap();
         self.modify_efficiency(duration.as_micros());
-        self.modify_coefficient((result.len() * 100 / bytes.len()) as u8);
+        self.modify_coefficient((result.len() * 100 / bytes.len().max(1)).min(u8::MAX as usize) as u8);
         result
     }
 }
 
-pub struct DecodeConfig {
-    pub inner: HashMap<String, char>,
-    pub space: u8,
-    pub capacity: usize,
-}
-impl DecodeConfig {
-    pub fn build(source: &String) -> Self {
-        let mut map = HashMap::default();
-        let (mut space, mut capacity) = (0u8, 0usize);
-        let arr = source.split("\n");
-        for s in arr {
-            let pair: Vec<&str> = s.split(":").collect();
-            if pair.len() != 2 {
-                continue;
-            }
-            let (ch, bit) = (pair[0], pair[1]);
-            match ch {
-                "space" => {
-                    space = u8::from_str_radix(bit, 10).unwrap();
-                    continue;
-                },
-                "capacity" => {
-                    capacity = usize::from_str_radix(bit, 10).unwrap();
-                    continue;
-                },
-                _ => (),
-            }
-            map.insert(bit.to_owned(), char::from_u32(u32::from_str_radix(ch, 10).unwrap()).unwrap());
-        };
-        Self { inner: map, space, capacity }
-    }
-    pub fn get(&self, k: &String) -> Option<&char> {
-        self.inner.get(k)
-    }
-}
-
 #[cfg(test)]
 mod test {
     use crate::compress::compress::Compress;
@@ -281,6 +274,98 @@ mod test {
         let data = "fsfjlahuhdwnf.v.sljp;jdqdsjdfhalkshdlhliqjfsfjlahuhdwnf.v.sljp;jdqdsjdfhalkshdlhliqjdna,dnlawjdla.jdj.lskd.wnkakadmbDmabdmadahqbdkfsfsknasnwnkdnsnsckwkcwjlkrjflqwjclamlqwdjwlfdjlamflcmljwijrlqflkmlkmlam;c;wk;rk;qkf;,l.e,s;lad;lca;skc;lkasc;k;wk;ekr;qkw;fk;qk;aclks;lck;kwe;qlkf;lwekf;lqk;kca/kcq/;kf;/wq;er/;wemc;kasd/vjlerhgnkv,bsfnqlnfknjk,env,nq,nfwqnf.wmlmvavqljwlejl   jdlj    llk jcljljhajsjqbwd bdkcdashlcahlcb,kbd,    n,kew   kdkqwn,cknc ,k,qnwn qbd,k   bx, mbmasbcmbambmdbamcbamscmnavfkjfhkqwhecquhakcbkwb,ek,fbqwfqwbfnqefkqfqewfqwfqvaddna,dnlawjdla.jdj.lskd.wnkakadmbDmabdmadahqbdkfsfsknasnwnkdnsnsckwkcwjlkrjflqwjclamlqwdjwlfdjlamflcmljwijrlqflkmlkmlam;c;wk;rk;qkf;,l.e,s;lad;lca;skc;lkasc;k;wk;ekr;qkw;fk;qk;aclks;lck;kwe;qlkf;lwekf;lqk;kca/kcq/;kf;/wq;er/;wemc;kasd/vjlerhgnkv,bsfnqlnfknjk,env,nq,nfwqnf.wmlmvavqljwlejl   jdlj    llk jcljljhajsjqbwd bdkcdashlcahlcb,kbd,    n,kew   kdkqwn,cknc ,k,qnwn qbd,k   bx, mbmasbcmbambmdbamcbamscmnavfkjfhkqwhecquhakcbkwb,ek,fbqwfqwbfnqefkqfqewfqwfqvadvavafsfjlahuhdwnf.v.sljp;jdqdsjdfhalkshdlhliqjdna,dnlawjdla.jdj.lskd.wnkakadmbDmabdmadahqbdkfsfsknasnwnkdnsnsckwkcwjlkrjflqwjclamlqwdjwlfdjlamflcmljwijrlqflkmlkmlam;c;wk;rk;qkf;,l.e,s;lad;lca;skc;lkasc;k;wk;ekr;qkw;fk;qk;aclks;lck;kwe;qlkf;lwekf;lqk;kca/kcq/;kf;/wq;er/;wemc;kasd/vjlerhgnkv,bsfnqlnfknjk,env,nq,nfwqnf.wmlmvavqljwlejl   jdlj    llk jcljljhajsjqbwd bdkcdashlcahlcb,kbd,    n,kew   kdkqwn,cknc ,k,qnwn qbd,k   bx, mbmasbcmbambmdbamcbamscmnavfkjfhkqwhecquhakcbkwb,ek,fbqwfqwbfnqefkqfqewfqwfqvadfsfjlahuhdwnf.v.sljp;jdqdsjdfhalkshdlhliqjdna,dnlawjdla.jdj.lskd.wnkakadmbDmabdmadahqbdkfsfsknasnwnkdnsnsckwkcwjlkrjflqwjclamlqwdjwlfdjlamflcmljwijrlqflkmlkmlam;c;wk;rk;qkf;,l.e,s;lad;lca;skc;lkasc;k;wk;ekr;qkw;fk;qk;aclks;lck;kwe;qlkf;lwekf;lqk;kca/kcq/;kf;/wq;er/;wemc;kasd/vjlerhgnkv,bsfnqlnfknjk,env,nq,nfwqnf.wmlmvavqljwlejl   jdlj    llk jcljljhajsjqbwd bdkcdashlcahlcb,kbd,    n,kew   kdkqwn,cknc ,k,qnwn qbd,k   bx, mbmasbcmbambmdbamcbamscmnavfkjfhkqwhecquhakcbkwb,ek,fbqwfqwbfnqefkqfqewfqwfqvadfsfjlahuhdwnf.v.sljp;jdqdsjdfhalkshdlhliqjdna,dnlawjdla.jdj.lskd.wnkakadmbDmabdmadahqbdkfsfsknasnwnkdnsnsckwkcwjlkrjflqwjclamlqwdjwlfdjlamflcmljwijrlqflkmlkmlam;c;wk;rk;qkf;,l.e,s;lad;lca;skc;lkasc;k;wk;ekr;qkw;fk;qk;aclks;lck;kwe;qlkf;lwekf;lqk;kca/kcq/;kf;/wq;er/;wemc;kasd/vjlerhgnkv,bsfnqlnfknjk,env,nq,nfwqnf.wmlmvavqljwlejl   jdlj    llk jcljljhajsjqbwd bdkcdashlcahlcb,kbd,    n,kew   kdkqwn,cknc ,k,qnwn qbd,k   bx, mbmasbcmbambmdbamcbamscmnavfkjfhkqwhecquhakcbkwb,ek,fbqwfqwbfnqefkqfqewfqwfqvadfqfqv".as_bytes();
         let mut compress = HuffmanCodec::new();
         let compressed = compress.encode(&data);
-        compress.decode(&compressed);
+        assert_eq!(compress.decode(&compressed), data);
+        assert!(compressed.len() < data.len());
+    }
+
+    #[test]
+    fn binary() {
+        let mut compress = HuffmanCodec::new();
+        let data = (0..10000u32).map(|i| (i * i % 251) as u8).collect::<Vec<u8>>();
+        let compressed = compress.encode(&data);
+        assert_eq!(compress.decode(&compressed), data);
+        assert_eq!(compress.decode_page(&compressed, 1).unwrap(), data[4096..8192].to_vec());
+        assert_eq!(compress.decode_page(&compressed, 2).unwrap(), data[8192..].to_vec());
+        assert!(compress.decode_page(&compressed, 3).is_none());
+        let data = vec![0u8; 4096];
+        let compressed = compress.encode(&data);
+        assert_eq!(compressed.len(), HUFFMAN_HEADER_SIZE + 2 + 512);
+        assert_eq!(compress.decode(&compressed), data);
+        // skewed weights would need codes longer than the header allows
+        let mut data = vec![];
+        for i in 0..20 {
+            data.extend(vec![i as u8; 1 << i]);
+        }
+        let compressed = compress.encode(&data);
+        assert_eq!(compress.decode(&compressed), data);
+        assert!(compress.encode(&[]).len() == HUFFMAN_HEADER_SIZE);
+        assert!(compress.decode(&[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn ratio() {
+        let mut seed = 0x9e3779b9u32;
+        let random = (0..1 << 16).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect::<Vec<u8>>();
+        let text = "Jan 01 00:00:00 host wondfs[42]: write ino 17 offset 4096 len 4096\n".repeat(1000).into_bytes();
+        let zero = vec![0u8; 1 << 16];
+        // percent of the input each kind of data is expected to shrink to at most
+        for (data, percent) in [(&text, 75), (&zero, 20), (&random, 105)] {
+            let mut huffman = HuffmanCodec::new();
+            let huffman_data = data.chunks(HUFFMAN_BLOCK_SIZE).map(|page| huffman.encode(page)).collect::<Vec<Vec<u8>>>();
+            let decoded = huffman_data.iter().flat_map(|page| huffman.decode(page)).collect::<Vec<u8>>();
+            assert_eq!(&decoded, data);
+            let huffman_len = huffman_data.iter().map(|page| page.len()).sum::<usize>();
+            assert!(huffman_len * 100 <= data.len() * percent);
+        }
+    }
+
+    // cargo test huffman::test::bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench() {
+        use crate::compress::snappy;
+        let mut seed = 0x9e3779b9u32;
+        let random = (0..1 << 20).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect::<Vec<u8>>();
+        let text = "Jan 01 00:00:00 host wondfs[42]: write ino 17 offset 4096 len 4096\n".repeat(16000).into_bytes();
+        for (name, data) in [("text", &text), ("random", &random)] {
+            let mut huffman = HuffmanCodec::new();
+            let mut snappy = snappy::Snappy::new();
+            let codecs: [(&str, &mut dyn compress::Compress); 2] = [("huffman", &mut huffman), ("snappy", &mut snappy)];
+            for (codec, compress) in codecs {
+                let start_time = SystemTime::now();
+                let encoded = data.chunks(HUFFMAN_BLOCK_SIZE).map(|page| compress.encode(page)).collect::<Vec<Vec<u8>>>();
+                let encode_time = start_time.elapsed().unwrap();
+                let start_time = SystemTime::now();
+                let decoded = encoded.iter().flat_map(|page| compress.decode(page)).collect::<Vec<u8>>();
+                let decode_time = start_time.elapsed().unwrap();
+                assert_eq!(&decoded, data);
+                let len = encoded.iter().map(|page| page.len()).sum::<usize>();
+                println!("{} {}: {}% encode {:?} decode {:?}", name, codec, len * 100 / data.len(), encode_time, decode_time);
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn corrupt_header() {
+        let mut header = vec![0u8; HUFFMAN_HEADER_SIZE];
+        header[0] = 1;
+        // two codes of length 1 fill the code space, a third one over-subscribes it
+        header[4] = 0x11;
+        assert!(HuffmanTable::decode_header(&header).is_some());
+        header[5] = 0x10;
+        assert!(HuffmanTable::decode_header(&header).is_none());
+        let mut compress = HuffmanCodec::new();
+        header.push(0);
+        assert!(compress.decode(&header).is_empty());
+    }
+}
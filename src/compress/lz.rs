@@ -0,0 +1,201 @@
+use crate::compress::compress;
+use std::time::SystemTime;
+
+// Shortest match worth a sequence, a token plus offset costs three bytes
+pub const LZ_MIN_MATCH: usize = 4;
+
+// Matches reach back at most this far, offsets are stored in two bytes
+pub const LZ_MAX_OFFSET: usize = 65535;
+
+const LZ_HASH_BITS: u32 = 12;
+
+// Stream is the data len followed by sequences of
+// token (literal len << 4 | match len - LZ_MIN_MATCH), literals, u16 offset,
+// a nibble of 15 is continued by bytes of 255 until a smaller byte,
+// the last sequence carries only literals
+pub struct LzCodec {
+    pub coefficient: u8,
+    pub efficiency: u128,
+}
+
+impl LzCodec {
+    pub fn new() -> Self {
+        Self {
+            coefficient: 1,
+            efficiency: 0,
+        }
+    }
+
+    pub fn modify_coefficient(&mut self, coeffi: u8) {
+        self.coefficient = (6 * coeffi as u16 / 10 + 4 * self.coefficient as u16 / 10) as u8;
+    }
+
+    pub fn modify_efficiency(&mut self, effi: u128) {
+        self.efficiency = 6 * effi / 10 + 4 * self.efficiency / 10;
+    }
+}
+
+fn lz_hash(bytes: &[u8]) -> usize {
+    let seq = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    (seq.wrapping_mul(2654435761) >> (32 - LZ_HASH_BITS)) as usize
+}
+
+fn lz_push_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn lz_read_len(bytes: &[u8], pos: &mut usize, mut len: usize) -> Option<usize> {
+    loop {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+fn lz_push_sequence(out: &mut Vec<u8>, literals: &[u8], offset: usize, match_len: usize) {
+    let match_nibble = match match_len {
+        0 => 0,
+        _ => match_len - LZ_MIN_MATCH,
+    };
+    out.push((literals.len().min(15) << 4 | match_nibble.min(15)) as u8);
+    if literals.len() >= 15 {
+        lz_push_len(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if match_len == 0 {
+        return;
+    }
+    out.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_nibble >= 15 {
+        lz_push_len(out, match_nibble - 15);
+    }
+}
+
+fn lz_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().unwrap()) as usize;
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 4;
+    while pos < bytes.len() {
+        let token = bytes[pos];
+        pos += 1;
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len = lz_read_len(bytes, &mut pos, literal_len)?;
+        }
+        out.extend_from_slice(bytes.get(pos..pos + literal_len)?);
+        pos += literal_len;
+        if pos == bytes.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes(bytes.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+        pos += 2;
+        let mut match_len = (token & 0x0f) as usize;
+        if match_len == 15 {
+            match_len = lz_read_len(bytes, &mut pos, match_len)?;
+        }
+        match_len += LZ_MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > len {
+            return None;
+        }
+        // the match may overlap the bytes it produces
+        let start = out.len() - offset;
+        for index in start..start + match_len {
+            out.push(out[index]);
+        }
+    }
+    match out.len() == len {
+        true => Some(out),
+        false => None,
+    }
+}
+
+impl compress::Compress for LzCodec {
+    fn encode(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let start_time = SystemTime::now();
+        let mut result = vec![];
+        result.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        // positions are stored plus one so zero means empty
+        let mut table = vec![0usize; 1 << LZ_HASH_BITS];
+        let mut anchor = 0;
+        let mut pos = 0;
+        while pos + LZ_MIN_MATCH <= bytes.len() {
+            let hash = lz_hash(&bytes[pos..]);
+            let candidate = table[hash];
+            table[hash] = pos + 1;
+            if candidate == 0 || pos + 1 - candidate > LZ_MAX_OFFSET
+                || bytes[candidate - 1..candidate - 1 + LZ_MIN_MATCH] != bytes[pos..pos + LZ_MIN_MATCH]
+            {
+                pos += 1;
+                continue;
+            }
+            let candidate = candidate - 1;
+            let mut match_len = LZ_MIN_MATCH;
+            while pos + match_len < bytes.len() && bytes[candidate + match_len] == bytes[pos + match_len] {
+                match_len += 1;
+            }
+            lz_push_sequence(&mut result, &bytes[anchor..pos], pos - candidate, match_len);
+            pos += match_len;
+            anchor = pos;
+        }
+        lz_push_sequence(&mut result, &bytes[anchor..], 0, 0);
+        let end_time = SystemTime::now();
+        let duration = end_time.duration_since(start_time).ok().unwrap();
+        self.modify_efficiency(duration.as_micros());
+        self.modify_coefficient((result.len() * 100 / bytes.len().max(1)).min(u8::MAX as usize) as u8);
+        result
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let start_time = SystemTime::now();
+        let result = lz_decode(bytes).unwrap_or_default();
+        let end_time = SystemTime::now();
+        let duration = end_time.duration_since(start_time).ok().unwrap();
+        self.modify_efficiency(duration.as_micros());
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compress::compress::Compress;
+    use super::*;
+
+    #[test]
+    fn basics() {
+        let mut compress = LzCodec::new();
+        let data = "wondfs write ino 17 offset 4096 len 4096\n".repeat(300).into_bytes();
+        let compressed = compress.encode(&data);
+        assert!(compressed.len() * 10 < data.len());
+        assert_eq!(compress.decode(&compressed), data);
+
+        // overlapping matches and long literal runs
+        let data = vec![7u8; 100000];
+        let compressed = compress.encode(&data);
+        assert_eq!(compress.decode(&compressed), data);
+        let mut seed = 0x2545f491u32;
+        let data = (0..20000).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect::<Vec<u8>>();
+        let compressed = compress.encode(&data);
+        assert!(compressed.len() <= data.len() + data.len() / 255 + 16);
+        assert_eq!(compress.decode(&compressed), data);
+        let compressed = compress.encode(&[1, 2, 3]);
+        assert_eq!(compress.decode(&compressed), vec![1, 2, 3]);
+        let compressed = compress.encode(&[]);
+        assert!(compress.decode(&compressed).is_empty());
+
+        // malformed streams decode to nothing instead of panicking
+        assert!(compress.decode(&[5, 0, 0, 0, 0x10, 1, 5, 0]).is_empty());
+        assert!(compress.decode(&[1]).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod snappy;
 pub mod huffman;
+pub mod lz;
 pub mod compress;
\ No newline at end of file
@@ -2,7 +2,8 @@
 // Core Layer
 //
 
-use std::sync::Mutex;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use crate::buf;
 use crate::core::bit;
 use crate::core::pit;
@@ -17,6 +18,8 @@ use crate::kv::raw_inode;
 use crate::gc::gc_manager;
 use crate::gc::gc_event;
 use crate::gc::gc_define::*;
+use crate::compress::compress;
+use crate::crypt::crypt;
 
 // Core Layer Main Controller Structure
 pub struct CoreManager {
@@ -28,6 +31,29 @@ pub struct CoreManager {
     gc: gc_manager::GCManager,
     journal: journal::Journal,
     buf_cache: buf::BufCache,
+    compress: compress::CompressManager,
+    compress_policy: compress::CompressPolicy,
+    crypt: crypt::CryptManager,
+    dedup_enabled: bool,
+}
+
+// File System Usage Statistics
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FileSystemStat {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+}
+
+// Where the content of a page to write comes from
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum PageSource {
+    New, // programmed to a newly allocated page
+    Stored(u32), // already on disk at this physical address
+    Same(usize), // same content as an earlier new page of the same write
 }
 
 // Core Layer Simple Interface Function
@@ -42,6 +68,10 @@ impl CoreManager {
             journal: journal::Journal::new(),
             buf_cache: buf::BufCache::new(),
             stat: super_stat::SuperStat::new(),
+            compress: compress::CompressManager::new(),
+            compress_policy: compress::CompressPolicy::Off,
+            crypt: crypt::CryptManager::new(),
+            dedup_enabled: false,
         }
     }
 
@@ -50,12 +80,54 @@ impl CoreManager {
         self.read_bit();
         self.read_pit();
         self.read_journal();
+        self.read_kv();
+        // files unlinked while open are freed here when the last close never came
+        if self.clean_orphans() > 0 {
+            self.sync();
+        }
     }
 }
 
 // Core Layer Main Interface Function
 impl CoreManager {
-    
+    /// Write back all dirty metadata and cached pages to disk
+    /// params:
+    /// ()
+    /// return:
+    /// ()
+    pub fn sync(&mut self) {
+        self.sync_bit();
+        self.sync_pit();
+        self.sync_journal();
+        self.sync_kv();
+        self.buf_cache.sync();
+    }
+
+    /// Get file system usage statistics in pages
+    /// params:
+    /// ()
+    /// return:
+    /// usage statistics
+    pub fn statfs(&self) -> FileSystemStat {
+        // the reserved region backs the translation layer's spare pool and is never
+        // handed out to data, so only the main region counts as capacity
+        let total = min(self.stat.get_main_size() * self.stat.get_page_num_per_block(), self.gc.get_page_num());
+        let used = self.gc.get_page_num() - self.gc.get_free_page_num();
+        let free = total.saturating_sub(used);
+        // forward GC needs a spare block to move busy pages into before it can erase, and the
+        // translation layer's spare blocks are held back too so worn blocks can be remapped
+        let spare = self.buf_cache.get_spare_block_num() * self.stat.get_page_num_per_block();
+        let avail = free.saturating_sub(GCRESERVEDPAGENUM + spare);
+        let free_inode = self.kv.get_free_inode_num();
+        FileSystemStat {
+            blocks: total as u64,
+            bfree: free as u64,
+            bavail: avail as u64,
+            files: fake_kv::MAX_INODE_NUM as u64,
+            ffree: free_inode as u64,
+            bsize: self.stat.get_page_size(),
+        }
+    }
 }
 
 // Core Layer KV Module Function
@@ -64,10 +136,75 @@ impl CoreManager {
     /// params:
     /// ()
     /// return:
-    /// new inode
-    pub fn allocate_inode(&mut self) -> inode::Inode {
-        let raw_inode = self.kv.allocate_inode();
-        CoreManager::transfer_raw_inode_to_inode(&raw_inode)
+    /// new inode, none if every inode is in use
+    pub fn allocate_inode(&mut self) -> Option<inode::Inode> {
+        let raw_inode = self.kv.allocate_inode()?;
+        Some(CoreManager::transfer_raw_inode_to_inode(&raw_inode))
+    }
+
+    /// Copy an inode into a new frozen inode, the copy shares all data pages
+    /// params:
+    /// ino: inode's ino
+    /// return:
+    /// ino of the copy, none if every inode is in use
+    pub fn snapshot_inode(&mut self, ino: u32) -> Option<u32> {
+        let mut raw_inode = self.kv.get_inode(ino);
+        let copy_ino = self.kv.allocate_inode()?.ino;
+        raw_inode.ino = copy_ino;
+        // shared pages stay busy until the last owner releases them, so GC never reclaims them
+        self.pit_begin_op();
+        for entry in raw_inode.data.iter() {
+            for address in entry.address..entry.address + entry.size {
+                self.share_pit(address, copy_ino);
+            }
+        }
+        self.pit_end_op();
+        self.kv.update_inode(raw_inode);
+        self.kv.freeze_inode(copy_ino);
+        Some(copy_ino)
+    }
+
+    /// Check whether an inode belongs to a snapshot
+    /// params:
+    /// ino: inode's ino
+    /// return:
+    /// frozen or not
+    pub fn is_frozen_inode(&self, ino: u32) -> bool {
+        self.kv.is_frozen(ino)
+    }
+
+    /// Record an inode unlinked while still in use, it is freed with its last reference
+    /// params:
+    /// ino: inode's ino
+    /// return:
+    /// ()
+    pub fn add_orphan_inode(&mut self, ino: u32) {
+        self.kv.add_orphan(ino);
+    }
+
+    /// Check whether an inode waits to be freed after its last unlink
+    /// params:
+    /// ino: inode's ino
+    /// return:
+    /// orphan or not
+    pub fn is_orphan_inode(&self, ino: u32) -> bool {
+        self.kv.is_orphan(ino)
+    }
+
+    /// Free every inode recorded as orphan in kv region
+    /// params:
+    /// ()
+    /// return:
+    /// count of inodes freed
+    pub fn clean_orphans(&mut self) -> u32 {
+        let orphans = self.kv.get_orphans();
+        for ino in orphans.iter() {
+            let mut event_group = inode_event::InodeEventGroup::new();
+            event_group.inode = self.get_inode(*ino);
+            event_group.need_delete = true;
+            self.dispose_event_group(event_group);
+        }
+        orphans.len() as u32
     }
 
     /// Get inode from kv region by ino
@@ -101,6 +238,21 @@ impl CoreManager {
         self.kv.update_inode(raw_inode);
     }
 
+    /// Drop an inode leaving the inode cache, its virtual addresses are unmapped
+    /// params:
+    /// inode - inode without unsaved changes
+    /// return:
+    /// ()
+    pub fn put_inode(&mut self, inode: &inode::Inode) {
+        for entry in inode.data.iter() {
+            for v_address in entry.address..entry.address + entry.size {
+                if let Some(address) = self.vam.get_physic_address(v_address) {
+                    self.vam.delete_map(address, v_address);
+                }
+            }
+        }
+    }
+
     /// Delete inode in kv region
     /// params:
     /// ino
@@ -110,6 +262,15 @@ impl CoreManager {
         self.kv.delete_inode(ino);
     }
 
+    /// Check whether an inode is still stored in kv region
+    /// params:
+    /// ino: inode's ino
+    /// return:
+    /// stored or not
+    pub fn has_inode(&self, ino: u32) -> bool {
+        self.kv.has_inode(ino)
+    }
+
     /// Get raw inode from kv region by ino
     /// params:
     /// ino - raw inode's ino
@@ -191,32 +352,7 @@ impl CoreManager {
                     self.erase_block_in_block_table(event.block_no);
                 }
                 gc_event::GCEvent::Move(event) => {
-                    let o_address = event.o_address;
-                    let d_address = event.d_address;
-                    let size = event.size;
-                    let ino = event.ino;
-                    let mut data = vec![];
-                    for i in o_address..o_address + size {
-                        data.push(self.read_page(i, true));
-                        let v_address = self.vam.get_virtual_address(i);
-                        if v_address.is_some() {
-                            self.vam.update_map(d_address + i, v_address.unwrap());
-                        }
-                        self.dirty_pit(i);
-                    }
-                    for i in d_address..d_address + size {
-                        self.update_bit(i, true);
-                        self.update_pit(i, ino);
-                        self.write_page(i, data[(i - d_address) as usize], true);
-                    }
-                    let mut raw_inode = self.get_raw_inode(ino);
-                    for entry in raw_inode.data.iter_mut() {
-                        if entry.address == o_address {
-                            entry.address = d_address;
-                            break;
-                        }
-                    }
-                    self.update_raw_inode(raw_inode);
+                    self.move_pages(event.o_address, event.d_address, event.size);
                 }
                 _ => ()
             }
@@ -225,6 +361,41 @@ impl CoreManager {
         self.pit_end_op();
         self.clear_journal();
     }
+
+    fn move_pages(&mut self, o_address: u32, d_address: u32, size: u32) {
+        let mut owners = vec![];
+        for i in 0..size {
+            let data = self.read_page(o_address + i, true);
+            for v_address in self.vam.get_virtual_addresses(o_address + i) {
+                self.vam.update_map(d_address + i, v_address);
+            }
+            let page_owners = self.pit.get_owners(o_address + i);
+            if page_owners.is_empty() {
+                continue;
+            }
+            self.dirty_pit(o_address + i);
+            self.kv.move_fingerprint(o_address + i, d_address + i);
+            self.write_page(d_address + i, data, true);
+            self.update_bit(d_address + i, true);
+            self.update_pit(d_address + i, page_owners[0]);
+            for owner in page_owners[1..].iter() {
+                self.share_pit(d_address + i, *owner);
+            }
+            owners.extend(page_owners);
+        }
+        owners.sort();
+        owners.dedup();
+        // a shared page is referenced by the entries of every owner
+        for ino in owners {
+            let mut raw_inode = self.get_raw_inode(ino);
+            for entry in raw_inode.data.iter_mut() {
+                if entry.address >= o_address && entry.address < o_address + size {
+                    entry.address = entry.address - o_address + d_address;
+                }
+            }
+            self.update_raw_inode(raw_inode);
+        }
+    }
 }
 
 
@@ -430,8 +601,26 @@ impl CoreManager {
         self.sync_pit();
     }
 
+    pub fn share_pit(&mut self, address: u32, ino: u32) {
+        self.pit.add_owner(address, ino);
+        self.sync_pit();
+    }
+
+    pub fn unshare_pit(&mut self, address: u32, ino: u32) {
+        let owners = self.pit.remove_owner(address, ino);
+        match owners.first() {
+            Some(owner) => self.set_page(address, PageUsedStatus::Busy(*owner)),
+            None => {
+                self.set_page(address, PageUsedStatus::Dirty);
+                self.kv.remove_fingerprint(address);
+            },
+        }
+        self.sync_pit();
+    }
+
     pub fn clean_pit(&mut self, address: u32) {
         self.pit.clean_page(address);
+        self.kv.remove_fingerprint(address);
         self.set_page(address, PageUsedStatus::Clean);
         self.sync_pit();
     }
@@ -458,6 +647,47 @@ impl CoreManager {
     }
 }
 
+// Core Layer KV Region Function
+impl CoreManager {
+    /// Read KV region from disk and finish an interrupted sync
+    /// params:
+    /// ()
+    /// return:
+    /// ()
+    pub fn read_kv(&mut self) {
+        let mut data_1 = self.read_block(26, false);
+        let data_2 = self.read_block(27, false);
+        let mut flag = false;
+        for i in 0..4 {
+            if data_2.get(0)[i] & 0b1111_1111 != 0 {
+                flag = true;
+                break;
+            }
+        }
+        if flag {
+            self.erase_block(26, false);
+            self.write_block(26, &data_2, false);
+            self.erase_block(27, false);
+            data_1 = data_2;
+        }
+        self.kv.build(&data_1);
+    }
+
+    // blocks 26 and 27 sit between the main region and the translation layer's reserved
+    // blocks, the kv image uses them the same way BIT and PIT use their block pairs
+    pub fn sync_kv(&mut self) {
+        if self.kv.need_sync() {
+            let data = self.kv.encode();
+            let data = CoreManager::transfer(&data);
+            self.write_block(27, &data, false);
+            self.erase_block(26, false);
+            self.write_block(26, &data, false);
+            self.erase_block(27, false);
+            self.kv.sync();
+        }
+    }
+}
+
 // Core Layer Journal Region Function
 impl CoreManager {
     pub fn read_journal(&mut self) {
@@ -521,32 +751,14 @@ impl CoreManager {
         let block_no = self.journal.get_erase_block_no();
         let start_index = block_no * 128;
         let end_index = (block_no + 1) * 128;
+        // owners are read from PIT, so move pages before the block is cleaned
+        for entry in &self.journal.table.clone() {
+            self.move_pages(*entry.0, *entry.1, 1);
+        }
         for i in start_index..end_index {
             self.update_bit(i, false);
             self.clean_pit(i);
         }
-        for entry in &self.journal.table.clone() {
-            let o_address = *entry.0;
-            let d_address = *entry.1;
-            let ino = self.pit.get_page(o_address);
-            let data = self.read_page(o_address, true);
-            let v_address = self.vam.get_virtual_address(o_address);
-            if v_address.is_some() {
-                self.vam.update_map(d_address, v_address.unwrap());
-            }
-            self.write_page(d_address, data, true);
-            self.dirty_pit(o_address);
-            self.update_bit(d_address, true);
-            self.update_pit(d_address, ino);
-            let mut raw_inode = self.get_raw_inode(ino);
-            for entry in raw_inode.data.iter_mut() {
-                if entry.address == o_address {
-                    entry.address = d_address;
-                    break;
-                }
-            }
-            self.update_raw_inode(raw_inode);
-        }
         self.bit_end_op();
         self.pit_end_op();
         self.erase_block(block_no, true);
@@ -604,7 +816,12 @@ impl CoreManager {
             panic!("CoreManager: write block not matched size");
         }
         let address = block_no * 128;
+        // blocks are erased before they are written and erased pages read as zero,
+        // so the mostly empty region tables only program the pages that hold data
         for (index, data) in data.iter().enumerate() {
+            if data == [0; 4096] {
+                continue;
+            }
             self.write_page(address + index as u32, data, is_main);
         }
     }
@@ -625,21 +842,184 @@ impl CoreManager {
         self.read_page(address, true)
     }
 
+    pub fn set_compress_policy(&mut self, policy: compress::CompressPolicy) {
+        self.compress_policy = policy;
+    }
+
+    pub fn get_compress_policy(&self) -> compress::CompressPolicy {
+        self.compress_policy
+    }
+
+    pub fn set_dedup(&mut self, enabled: bool) {
+        self.dedup_enabled = enabled;
+    }
+
+    pub fn decompress(&mut self, data: &[u8], compress_type: compress::CompressType) -> Vec<u8> {
+        self.compress.decode(data, compress_type)
+    }
+
+    fn compress_content(&mut self, content: &[u8], size: u32, policy: compress::CompressPolicy) -> (Vec<u8>, compress::CompressType) {
+        // a single page can't shrink
+        if size < 2 {
+            return (content.to_vec(), compress::CompressType::None);
+        }
+        let (data, compress_type) = self.compress.encode_with_policy(content, policy);
+        if compress_type == compress::CompressType::None
+            || data.len().div_ceil(4096) >= size as usize
+        {
+            return (content.to_vec(), compress::CompressType::None);
+        }
+        (data, compress_type)
+    }
+
+    pub fn set_crypt_key(&mut self, key: [u8; 32]) {
+        self.crypt.set_key(key);
+    }
+
+    pub fn has_crypt_key(&self) -> bool {
+        self.crypt.has_key()
+    }
+
+    pub fn new_crypt_context(&mut self) -> Option<Vec<u8>> {
+        self.crypt.new_context().map(|context| context.encode())
+    }
+
+    pub fn can_crypt(&self, context: &[u8]) -> bool {
+        match crypt::CryptContext::decode(context) {
+            Some(context) => self.crypt.can_crypt(&context),
+            None => false,
+        }
+    }
+
+    pub fn decrypt(&self, context: &[u8], entry: &inode::InodeEntry, data: &mut [u8]) -> bool {
+        match crypt::CryptContext::decode(context) {
+            Some(context) => {
+                let aad = crypt::entry_aad(entry.offset, entry.len);
+                self.crypt.open(&context, &entry.crypt_nonce, &aad, data, &entry.crypt_tag)
+            },
+            None => false,
+        }
+    }
+
+    fn encrypt_content(&mut self, inode: &inode::Inode, offset: u64, len: u64, content: &mut [u8]) -> Option<([u8; 12], [u8; 16])> {
+        let context = inode.get_xattr(crypt::CRYPT_XATTR)?;
+        let context = crypt::CryptContext::decode(&context).expect("CoreManager: encrypt content context not valid");
+        let nonce = self.crypt.new_nonce();
+        let tag = self.crypt.seal(&context, &nonce, &crypt::entry_aad(offset, len), content)
+            .expect("CoreManager: encrypt content key not available");
+        Some((nonce, tag))
+    }
+
+    fn find_same_page(&mut self, fingerprint: u64, page: &[u8; 4096]) -> Option<u32> {
+        // fingerprints may collide, so candidates are compared byte by byte
+        for address in self.kv.get_fingerprint_pages(fingerprint) {
+            if self.read_page(address, true) == *page {
+                return Some(address);
+            }
+        }
+        None
+    }
+
+    fn find_same_pages(&mut self, pages: &[[u8; 4096]]) -> Vec<PageSource> {
+        let mut sources: Vec<PageSource> = vec![];
+        let mut new_pages: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, page) in pages.iter().enumerate() {
+            let fingerprint = CoreManager::fingerprint(page);
+            let source = match self.find_same_page(fingerprint, page) {
+                Some(address) => PageSource::Stored(address),
+                None => {
+                    let candidates = new_pages.entry(fingerprint).or_default();
+                    match candidates.iter().find(|candidate| pages[**candidate] == *page) {
+                        Some(candidate) => PageSource::Same(*candidate),
+                        None => {
+                            candidates.push(index);
+                            PageSource::New
+                        },
+                    }
+                },
+            };
+            sources.push(source);
+        }
+        sources
+    }
+
+    fn allocate_pages(&mut self, pages: &[[u8; 4096]], dedup: bool) -> (u32, Vec<PageSource>) {
+        if !dedup {
+            return (self.find_next_pos_to_write(pages.len() as u32), vec![PageSource::New; pages.len()]);
+        }
+        // only new pages need space, but GC may move stored copies while making room,
+        // so they are looked up again afterwards and everything starts over if one went missing
+        loop {
+            let sources = self.find_same_pages(pages);
+            let size = sources.iter().filter(|source| **source == PageSource::New).count() as u32;
+            let address = if size > 0 { self.find_next_pos_to_write(size) } else { 0 };
+            if !sources.iter().any(|source| matches!(source, PageSource::Stored(_))) {
+                return (address, sources);
+            }
+            let checked = self.find_same_pages(pages);
+            if sources.iter().zip(checked.iter()).all(|(before, after)| !matches!(before, PageSource::Stored(_)) || matches!(after, PageSource::Stored(_))) {
+                let sources = sources.iter().zip(checked).map(|(before, after)| match before {
+                    PageSource::Stored(_) => after,
+                    _ => *before,
+                }).collect();
+                return (address, sources);
+            }
+        }
+    }
+
+    pub fn get_data_owners(&self, v_address: u32) -> Vec<u32> {
+        let address = self.vam.get_physic_address(v_address).unwrap();
+        self.pit.get_owners(address)
+    }
+
+    fn release_pages(&mut self, ino: u32, v_address: u32, size: u32) {
+        // shared pages only turn dirty when the last owner lets them go
+        for v_address in v_address..v_address + size {
+            let address = self.vam.get_physic_address(v_address).unwrap();
+            self.unshare_pit(address, ino);
+            self.vam.delete_map(address, v_address);
+        }
+    }
+
+    fn merge_entries(&mut self, data: &mut Vec<inode::InodeEntry>) {
+        // merge neighbours which are contiguous both in file and in one block
+        let mut index = 1;
+        while index < data.len() {
+            let prev = data[index - 1];
+            let next = data[index];
+            let prev_end = self.vam.get_physic_address(prev.address + prev.size - 1).unwrap();
+            let next_start = self.vam.get_physic_address(next.address).unwrap();
+            if !prev.is_sealed()
+                && !next.is_sealed()
+                && prev.offset + prev.len == next.offset
+                && prev.address + prev.size == next.address
+                && prev_end + 1 == next_start
+                && prev_end / 128 == (next_start + next.size - 1) / 128
+            {
+                data[index - 1].len += next.len;
+                data[index - 1].size += next.size;
+                data.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
     pub fn dispose_event_group(&mut self, event_group: inode_event::InodeEventGroup) -> Option<inode::Inode> {
         let mut inode = event_group.dup().inode;
         let mut event_group = event_group;
         CoreManager::sort_inode_event(&mut event_group);
         event_group.debug();
+        // sync BIT and PIT once per event group instead of once per page
+        self.bit_begin_op();
+        self.pit_begin_op();
         if event_group.need_delete {
             for entry in inode.data.iter() {
-                let address = self.vam.get_physic_address(entry.address).unwrap();
-                for i in 0..entry.size {
-                    self.dirty_pit(address + i);
-                    let v_address = self.vam.get_virtual_address(address + i).unwrap();
-                    self.vam.delete_map(address, v_address);
-                }
+                self.release_pages(inode.ino, entry.address, entry.size);
             }
             self.kv.delete_inode(inode.ino);
+            self.bit_end_op();
+            self.pit_end_op();
             None
         } else {
             for entry in inode.data.iter_mut() {
@@ -647,36 +1027,86 @@ impl CoreManager {
                     entry.valid = true;
                 }
             }
+            let mut new_size = None;
+            let mut extra_entries = vec![];
             for event in event_group.events {
                 match event {
                     inode_event::InodeEvent::AddContent(event) => {
-                        let mut address = self.find_next_pos_to_write(event.size);
-                        let mut v_address = self.vam.get_available_address(event.size);
+                        let (mut content, compress_type) = self.compress_content(&event.content, event.size, event.compress_policy);
+                        // compressed data is encrypted, the pages on disk and their signatures only see ciphertext
+                        let sealed = self.encrypt_content(&inode, event.offset, event.len, &mut content);
+                        let size = match compress_type {
+                            compress::CompressType::None => event.size,
+                            _ => content.len().div_ceil(4096) as u32,
+                        };
+                        // only pages stored as is are deduplicated, sealed entries are read whole
+                        let dedup = self.dedup_enabled && compress_type == compress::CompressType::None && sealed.is_none();
+                        let pages = (0..size).map(|i| {
+                            let mut page = [0; 4096];
+                            let start = min((i * 4096) as usize, content.len());
+                            let end = min(start + 4096, content.len());
+                            page[..end - start].copy_from_slice(&content[start..end]);
+                            page
+                        }).collect::<Vec<[u8; 4096]>>();
+                        let (mut address, sources) = self.allocate_pages(&pages, dedup);
+                        let mut v_address = self.vam.get_available_address(size);
+                        let mut addresses = vec![];
                         let entry = inode::InodeEntry {
                             offset: event.offset,
                             len: event.len,
-                            size: event.size,
+                            size,
                             valid: true,
                             address: v_address,
+                            compress_type,
+                            compressed_len: match compress_type {
+                                compress::CompressType::None => 0,
+                                _ => content.len() as u64,
+                            },
+                            encrypted: sealed.is_some(),
+                            crypt_nonce: sealed.map_or([0; 12], |(nonce, _)| nonce),
+                            crypt_tag: sealed.map_or([0; 16], |(_, tag)| tag),
                         };
-                        for i in 0..event.size {
-                            let mut page = [0; 4096];
-                            for j in 0..4096 {
-                                let byte = event.content.get((i * 4096 + j) as usize);
-                                if byte.is_some() {
-                                    page[j as usize] = *byte.unwrap();
-                                } else {
-                                    page[j as usize] = 0;
-                                }
+                        for (page, source) in pages.into_iter().zip(sources) {
+                            let same_address = match source {
+                                PageSource::New => None,
+                                PageSource::Stored(same_address) => Some(same_address),
+                                PageSource::Same(index) => Some(addresses[index]),
+                            };
+                            if let Some(same_address) = same_address {
+                                self.share_pit(same_address, inode.ino);
+                                self.vam.insert_map(same_address, v_address);
+                                addresses.push(same_address);
+                                v_address += 1;
+                                continue;
                             }
+                            addresses.push(address);
                             self.write_page(address, page, true);
                             self.update_bit(address, true);
                             self.update_pit(address, inode.ino);
+                            if dedup {
+                                self.kv.insert_fingerprint(CoreManager::fingerprint(&page), address);
+                            }
                             self.vam.insert_map(address, v_address);
                             address += 1;
                             v_address += 1;
                         }
-                        inode.data.insert(event.index as usize, entry);
+                        if !dedup {
+                            inode.data.insert(event.index as usize, entry);
+                            continue;
+                        }
+                        // pages may now live anywhere, entries are physically contiguous so each page
+                        // gets its own and merge_entries joins the runs that still line up
+                        for i in 0..size {
+                            let mut page_entry = entry;
+                            page_entry.offset = entry.offset + i as u64 * 4096;
+                            page_entry.len = min(4096, entry.len - i as u64 * 4096);
+                            page_entry.size = 1;
+                            page_entry.address = entry.address + i;
+                            match i {
+                                0 => inode.data.insert(event.index as usize, page_entry),
+                                _ => extra_entries.push(page_entry),
+                            }
+                        }
 
                     }
                     inode_event::InodeEvent::TruncateContent(event) => {
@@ -684,12 +1114,7 @@ impl CoreManager {
                         entry.len = event.len;
                         entry.size = event.size;
                         entry.offset = event.offset;
-                        let address = self.vam.get_physic_address(event.v_address).unwrap();
-                        for i in event.size..event.o_size {
-                            self.dirty_pit(address + i);
-                            let v_address = self.vam.get_virtual_address(address + i).unwrap();
-                            self.vam.delete_map(address, v_address);
-                        }
+                        self.release_pages(inode.ino, event.v_address + event.size, event.o_size - event.size);
 
                     }
                     inode_event::InodeEvent::ChangeContent(event) => {
@@ -697,14 +1122,45 @@ impl CoreManager {
                         entry.offset = event.offset;
                         entry.address = event.v_address;
                     }
-                    inode_event::InodeEvent::DeleteContent(event) => {
-                        let mut entry = inode.data.get_mut(event.index as usize).unwrap();
-                        let address = self.vam.get_physic_address(event.v_address).unwrap();
+                    inode_event::InodeEvent::SplitContent(event) => {
+                        let entry = inode::InodeEntry {
+                            offset: event.offset,
+                            len: event.len,
+                            size: event.size,
+                            valid: true,
+                            address: event.v_address,
+                            compress_type: compress::CompressType::None,
+                            compressed_len: 0,
+                            encrypted: false,
+                            crypt_nonce: [0; 12],
+                            crypt_tag: [0; 16],
+                        };
+                        inode.data.insert(event.index as usize, entry);
+                    }
+                    inode_event::InodeEvent::ShareContent(event) => {
+                        let v_address = self.vam.get_available_address(event.size);
                         for i in 0..event.size {
-                            self.dirty_pit(address + i);
-                            let v_address = self.vam.get_virtual_address(address + i).unwrap();
-                            self.vam.delete_map(address, v_address);
+                            let address = self.vam.get_physic_address(event.v_address + i).unwrap();
+                            self.vam.insert_map(address, v_address + i);
+                            self.share_pit(address, inode.ino);
                         }
+                        let entry = inode::InodeEntry {
+                            offset: event.offset,
+                            len: event.len,
+                            size: event.size,
+                            valid: true,
+                            address: v_address,
+                            compress_type: event.compress_type,
+                            compressed_len: event.compressed_len,
+                            encrypted: false,
+                            crypt_nonce: [0; 12],
+                            crypt_tag: [0; 16],
+                        };
+                        inode.data.insert(event.index as usize, entry);
+                    }
+                    inode_event::InodeEvent::DeleteContent(event) => {
+                        let mut entry = inode.data.get_mut(event.index as usize).unwrap();
+                        self.release_pages(inode.ino, event.v_address, event.size);
                         entry.valid = false;
                     }
                     inode_event::InodeEvent::ModifyStat(event) => {
@@ -713,11 +1169,32 @@ impl CoreManager {
                         inode.size = event.size;
                         inode.uid = event.uid;
                         inode.gid = event.gid;
+                        inode.rdev = event.rdev;
                         inode.n_link = event.n_link;
+                        inode.mode = event.mode;
+                        inode.last_accessed = event.last_accessed;
+                        inode.last_modified = event.last_modified;
+                        inode.last_metadata_changed = event.last_metadata_changed;
+                    }
+                    inode_event::InodeEvent::SetXattr(event) => {
+                        match inode.xattr.iter_mut().find(|(name, _)| *name == event.name) {
+                            Some(xattr) => xattr.1 = event.value,
+                            None => inode.xattr.push((event.name, event.value)),
+                        }
+                    }
+                    inode_event::InodeEvent::RemoveXattr(event) => {
+                        inode.xattr.retain(|(name, _)| *name != event.name);
+                    }
+                    inode_event::InodeEvent::SetSize(event) => {
+                        new_size = Some(event.size);
+                    }
+                    inode_event::InodeEvent::SetInlineData(event) => {
+                        inode.inline_data = event.data;
                     }
                     _ => ()
                 }
             }
+            inode.data.extend(extra_entries);
             let mut remove_indexs = vec![];
             for index in 0..inode.data.len() {
                 if !inode.data.get(index).unwrap().valid {
@@ -727,16 +1204,24 @@ impl CoreManager {
             for index in remove_indexs.into_iter().rev() {
                 inode.data.remove(index);
             }
-            let mut size = 0;
+            inode.data.sort_by_key(|entry| entry.offset);
+            self.merge_entries(&mut inode.data);
+            // entries may leave holes, so the size only follows them when it grows
+            let mut end = inode.inline_data.len() as u64;
             for entry in inode.data.iter() {
-                size += entry.len;
+                end = max(end, entry.offset + entry.len);
             }
-            inode.size = size;
+            inode.size = match new_size {
+                Some(size) => size,
+                None => max(inode.size, end),
+            };
             let mut raw_inode = CoreManager::transfer_inode_to_raw_inode(&inode);
             for entry in raw_inode.data.iter_mut() {
                 entry.address = self.vam.get_physic_address(entry.address).unwrap();
             }
             self.kv.update_inode(raw_inode);
+            self.bit_end_op();
+            self.pit_end_op();
             Some(inode)
         }
     }
@@ -750,15 +1235,31 @@ impl CoreManager {
         match raw_inode.file_type {
             0 => file_type = inode::InodeFileType::File,
             1 => file_type = inode::InodeFileType::Directory,
+            2 => file_type = inode::InodeFileType::Fifo,
+            3 => file_type = inode::InodeFileType::Socket,
+            4 => file_type = inode::InodeFileType::CharDevice,
+            5 => file_type = inode::InodeFileType::BlockDevice,
             _ => panic!("CoreManager: transfer raw inode not available file type"),
         }
         for entry in raw_inode.data.iter() {
+            let compress_type = match entry.compress_type {
+                0 => compress::CompressType::None,
+                1 => compress::CompressType::Huffman,
+                2 => compress::CompressType::Snappy,
+                3 => compress::CompressType::Lz,
+                _ => panic!("CoreManager: transfer raw inode not available compress type"),
+            };
             let entry = inode::InodeEntry {
                 len: entry.len,
                 size: entry.size,
                 offset: entry.offset,
                 address: entry.address,
                 valid: true,
+                compress_type,
+                compressed_len: entry.compressed_len,
+                encrypted: entry.encrypted,
+                crypt_nonce: entry.crypt_nonce,
+                crypt_tag: entry.crypt_tag,
             };
             data.push(entry);
         }
@@ -767,12 +1268,15 @@ impl CoreManager {
             size: raw_inode.size,
             uid: raw_inode.uid,
             gid: raw_inode.gid,
+            rdev: raw_inode.rdev,
             ref_cnt: raw_inode.ref_cnt,
             n_link: raw_inode.n_link,
-            lock: Mutex::new(false),
             core: None,
+            dirty: false,
             file_type,
             data,
+            inline_data: raw_inode.inline_data.clone(),
+            xattr: raw_inode.xattr.clone(),
             mode: raw_inode.mode,
             last_accessed: raw_inode.last_accessed,
             last_modified: raw_inode.last_modified,
@@ -786,13 +1290,28 @@ impl CoreManager {
         match inode.file_type {
             inode::InodeFileType::File => file_type = 0,
             inode::InodeFileType::Directory => file_type = 1,
+            inode::InodeFileType::Fifo => file_type = 2,
+            inode::InodeFileType::Socket => file_type = 3,
+            inode::InodeFileType::CharDevice => file_type = 4,
+            inode::InodeFileType::BlockDevice => file_type = 5,
         }
         for entry in inode.data.iter() {
+            let compress_type = match entry.compress_type {
+                compress::CompressType::None => 0,
+                compress::CompressType::Huffman => 1,
+                compress::CompressType::Snappy => 2,
+                compress::CompressType::Lz => 3,
+            };
             let entry = raw_inode::RawEntry {
                 len: entry.len,
                 size: entry.size,
                 offset: entry.offset,
                 address: entry.address,
+                compress_type,
+                compressed_len: entry.compressed_len,
+                encrypted: entry.encrypted,
+                crypt_nonce: entry.crypt_nonce,
+                crypt_tag: entry.crypt_tag,
             };
             data.push(entry);
         }
@@ -805,13 +1324,30 @@ impl CoreManager {
             ref_cnt: inode.ref_cnt,
             file_type,
             data,
+            inline_data: inode.inline_data.clone(),
+            xattr: inode.xattr.clone(),
             mode: inode.mode,
+            rdev: inode.rdev,
             last_accessed: inode.last_accessed,
             last_modified: inode.last_modified,
             last_metadata_changed: inode.last_metadata_changed,
         }
     }
 
+    /// Hash a page for deduplication, FNV-1a over the whole page
+    /// params:
+    /// page - page data
+    /// return:
+    /// fingerprint
+    pub fn fingerprint(page: &[u8; 4096]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in page.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
     pub fn sort_gc_event(event_group: &mut gc_event::GCEventGroup) {
         let len = event_group.events.len();
         for i in 0..len {
@@ -894,6 +1430,7 @@ impl CoreManager {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::inode::inode_manager::InodeManager;
 
     fn init_test() -> CoreManager {
         let mut manager = CoreManager::new();
@@ -906,6 +1443,37 @@ mod test {
 
     }
 
+    #[test]
+    fn statfs() {
+        let mut manager = init_test();
+        let stat = manager.statfs();
+        assert_eq!(stat.bsize, 4096);
+        assert_eq!(stat.blocks, 18 * 128);
+        assert_eq!(stat.bfree, stat.blocks);
+        // the translation layer keeps one spare block beside its table and sign blocks
+        assert_eq!(stat.bavail, stat.blocks - GCRESERVEDPAGENUM as u64 - 128);
+        assert_eq!(stat.files, fake_kv::MAX_INODE_NUM as u64);
+        let mut inode = manager.allocate_inode().unwrap();
+        let stat_1 = manager.statfs();
+        assert_eq!(stat_1.files, stat.files);
+        assert_eq!(stat_1.ffree, stat.ffree - 1);
+        let mut event_group = inode_event::InodeEventGroup::new();
+        inode.size = 0;
+        event_group.inode = inode;
+        event_group.events.push(inode_event::InodeEvent::AddContent(inode_event::AddContentInodeEvent {
+            index: 0,
+            offset: 0,
+            len: 4096 * 3,
+            size: 3,
+            content: vec![1; 4096 * 3],
+            compress_policy: compress::CompressPolicy::Off,
+        }));
+        manager.dispose_event_group(event_group);
+        let stat_2 = manager.statfs();
+        assert_eq!(stat_2.bfree, stat.bfree - 3);
+        assert_eq!(stat_2.bavail, stat.bavail - 3);
+    }
+
     #[test]
     fn bit() {
         let mut manager = init_test();
@@ -979,9 +1547,9 @@ mod test {
     #[test]
     fn kv() {
         let mut manager = init_test();
-        let _ = manager.allocate_inode();
-        let _ = manager.allocate_inode();
-        let mut inode = manager.allocate_inode();
+        let _ = manager.allocate_inode().unwrap();
+        let _ = manager.allocate_inode().unwrap();
+        let mut inode = manager.allocate_inode().unwrap();
         inode.n_link = 3;
         manager.update_inode(inode);
         let inode = manager.get_inode(3);
@@ -997,7 +1565,7 @@ mod test {
     #[test]
     fn gc() {
         let mut manager = init_test();
-        manager.allocate_inode();
+        manager.allocate_inode().unwrap();
         assert_eq!(manager.find_next_pos_to_write(10), 0);
         manager.bit_begin_op();
         manager.pit_begin_op();
@@ -1014,6 +1582,38 @@ mod test {
         manager.forward_gc();
     }
 
+    #[test]
+    fn dedup() {
+        let mut manager = init_test();
+        manager.allocate_inode().unwrap();
+        manager.allocate_inode().unwrap();
+        let page = [3; 4096];
+        let fingerprint = CoreManager::fingerprint(&page);
+        assert_ne!(fingerprint, CoreManager::fingerprint(&[4; 4096]));
+        manager.bit_begin_op();
+        manager.pit_begin_op();
+        manager.write_page(0, page, true);
+        manager.update_bit(0, true);
+        manager.update_pit(0, 1);
+        manager.kv.insert_fingerprint(fingerprint, 0);
+        manager.share_pit(0, 2);
+        manager.bit_end_op();
+        manager.pit_end_op();
+        assert_eq!(manager.find_same_page(fingerprint, &page), Some(0));
+        let mut other = page;
+        other[4095] = 4;
+        assert_eq!(manager.find_same_page(fingerprint, &other), None);
+        // the index follows pages moved by GC and forgets them once the last reference goes
+        manager.move_pages(0, 200, 1);
+        assert_eq!(manager.find_same_page(fingerprint, &page), Some(200));
+        assert_eq!(manager.pit.get_ref_count(200), 2);
+        manager.unshare_pit(200, 1);
+        assert_eq!(manager.find_same_page(fingerprint, &page), Some(200));
+        manager.unshare_pit(200, 2);
+        assert_eq!(manager.find_same_page(fingerprint, &page), None);
+        assert_eq!(manager.gc.get_page(200), PageUsedStatus::Dirty);
+    }
+
     #[test]
     fn underlay() {
         let mut manager = init_test();
@@ -1040,6 +1640,52 @@ mod test {
         assert_eq!(manager.read_page(100, false), [0; 4096]);
     }
 
+    #[test]
+    fn remount() {
+        let manager = InodeManager::new();
+        manager.core_manager.lock().unwrap().mount();
+        let kept = manager.i_alloc().unwrap();
+        let kept_ino = kept.read().unwrap().ino;
+        let mut stat = kept.read().unwrap().get_stat();
+        stat.n_link = 1;
+        kept.write().unwrap().modify_stat(stat);
+        assert!(kept.write().unwrap().write(0, 5000, &vec![5; 5000]));
+        manager.i_put(kept);
+        let ffree = manager.core_manager.lock().unwrap().statfs().ffree;
+
+        // unlinked while open and never closed before the crash
+        let orphan = manager.i_alloc().unwrap();
+        let ino = orphan.read().unwrap().ino;
+        assert!(orphan.write().unwrap().write(0, 5000, &vec![6; 5000]));
+        manager.core_manager.lock().unwrap().add_orphan_inode(ino);
+        manager.sync();
+        let mut core = manager.core_manager.lock().unwrap();
+        core.sync();
+        let addresses = core.get_raw_inode(ino).data.iter().flat_map(|entry| entry.address..entry.address + entry.size).collect::<Vec<u32>>();
+        assert!(!addresses.is_empty());
+        let buf_cache = std::mem::replace(&mut core.buf_cache, buf::BufCache::new());
+        drop(core);
+        drop(orphan);
+        drop(manager);
+
+        let manager = InodeManager::new();
+        let mut core = manager.core_manager.lock().unwrap();
+        core.buf_cache = buf_cache;
+        core.mount();
+        assert!(!core.has_inode(ino));
+        assert!(!core.is_orphan_inode(ino));
+        assert_eq!(core.statfs().ffree, ffree);
+        for address in addresses {
+            assert_eq!(core.gc.get_page(address), PageUsedStatus::Dirty);
+        }
+        drop(core);
+        let kept = manager.i_get(kept_ino).unwrap();
+        let mut buf = vec![];
+        assert_eq!(kept.write().unwrap().read_all(&mut buf), 5000);
+        assert_eq!(buf, vec![5; 5000]);
+        manager.i_put(kept);
+    }
+
     #[test]
     fn inode() {
         
@@ -1073,13 +1719,41 @@ mod test {
             ref_cnt: 3,
             file_type: 1,
             data: vec![],
+            inline_data: vec![],
+            xattr: vec![],
             mode: 0,
+            rdev: 0,
             last_accessed: (0, 0),
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
         };
         let inode = CoreManager::transfer_raw_inode_to_inode(&raw_inode);
         assert_eq!(inode.file_type, inode::InodeFileType::Directory);
+
+        let raw_inode = raw_inode::RawInode {
+            ino: 11,
+            uid: 1,
+            gid: 2,
+            size: 0,
+            n_link: 1,
+            ref_cnt: 0,
+            file_type: 4,
+            data: vec![],
+            inline_data: vec![],
+            xattr: vec![("user.k".to_string(), vec![7; 3])],
+            mode: 0o644,
+            rdev: 0x0103,
+            last_accessed: (0, 0),
+            last_modified: (0, 0),
+            last_metadata_changed: (0, 0),
+        };
+        let inode = CoreManager::transfer_raw_inode_to_inode(&raw_inode);
+        assert_eq!(inode.file_type, inode::InodeFileType::CharDevice);
+        assert_eq!(inode.rdev, 0x0103);
+        let raw_inode = CoreManager::transfer_inode_to_raw_inode(&inode);
+        assert_eq!(raw_inode.file_type, 4);
+        assert_eq!(raw_inode.rdev, 0x0103);
+        assert_eq!(raw_inode.xattr, vec![("user.k".to_string(), vec![7; 3])]);
         
         let mut inode = inode::Inode::new();
         inode.ino = 12;
@@ -19,7 +19,7 @@ pub enum PITStrategy {
 // PIT Region Main Structure
 pub struct PIT {
     pub page_num: u32,
-    pub table: HashMap<u32, u32>,  // address -> ino
+    pub table: HashMap<u32, Vec<u32>>,  // address -> owner inos, one per reference so an ino may repeat
     pub sync: bool,                // true 需要持久化到磁盘中
     pub is_op: bool,               // true 等调用end_op才持久化到磁盘中
 }
@@ -40,29 +40,59 @@ impl PIT {
     }
 
     pub fn init_page(&mut self, address: u32, status: u32) {
-        if self.table.contains_key(&address) {
-            panic!("PIT: init page has exist");
-        }
-        self.table.insert(address, status);
+        self.table.entry(address).or_insert_with(Vec::new).push(status);
     }
 
     pub fn get_page(&self, address: u32) -> u32 {
         if !self.table.contains_key(&address) {
             panic!("PIT: get not that page");
         }
-        self.table.get(&address).unwrap().clone()
+        self.table.get(&address).unwrap()[0]
+    }
+
+    pub fn get_owners(&self, address: u32) -> Vec<u32> {
+        match self.table.get(&address) {
+            Some(owners) => owners.clone(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_ref_count(&self, address: u32) -> u32 {
+        match self.table.get(&address) {
+            Some(owners) => owners.len() as u32,
+            None => 0,
+        }
     }
 
     pub fn set_page(&mut self, address: u32, status: u32) {
+        self.table.insert(address, vec![status]);
+        self.sync = true;
+    }
+
+    pub fn add_owner(&mut self, address: u32, ino: u32) {
         if !self.table.contains_key(&address) {
-            self.table.insert(address, status);
-            self.sync = true;
-            return;
+            panic!("PIT: add owner not that page");
         }
-        *self.table.get_mut(&address).unwrap() = status;
+        self.table.get_mut(&address).unwrap().push(ino);
         self.sync = true;
     }
 
+    pub fn remove_owner(&mut self, address: u32, ino: u32) -> Vec<u32> {
+        if !self.table.contains_key(&address) {
+            panic!("PIT: remove owner not that page");
+        }
+        let owners = self.table.get_mut(&address).unwrap();
+        if let Some(index) = owners.iter().position(|owner| *owner == ino) {
+            owners.remove(index);
+        }
+        let owners = owners.clone();
+        if owners.is_empty() {
+            self.table.remove(&address);
+        }
+        self.sync = true;
+        owners
+    }
+
     pub fn delete_page(&mut self, address: u32) {
         if !self.table.contains_key(&address) {
             panic!("PIT: delete not that page");
@@ -119,6 +149,10 @@ impl PIT {
 // PIT Region Internal Function
 impl PIT {
     fn choose_strategy(&self) -> PITStrategy {
+        // serial layout keeps one owner per page
+        if self.table.values().any(|owners| owners.len() > 1) {
+            return PITStrategy::Map;
+        }
         let num = self.table.len();
         let multiples =  num as f32 / self.page_num as f32;
         if multiples < 0.5 {
@@ -132,7 +166,7 @@ impl PIT {
         let mut res = array::Array1::<u32>::new(128 * 4096 / 4 - 2);
         res.init(0);
         for (key, value) in &self.table {
-            res.set(*key, *value);
+            res.set(*key, value[0]);
         }
         let mut data = array::Array1::<u8>::new(128 * 4096);
         data.init(0);
@@ -162,7 +196,7 @@ impl PIT {
         data.set(2, 0xdd);
         data.set(3, 0xdd);
         let mut index = 0;
-        for (key, value) in &self.table {
+        for (key, value) in self.table.iter().flat_map(|(key, owners)| owners.iter().map(move |owner| (key, owner))) {
             let start_index = 8 + index * 8;
             let byte_1 = (*key >> 24) as u8;
             let byte_2 = (*key >> 16) as u8;
@@ -288,4 +322,47 @@ mod test {
         assert_eq!(pit.get_page(200), 100);
         assert_eq!(pit.need_sync(), true);
     }
+
+    #[test]
+    fn shared() {
+        let mut pit = PIT::new();
+        pit.set_page_num(128);
+        pit.set_page(5, 1);
+        pit.add_owner(5, 2);
+        pit.set_page(6, 1);
+        let data = CoreManager::transfer(&pit.encode());
+        let mut other = PIT::new();
+        for (index, ino) in DataRegion::new(&data, PITStrategy::Map) {
+            other.init_page(index, ino);
+        }
+        let mut owners = other.get_owners(5);
+        owners.sort();
+        assert_eq!(owners, vec![1, 2]);
+        assert_eq!(other.get_owners(6), vec![1]);
+        assert_eq!(pit.remove_owner(5, 1), vec![2]);
+        assert_eq!(pit.remove_owner(5, 2), vec![]);
+        assert_eq!(pit.get_owners(5), vec![]);
+    }
+
+    #[test]
+    fn ref_count() {
+        let mut pit = PIT::new();
+        pit.set_page_num(128);
+        // deduplicated pages are referenced more than once by the same inode
+        pit.set_page(5, 1);
+        pit.add_owner(5, 1);
+        pit.add_owner(5, 2);
+        assert_eq!(pit.get_ref_count(5), 3);
+        let data = CoreManager::transfer(&pit.encode());
+        let mut other = PIT::new();
+        for (index, ino) in DataRegion::new(&data, PITStrategy::Map) {
+            other.init_page(index, ino);
+        }
+        assert_eq!(other.get_ref_count(5), 3);
+        assert_eq!(pit.remove_owner(5, 1), vec![1, 2]);
+        assert_eq!(pit.remove_owner(5, 1), vec![2]);
+        assert_eq!(pit.get_ref_count(5), 1);
+        pit.remove_owner(5, 2);
+        assert_eq!(pit.get_ref_count(5), 0);
+    }
 }
@@ -7,7 +7,7 @@ use std::collections::HashMap;
 // VAM Manager Main Structure
 pub struct VAM {
     count: u32,
-    physical_address_table: HashMap<u32, u32>, // physical -> virtual
+    physical_address_table: HashMap<u32, Vec<u32>>, // physical -> virtual, shared pages have more than one
     virtual_address_table: HashMap<u32, u32>,  // virtual -> physical
 }
 
@@ -31,12 +31,19 @@ impl VAM {
         let address = self.physical_address_table.get(&address);
         match address {
             Some(address) => {
-                Some(address.clone())
+                Some(address[0])
             }
             None => None
         }
     }
 
+    pub fn get_virtual_addresses(&self, address: u32) -> Vec<u32> {
+        match self.physical_address_table.get(&address) {
+            Some(v_addresses) => v_addresses.clone(),
+            None => vec![],
+        }
+    }
+
     pub fn get_physic_address(&self, v_address: u32) -> Option<u32> {
         let address = self.virtual_address_table.get(&v_address);
         match address {
@@ -51,7 +58,7 @@ impl VAM {
         // if self.physical_address_table.contains_key(&address) {
             // panic!("VAM: insert map has exist");
         // }
-        self.physical_address_table.insert(address, v_address);
+        self.physical_address_table.entry(address).or_insert_with(Vec::new).push(v_address);
         self.virtual_address_table.insert(v_address, address);
     }
 
@@ -68,7 +75,11 @@ impl VAM {
         if !self.physical_address_table.contains_key(&address) {
             panic!("VAM: delete no that map");
         }
-        self.physical_address_table.remove(&address);
+        let v_addresses = self.physical_address_table.get_mut(&address).unwrap();
+        v_addresses.retain(|v| *v != v_address);
+        if v_addresses.is_empty() {
+            self.physical_address_table.remove(&address);
+        }
         self.virtual_address_table.remove(&v_address);
     }
 }
@@ -98,5 +109,11 @@ mod test {
         vam.delete_map(100, 13);
         assert_eq!(vam.get_physic_address(13), None);
         assert_eq!(vam.get_virtual_address(100), None);
+
+        vam.insert_map(4, 30);
+        assert_eq!(vam.get_virtual_addresses(4), vec![14, 30]);
+        vam.update_map(200, 14);
+        assert_eq!(vam.get_virtual_addresses(4), vec![30]);
+        assert_eq!(vam.get_virtual_addresses(200), vec![14]);
     }
 }
\ No newline at end of file
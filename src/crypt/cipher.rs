@@ -0,0 +1,248 @@
+//
+// ChaCha20-Poly1305 AEAD (RFC 8439) and HChaCha20
+//
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[0..4].try_into().unwrap())
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn chacha_rounds(state: &mut [u32; 16]) {
+    for _ in 0..10 {
+        quarter_round(state, 0, 4, 8, 12);
+        quarter_round(state, 1, 5, 9, 13);
+        quarter_round(state, 2, 6, 10, 14);
+        quarter_round(state, 3, 7, 11, 15);
+        quarter_round(state, 0, 5, 10, 15);
+        quarter_round(state, 1, 6, 11, 12);
+        quarter_round(state, 2, 7, 8, 13);
+        quarter_round(state, 3, 4, 9, 14);
+    }
+}
+
+fn chacha_state(key: &[u8; KEY_SIZE], input: &[u8; 16]) -> [u32; 16] {
+    let mut state = [0u32; 16];
+    state[0..4].copy_from_slice(&CHACHA_CONSTANTS);
+    for i in 0..8 {
+        state[4 + i] = le32(&key[i * 4..]);
+    }
+    for i in 0..4 {
+        state[12 + i] = le32(&input[i * 4..]);
+    }
+    state
+}
+
+pub fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; 64] {
+    let mut input = [0u8; 16];
+    input[0..4].copy_from_slice(&counter.to_le_bytes());
+    input[4..16].copy_from_slice(nonce);
+    let initial = chacha_state(key, &input);
+    let mut state = initial;
+    chacha_rounds(&mut state);
+    let mut block = [0u8; 64];
+    for i in 0..16 {
+        block[i * 4..i * 4 + 4].copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+pub fn chacha20_xor(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+    for (index, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(index as u32), nonce);
+        for (byte, key_byte) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+pub fn hchacha20(key: &[u8; KEY_SIZE], input: &[u8; 16]) -> [u8; KEY_SIZE] {
+    let mut state = chacha_state(key, input);
+    chacha_rounds(&mut state);
+    let mut subkey = [0u8; KEY_SIZE];
+    for i in 0..4 {
+        subkey[i * 4..i * 4 + 4].copy_from_slice(&state[i].to_le_bytes());
+        subkey[16 + i * 4..16 + i * 4 + 4].copy_from_slice(&state[12 + i].to_le_bytes());
+    }
+    subkey
+}
+
+// Poly1305 over 26 bit limbs
+pub fn poly1305(key: &[u8; KEY_SIZE], msg: &[u8]) -> [u8; TAG_SIZE] {
+    const MASK: u32 = 0x3ffffff;
+    let r0 = le32(&key[0..]) & 0x3ffffff;
+    let r1 = (le32(&key[3..]) >> 2) & 0x3ffff03;
+    let r2 = (le32(&key[6..]) >> 4) & 0x3ffc0ff;
+    let r3 = (le32(&key[9..]) >> 6) & 0x3f03fff;
+    let r4 = (le32(&key[12..]) >> 8) & 0x00fffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+    for chunk in msg.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        // full blocks carry the 2^128 bit, a short last block is padded with a one byte
+        let hibit = match chunk.len() {
+            16 => 1 << 24,
+            len => {
+                block[len] = 1;
+                0
+            },
+        };
+        h0 += le32(&block[0..]) & MASK;
+        h1 += (le32(&block[3..]) >> 2) & MASK;
+        h2 += (le32(&block[6..]) >> 4) & MASK;
+        h3 += (le32(&block[9..]) >> 6) & MASK;
+        h4 += (le32(&block[12..]) >> 8) | hibit;
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m(h0, r0) + m(h1, s4) + m(h2, s3) + m(h3, s2) + m(h4, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s4) + m(h3, s3) + m(h4, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0) + m(h3, s4) + m(h4, s3);
+        let mut d3 = m(h0, r3) + m(h1, r2) + m(h2, r1) + m(h3, r0) + m(h4, s4);
+        let mut d4 = m(h0, r4) + m(h1, r3) + m(h2, r2) + m(h3, r1) + m(h4, r0);
+        h0 = d0 as u32 & MASK;
+        d1 += d0 >> 26;
+        h1 = d1 as u32 & MASK;
+        d2 += d1 >> 26;
+        h2 = d2 as u32 & MASK;
+        d3 += d2 >> 26;
+        h3 = d3 as u32 & MASK;
+        d4 += d3 >> 26;
+        h4 = d4 as u32 & MASK;
+        h0 += (d4 >> 26) as u32 * 5;
+        h1 += h0 >> 26;
+        h0 &= MASK;
+    }
+    // full carry, then h - p is taken when it doesn't borrow
+    let mut c;
+    c = h1 >> 26; h1 &= MASK; h2 += c;
+    c = h2 >> 26; h2 &= MASK; h3 += c;
+    c = h3 >> 26; h3 &= MASK; h4 += c;
+    c = h4 >> 26; h4 &= MASK; h0 += c * 5;
+    c = h0 >> 26; h0 &= MASK; h1 += c;
+    let mut g0 = h0 + 5;
+    c = g0 >> 26; g0 &= MASK;
+    let mut g1 = h1 + c;
+    c = g1 >> 26; g1 &= MASK;
+    let mut g2 = h2 + c;
+    c = g2 >> 26; g2 &= MASK;
+    let mut g3 = h3 + c;
+    c = g3 >> 26; g3 &= MASK;
+    let g4 = (h4 + c).wrapping_sub(1 << 26);
+    let select = (g4 >> 31).wrapping_sub(1);
+    h0 = (h0 & !select) | (g0 & select);
+    h1 = (h1 & !select) | (g1 & select);
+    h2 = (h2 & !select) | (g2 & select);
+    h3 = (h3 & !select) | (g3 & select);
+    h4 = (h4 & !select) | (g4 & select);
+    let words = [
+        h0 | (h1 << 26),
+        (h1 >> 6) | (h2 << 20),
+        (h2 >> 12) | (h3 << 14),
+        (h3 >> 18) | (h4 << 8),
+    ];
+    let mut tag = [0u8; TAG_SIZE];
+    let mut carry = 0u64;
+    for i in 0..4 {
+        let f = words[i] as u64 + le32(&key[16 + i * 4..]) as u64 + carry;
+        tag[i * 4..i * 4 + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        carry = f >> 32;
+    }
+    tag
+}
+
+fn aead_tag(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+    let block = chacha20_block(key, 0, nonce);
+    let otk: [u8; KEY_SIZE] = block[0..32].try_into().unwrap();
+    let mut msg = Vec::with_capacity(aad.len() + data.len() + 48);
+    msg.extend_from_slice(aad);
+    msg.resize(aad.len().div_ceil(16) * 16, 0);
+    msg.extend_from_slice(data);
+    msg.resize(msg.len().div_ceil(16) * 16, 0);
+    msg.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    msg.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    poly1305(&otk, &msg)
+}
+
+/// Encrypt data in place
+/// params:
+/// key - 256 bit key
+/// nonce - 96 bit nonce, never reused with the same key
+/// aad - data authenticated but not encrypted
+/// data - plaintext, ciphertext after return
+/// return:
+/// authentication tag
+pub fn seal(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE] {
+    chacha20_xor(key, 1, nonce, data);
+    aead_tag(key, nonce, aad, data)
+}
+
+/// Verify and decrypt data in place
+/// params:
+/// key - 256 bit key
+/// nonce - nonce used to seal
+/// aad - data authenticated but not encrypted
+/// data - ciphertext, plaintext after return if verified
+/// tag - authentication tag
+/// return:
+/// verify result, data is untouched on failure
+pub fn open(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &mut [u8], tag: &[u8; TAG_SIZE]) -> bool {
+    let expected = aead_tag(key, nonce, aad, data);
+    let diff = expected.iter().zip(tag.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return false;
+    }
+    chacha20_xor(key, 1, nonce, data);
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn basics() {
+        let key: [u8; 32] = (0x80..0xa0).collect::<Vec<u8>>().try_into().unwrap();
+        let nonce: [u8; 12] = hex("070000004041424344454647").try_into().unwrap();
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        let mut data = plaintext.clone();
+        let tag = seal(&key, &nonce, &aad, &mut data);
+        assert_eq!(&data[..16], &hex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+        assert_eq!(tag.to_vec(), hex("1ae10b594f09e26a7e902ecbd0600691"));
+        let mut tampered = data.clone();
+        tampered[3] ^= 1;
+        assert!(!open(&key, &nonce, &aad, &mut tampered, &tag));
+        assert!(!open(&key, &nonce, b"", &mut data.clone(), &tag));
+        assert!(open(&key, &nonce, &aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+
+        let key: [u8; 32] = hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").try_into().unwrap();
+        assert_eq!(poly1305(&key, b"Cryptographic Forum Research Group").to_vec(), hex("a8061dc1305136c6c22b8baf0c0127a9"));
+        let mut msg = vec![0xff; 16];
+        msg.push(1);
+        assert_eq!(poly1305(&[0xff; 32], &msg).to_vec(), hex("66ff7f1751fc1f474bfcbf7645fc5fa6"));
+
+        let key: [u8; 32] = (0..32).collect::<Vec<u8>>().try_into().unwrap();
+        let input: [u8; 16] = hex("000000090000004a0000000031415927").try_into().unwrap();
+        assert_eq!(hchacha20(&key, &input).to_vec(), hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::Read;
+use std::time::SystemTime;
+use crate::crypt::cipher;
+
+// Extended attribute holding the encryption context, not reachable through FUSE
+pub const CRYPT_XATTR: &str = "system.wondfs.crypt";
+
+pub const CRYPT_CONTEXT_VERSION: u8 = 1;
+
+// Context is the version, the master key identifier and the per file nonce
+pub const CRYPT_CONTEXT_SIZE: usize = 1 + 8 + 16;
+
+const KEY_IDENTIFIER_INPUT: &[u8; 16] = b"wondfs key ident";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CryptContext {
+    pub key_id: [u8; 8],
+    pub nonce: [u8; 16],
+}
+
+impl CryptContext {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![CRYPT_CONTEXT_VERSION];
+        data.extend_from_slice(&self.key_id);
+        data.extend_from_slice(&self.nonce);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != CRYPT_CONTEXT_SIZE || data[0] != CRYPT_CONTEXT_VERSION {
+            return None;
+        }
+        Some(Self {
+            key_id: data[1..9].try_into().unwrap(),
+            nonce: data[9..25].try_into().unwrap(),
+        })
+    }
+}
+
+// ChaCha20 keystream seeded once from the system
+struct CryptRng {
+    key: [u8; cipher::KEY_SIZE],
+    counter: u32,
+}
+
+impl CryptRng {
+    fn new() -> Self {
+        let mut key = [0u8; cipher::KEY_SIZE];
+        let seeded = std::fs::File::open("/dev/urandom")
+            .and_then(|mut file| file.read_exact(&mut key))
+            .is_ok();
+        if !seeded {
+            let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+            let mut input = [0u8; 16];
+            input.copy_from_slice(&nanos.to_le_bytes());
+            key[0..4].copy_from_slice(&std::process::id().to_le_bytes());
+            key = cipher::hchacha20(&key, &input);
+        }
+        Self {
+            key,
+            counter: 0,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = cipher::chacha20_block(&self.key, self.counter, &[0; cipher::NONCE_SIZE]);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            self.counter += 1;
+            if self.counter == u32::MAX {
+                let block = cipher::chacha20_block(&self.key, self.counter, &[0; cipher::NONCE_SIZE]);
+                self.key.copy_from_slice(&block[..cipher::KEY_SIZE]);
+                self.counter = 0;
+            }
+        }
+    }
+}
+
+pub struct CryptManager {
+    key: Option<[u8; cipher::KEY_SIZE]>,
+    key_id: [u8; 8],
+    rng: CryptRng,
+}
+
+impl CryptManager {
+    pub fn new() -> Self {
+        Self {
+            key: None,
+            key_id: [0; 8],
+            rng: CryptRng::new(),
+        }
+    }
+
+    pub fn set_key(&mut self, key: [u8; cipher::KEY_SIZE]) {
+        self.key_id.copy_from_slice(&cipher::hchacha20(&key, KEY_IDENTIFIER_INPUT)[..8]);
+        self.key = Some(key);
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn can_crypt(&self, context: &CryptContext) -> bool {
+        self.key.is_some() && context.key_id == self.key_id
+    }
+
+    pub fn new_context(&mut self) -> Option<CryptContext> {
+        self.key?;
+        let mut nonce = [0u8; 16];
+        self.rng.fill(&mut nonce);
+        Some(CryptContext {
+            key_id: self.key_id,
+            nonce,
+        })
+    }
+
+    pub fn new_nonce(&mut self) -> [u8; cipher::NONCE_SIZE] {
+        let mut nonce = [0u8; cipher::NONCE_SIZE];
+        self.rng.fill(&mut nonce);
+        nonce
+    }
+
+    pub fn seal(&self, context: &CryptContext, nonce: &[u8; cipher::NONCE_SIZE], aad: &[u8], data: &mut [u8]) -> Option<[u8; cipher::TAG_SIZE]> {
+        let key = self.file_key(context)?;
+        Some(cipher::seal(&key, nonce, aad, data))
+    }
+
+    pub fn open(&self, context: &CryptContext, nonce: &[u8; cipher::NONCE_SIZE], aad: &[u8], data: &mut [u8], tag: &[u8; cipher::TAG_SIZE]) -> bool {
+        match self.file_key(context) {
+            Some(key) => cipher::open(&key, nonce, aad, data, tag),
+            None => false,
+        }
+    }
+
+    fn file_key(&self, context: &CryptContext) -> Option<[u8; cipher::KEY_SIZE]> {
+        if !self.can_crypt(context) {
+            return None;
+        }
+        Some(cipher::hchacha20(self.key.as_ref().unwrap(), &context.nonce))
+    }
+}
+
+// Entries are bound to their place in the file
+pub fn entry_aad(offset: u64, len: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[0..8].copy_from_slice(&offset.to_le_bytes());
+    aad[8..16].copy_from_slice(&len.to_le_bytes());
+    aad
+}
+
+/// Parse a master key
+/// params:
+/// data - 32 raw bytes or 64 hex digits, surrounding whitespace is ignored
+/// return:
+/// key if data is well formed
+pub fn parse_key(data: &[u8]) -> Option<[u8; cipher::KEY_SIZE]> {
+    if data.len() == cipher::KEY_SIZE {
+        return Some(data.try_into().unwrap());
+    }
+    let text = std::str::from_utf8(data).ok()?.trim();
+    if text.len() != cipher::KEY_SIZE * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; cipher::KEY_SIZE];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn basics() {
+        let mut manager = CryptManager::new();
+        assert!(manager.new_context().is_none());
+        manager.set_key(parse_key("00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n".as_bytes()).unwrap());
+        assert!(parse_key(b"0011").is_none());
+        let context = manager.new_context().unwrap();
+        assert_eq!(CryptContext::decode(&context.encode()), Some(context));
+        assert!(CryptContext::decode(&context.encode()[1..]).is_none());
+        assert_ne!(manager.new_context().unwrap().nonce, context.nonce);
+
+        let nonce = manager.new_nonce();
+        let mut data = b"customer data".to_vec();
+        let tag = manager.seal(&context, &nonce, b"0", &mut data).unwrap();
+        assert_ne!(data, b"customer data".to_vec());
+        assert!(!manager.open(&context, &nonce, b"1", &mut data, &tag));
+        assert!(manager.open(&context, &nonce, b"0", &mut data, &tag));
+        assert_eq!(data, b"customer data".to_vec());
+
+        // files use their own keys, another master key can't open anything
+        let other = manager.new_context().unwrap();
+        let mut copy = data.clone();
+        let tag = manager.seal(&context, &nonce, b"0", &mut data).unwrap();
+        manager.seal(&other, &nonce, b"0", &mut copy).unwrap();
+        assert_ne!(data, copy);
+        let mut manager = CryptManager::new();
+        manager.set_key([7; 32]);
+        assert!(!manager.can_crypt(&context));
+        assert!(!manager.open(&context, &nonce, b"0", &mut data, &tag));
+    }
+}
@@ -9,19 +9,35 @@ use std::cmp::min;
 use std::ffi::OsStr;
 use std::os::unix::prelude::OsStrExt;
 use std::time::Duration;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use libc::{ENOENT, ENOSYS};
+use libc::ENOENT;
 use crate::inode::{inode, inode_manager};
 use crate::common::directory;
+use crate::common::snapshot;
 use crate::fuse::fuse_helper::*;
 use crate::fuse::permission::*;
+use crate::fuse::xattr;
+use crate::compress::compress;
+use crate::crypt::crypt;
 
 const TTL: Duration = Duration::new(1, 0); // 1 second
 
+// INIT flag asking the kernel to pass O_TRUNC to open instead of truncating by setattr first
+const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+
+// _IOW('W', 1, [u64; 4]), args are src ino, src offset, dst offset and len
+pub const WONDFS_IOC_REFLINK: u32 = 0x40205701;
+
+// _IO('W', 2), encrypts an empty directory with the master key given at mount
+pub const WONDFS_IOC_SET_ENCRYPTION_POLICY: u32 = 0x5702;
+
 // System Layer Main Structure
 pub struct WondFS {
     inode_manager: inode_manager::InodeManager,
     next_file_handle: AtomicU64,
+    snapshot_dir: u32,
+    atime_policy: inode::AtimePolicy,
 }
 
 // System Layer Simple Interface Function
@@ -31,23 +47,587 @@ impl WondFS {
         WondFS {
             inode_manager: manager,
             next_file_handle: AtomicU64::new(1),
+            snapshot_dir: 0,
+            atime_policy: inode::AtimePolicy::Relatime,
         }
     }
+
+    pub fn set_compress_policy(&mut self, policy: compress::CompressPolicy) {
+        self.inode_manager.core_manager.lock().unwrap().set_compress_policy(policy);
+    }
+
+    pub fn set_crypt_key(&mut self, key: [u8; 32]) {
+        self.inode_manager.core_manager.lock().unwrap().set_crypt_key(key);
+    }
+
+    pub fn set_dedup(&mut self, enabled: bool) {
+        self.inode_manager.core_manager.lock().unwrap().set_dedup(enabled);
+    }
+
+    pub fn set_atime_policy(&mut self, policy: inode::AtimePolicy) {
+        self.atime_policy = policy;
+    }
+
+    pub fn set_inode_cache_capacity(&mut self, capacity: usize) {
+        self.inode_manager.set_capacity(capacity);
+    }
 }
 
 // System Layer Internale Function
 impl WondFS {
-    fn allocate_next_file_handle(&self, read: bool, write: bool) -> u64 {
+    fn mount(&mut self) {
+        self.inode_manager.core_manager.lock().unwrap().mount();
+        if true {
+            let mut inode = self.inode_manager.i_alloc().unwrap();
+            assert!(inode.read().unwrap().ino == FUSE_ROOT_ID as u32);
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.file_type = inode::InodeFileType::Directory;
+            stat.size = 0;
+            stat.ref_cnt = 0;
+            stat.n_link = 2;
+            stat.mode = 0o777;
+            stat.uid = 0;
+            stat.gid = 0;
+            stat.last_accessed = time_now();
+            stat.last_modified = time_now();
+            stat.last_metadata_changed = time_now();
+            inode.write().unwrap().modify_stat(stat);
+            directory::dir_link(&mut inode, FUSE_ROOT_ID as u32, ".".to_string());
+            self.inode_manager.i_put(inode);
+        }
+        if true {
+            // snapshots are created and deleted by mkdir and rmdir in this directory
+            let mut root = self.inode_manager.i_get(FUSE_ROOT_ID as u32).unwrap();
+            let mut inode = self.inode_manager.i_alloc().unwrap();
+            let ino = inode.read().unwrap().ino;
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.file_type = inode::InodeFileType::Directory;
+            stat.size = 0;
+            stat.ref_cnt = 0;
+            stat.n_link = 2;
+            stat.mode = 0o755;
+            stat.uid = 0;
+            stat.gid = 0;
+            stat.last_accessed = time_now();
+            stat.last_modified = time_now();
+            stat.last_metadata_changed = time_now();
+            inode.write().unwrap().modify_stat(stat);
+            directory::dir_link(&mut inode, ino, ".".to_string());
+            directory::dir_link(&mut inode, FUSE_ROOT_ID as u32, "..".to_string());
+            directory::dir_link(&mut root, ino, snapshot::SNAPSHOT_DIR_NAME.to_string());
+            let mut stat = root.read().unwrap().get_stat();
+            stat.n_link += 1;
+            root.write().unwrap().modify_stat(stat);
+            self.snapshot_dir = ino;
+            self.inode_manager.i_put(inode);
+            self.inode_manager.i_put(root);
+        }
+    }
+
+    fn allocate_next_file_handle(&self, read: bool, write: bool, flags: i32) -> u64 {
         let mut fh = self.next_file_handle.fetch_add(1, Ordering::SeqCst);
-        assert!(fh < FILE_HANDLE_WRITE_BIT && fh < FILE_HANDLE_READ_BIT);
+        assert!(fh < FILE_HANDLE_NOATIME_BIT);
         if read {
             fh |= FILE_HANDLE_READ_BIT;
         }
         if write {
             fh |= FILE_HANDLE_WRITE_BIT;
         }
+        if flags & libc::O_APPEND != 0 {
+            fh |= FILE_HANDLE_APPEND_BIT;
+        }
+        if flags & libc::O_NOATIME != 0 {
+            fh |= FILE_HANDLE_NOATIME_BIT;
+        }
         fh
     }
+
+    fn get_acl(&self, inode: &inode_manager::InodeLink, name: &str) -> Option<Vec<AclEntry>> {
+        inode.read().unwrap().get_xattr(name).and_then(|value| parse_acl(&value))
+    }
+
+    fn check_inode_access(&self, inode: &inode_manager::InodeLink, req: &impl Caller, access_mask: i32) -> bool {
+        let groups = get_groups(req.pid());
+        let stat = inode.read().unwrap().get_stat();
+        match self.get_acl(inode, xattr::POSIX_ACL_ACCESS) {
+            Some(acl) => check_acl_access(&acl, stat.uid, stat.gid, stat.mode, req.uid(), req.gid(), &groups, access_mask),
+            None => check_access(stat.uid, stat.gid, stat.mode, req.uid(), req.gid(), &groups, access_mask),
+        }
+    }
+
+    fn init_owner(&self, req: &Request<'_>, parent_inode: &inode_manager::InodeLink, stat: &mut inode::InodeStat, mode: u32, umask: u32) -> Option<Vec<AclEntry>> {
+        let parent_stat = parent_inode.read().unwrap().get_stat();
+        let default_acl = self.get_acl(parent_inode, xattr::POSIX_ACL_DEFAULT);
+        stat.uid = req.uid();
+        stat.gid = creation_gid(&parent_stat, req.gid());
+        // the umask is ignored when the parent has a default ACL
+        stat.mode = creation_mode(mode, if default_acl.is_some() { 0 } else { umask });
+        if stat.file_type == inode::InodeFileType::Directory {
+            // subdirectories of a setgid directory are setgid as well
+            if parent_stat.mode & libc::S_ISGID as u16 != 0 {
+                stat.mode |= libc::S_ISGID as u16;
+            }
+        } else if stat.mode & libc::S_ISGID as u16 != 0
+            && req.uid() != 0
+            && !in_group(stat.gid, req.gid(), &get_groups(req.pid()))
+        {
+            stat.mode &= !(libc::S_ISGID as u16);
+        }
+        match default_acl {
+            Some(mut acl) => {
+                stat.mode = acl_create_masq(&mut acl, stat.mode);
+                Some(acl)
+            },
+            None => None,
+        }
+    }
+
+    fn inherit_acl(&self, parent_inode: &inode_manager::InodeLink, inode: &inode_manager::InodeLink, acl: Option<Vec<AclEntry>>) {
+        if let Some(acl) = acl {
+            if !acl_is_equiv_mode(&acl) {
+                inode.write().unwrap().set_xattr(xattr::POSIX_ACL_ACCESS, &encode_acl(&acl));
+            }
+            if inode.read().unwrap().file_type == inode::InodeFileType::Directory {
+                let default_acl = parent_inode.read().unwrap().get_xattr(xattr::POSIX_ACL_DEFAULT).unwrap();
+                inode.write().unwrap().set_xattr(xattr::POSIX_ACL_DEFAULT, &default_acl);
+            }
+        }
+    }
+
+    fn inherit_compress_policy(&self, parent_inode: &inode_manager::InodeLink, inode: &inode_manager::InodeLink, name: &str) {
+        let file_type = inode.read().unwrap().file_type;
+        // already compressed formats only cost time to compress again
+        if file_type == inode::InodeFileType::File
+            && compress::is_compressed_extension(name)
+            && parent_inode.read().unwrap().get_compress_policy() != compress::CompressPolicy::Off
+        {
+            inode.write().unwrap().set_xattr(compress::COMPRESS_XATTR, compress::CompressPolicy::Off.as_str().as_bytes());
+            return;
+        }
+        if file_type != inode::InodeFileType::File && file_type != inode::InodeFileType::Directory {
+            return;
+        }
+        if let Some(policy) = parent_inode.read().unwrap().get_xattr(compress::COMPRESS_XATTR) {
+            inode.write().unwrap().set_xattr(compress::COMPRESS_XATTR, &policy);
+        }
+    }
+
+    fn inherit_crypt_context(&self, parent_inode: &inode_manager::InodeLink, inode: &inode_manager::InodeLink) {
+        let file_type = inode.read().unwrap().file_type;
+        if !parent_inode.read().unwrap().is_encrypted() || (file_type != inode::InodeFileType::File && file_type != inode::InodeFileType::Directory) {
+            return;
+        }
+        // every file gets its own key, derived from the master key and a fresh nonce
+        let context = self.inode_manager.core_manager.lock().unwrap().new_crypt_context().unwrap();
+        inode.write().unwrap().set_xattr(crypt::CRYPT_XATTR, &context);
+    }
+
+    // Add the entry of a newly allocated inode, the inode is unlinked again when that fails
+    // so its last reference frees it
+    fn link_new_inode(&self, parent_inode: &mut inode_manager::InodeLink, inode: &inode_manager::InodeLink, name: String) -> bool {
+        let ino = inode.read().unwrap().ino;
+        if directory::dir_link(parent_inode, ino, name) {
+            return true;
+        }
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.n_link = 0;
+        inode.write().unwrap().modify_stat(stat);
+        false
+    }
+
+    fn is_crypt_locked(&mut self, ino: u32) -> bool {
+        match self.inode_manager.i_get(ino) {
+            Some(inode) => {
+                let locked = inode.read().unwrap().is_crypt_locked();
+                self.inode_manager.i_put(inode);
+                locked
+            },
+            None => false,
+        }
+    }
+
+    fn is_crypt_policy_kept(&self, parent_inode: &inode_manager::InodeLink, inode: &inode_manager::InodeLink) -> bool {
+        // an encrypted directory only holds files encrypted with the same master key
+        let parent_context = match parent_inode.read().unwrap().get_xattr(crypt::CRYPT_XATTR) {
+            Some(context) => context,
+            None => return true,
+        };
+        let context = match inode.read().unwrap().get_xattr(crypt::CRYPT_XATTR) {
+            Some(context) => context,
+            None => return inode.read().unwrap().file_type != inode::InodeFileType::File && inode.read().unwrap().file_type != inode::InodeFileType::Directory,
+        };
+        crypt::CryptContext::decode(&parent_context).map(|context| context.key_id) == crypt::CryptContext::decode(&context).map(|context| context.key_id)
+    }
+
+    fn set_encryption_policy(&mut self, req: &Request<'_>, ino: u32, reply: ReplyIoctl) {
+        if self.is_read_only(ino) {
+            debug!("WondFS: ioctl set encryption policy snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if !self.inode_manager.core_manager.lock().unwrap().has_crypt_key() {
+            debug!("WondFS: ioctl set encryption policy key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
+        let inode = match self.inode_manager.i_get(ino) {
+            Some(inode) => inode,
+            None => {
+                debug!("WondFS: ioctl set encryption policy ino not exists");
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let error = 'check: {
+            if inode.read().unwrap().file_type != inode::InodeFileType::Directory {
+                break 'check libc::ENOTDIR;
+            }
+            if req.uid() != 0 && req.uid() != inode.read().unwrap().uid {
+                debug!("WondFS: ioctl set encryption policy not owner");
+                break 'check libc::EACCES;
+            }
+            if let Some(context) = inode.read().unwrap().get_xattr(crypt::CRYPT_XATTR) {
+                // setting the policy a directory already has is a no-op
+                if self.inode_manager.core_manager.lock().unwrap().can_crypt(&context) {
+                    break 'check 0;
+                }
+                break 'check libc::EEXIST;
+            }
+            if !directory::dir_is_empty(&inode) {
+                debug!("WondFS: ioctl set encryption policy dir not empty");
+                break 'check libc::ENOTEMPTY;
+            }
+            let context = self.inode_manager.core_manager.lock().unwrap().new_crypt_context().unwrap();
+            if !inode.write().unwrap().set_crypt_context(&context) {
+                break 'check libc::EIO;
+            }
+            0
+        };
+        self.inode_manager.i_put(inode);
+        match error {
+            0 => reply.ioctl(0, &[]),
+            error => reply.error(error),
+        }
+    }
+
+    fn is_ancestor(&mut self, ancestor: u32, ino: u32) -> bool {
+        let mut current = ino;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+            if current == FUSE_ROOT_ID as u32 {
+                return false;
+            }
+            let inode = self.inode_manager.i_get(current);
+            if inode.is_none() {
+                return false;
+            }
+            let parent = directory::dir_lookup(inode.as_ref().unwrap(), "..".to_string());
+            self.inode_manager.i_put(inode.unwrap());
+            match parent {
+                Some((parent, _)) => current = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn is_read_only(&self, ino: u32) -> bool {
+        ino == self.snapshot_dir || self.inode_manager.core_manager.lock().unwrap().is_frozen_inode(ino)
+    }
+
+    fn is_snapshot_dir_entry(&self, parent: u32, name: &str) -> bool {
+        parent == FUSE_ROOT_ID as u32 && name == snapshot::SNAPSHOT_DIR_NAME
+    }
+
+    fn snapshot_mkdir(&mut self, req: &Request<'_>, name: String, reply: ReplyEntry) {
+        if name.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: mkdir snapshot name too long");
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        let parent_inode = self.inode_manager.i_get(self.snapshot_dir).unwrap();
+        let allowed = self.check_inode_access(&parent_inode, req, libc::W_OK | libc::X_OK);
+        self.inode_manager.i_put(parent_inode);
+        if !allowed {
+            debug!("WondFS: mkdir snapshot no permission to access");
+            reply.error(libc::EACCES);
+            return;
+        }
+        match snapshot::snapshot_create(&mut self.inode_manager, FUSE_ROOT_ID as u32, self.snapshot_dir, name) {
+            Ok(ino) => {
+                let inode = self.inode_manager.i_get(ino).unwrap();
+                let attr = transfer_stat_to_attr(inode.read().unwrap().get_stat());
+                self.inode_manager.i_lookup(&inode);
+                self.inode_manager.i_put(inode);
+                reply.entry(&TTL, &attr, 0);
+            },
+            Err(err) => {
+                debug!("WondFS: mkdir snapshot error {}", err);
+                reply.error(err);
+            },
+        }
+    }
+
+    fn snapshot_rmdir(&mut self, req: &Request<'_>, name: String, reply: ReplyEmpty) {
+        let parent_inode = self.inode_manager.i_get(self.snapshot_dir).unwrap();
+        let allowed = self.check_inode_access(&parent_inode, req, libc::W_OK | libc::X_OK);
+        self.inode_manager.i_put(parent_inode);
+        if !allowed {
+            debug!("WondFS: rmdir snapshot no permission to access");
+            reply.error(libc::EACCES);
+            return;
+        }
+        if snapshot::snapshot_delete(&mut self.inode_manager, self.snapshot_dir, name) {
+            reply.ok();
+        } else {
+            debug!("WondFS: rmdir snapshot not exists");
+            reply.error(ENOENT);
+        }
+    }
+
+    fn update_atime(&self, inode: &inode_manager::InodeLink, ino: u32, fh: u64) {
+        // snapshots never change, and the times of an access are only kept in memory until written back
+        if check_file_handle_noatime(fh) || self.is_read_only(ino) {
+            return;
+        }
+        inode.write().unwrap().update_atime(self.atime_policy, time_now());
+    }
+
+    fn truncate_inode(&self, inode: &inode_manager::InodeLink, size: u64, req: &impl Caller) -> Result<(), i32> {
+        if size > inode::MAX_FILE_SIZE {
+            return Err(libc::EFBIG);
+        }
+        if inode.read().unwrap().is_crypt_locked() {
+            return Err(libc::ENOKEY);
+        }
+        if !inode.write().unwrap().set_size(size) {
+            return Err(libc::EIO);
+        }
+        // like ftruncate the times change even when the size stays
+        let now = time_now();
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.last_modified = now;
+        stat.last_metadata_changed = now;
+        if req.uid() != 0 {
+            stat.mode = clear_suid_sgid(stat.mode);
+        }
+        if !inode.write().unwrap().modify_stat(stat) {
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    // Remove the entry name of parent for unlink, or for rmdir when is_dir is set
+    fn remove_entry(&mut self, req: &impl Caller, parent: u32, name: String, is_dir: bool) -> Result<(), i32> {
+        let op = if is_dir { "rmdir" } else { "unlink" };
+        if self.is_read_only(parent) {
+            debug!("WondFS: {} snapshot is read only", op);
+            return Err(libc::EROFS);
+        }
+        if self.is_snapshot_dir_entry(parent, &name) {
+            debug!("WondFS: {} snapshot directory is busy", op);
+            return Err(libc::EBUSY);
+        }
+        if self.is_crypt_locked(parent) {
+            debug!("WondFS: {} key not available", op);
+            return Err(libc::ENOKEY);
+        }
+        let mut parent_inode = match self.inode_manager.i_get(parent) {
+            Some(inode) => inode,
+            None => {
+                debug!("WondFS: {} parent not exists", op);
+                return Err(ENOENT);
+            },
+        };
+        let inode = match directory::dir_lookup(&parent_inode, name.clone()) {
+            Some((ino, _)) => self.inode_manager.i_get(ino),
+            None => None,
+        };
+        let inode = match inode {
+            Some(inode) => inode,
+            None => {
+                debug!("WondFS: {} name not exists", op);
+                self.inode_manager.i_put(parent_inode);
+                return Err(ENOENT);
+            },
+        };
+        let ino = inode.read().unwrap().ino;
+        let error = 'check: {
+            // permissions come first, so callers can't learn the type or contents of entries they may not remove
+            if !self.check_inode_access(&parent_inode, req, libc::W_OK | libc::X_OK) {
+                debug!("WondFS: {} no permission to access", op);
+                break 'check libc::EACCES;
+            }
+            // "Sticky bit" handling
+            if !check_sticky(parent_inode.read().unwrap().uid, parent_inode.read().unwrap().mode, inode.read().unwrap().uid, req.uid()) {
+                debug!("WondFS: {} sticky directory not owner", op);
+                break 'check libc::EPERM;
+            }
+            let file_is_dir = inode.read().unwrap().file_type == inode::InodeFileType::Directory;
+            if is_dir && !file_is_dir {
+                break 'check libc::ENOTDIR;
+            }
+            if !is_dir && file_is_dir {
+                break 'check libc::EISDIR;
+            }
+            if is_dir && !directory::dir_is_empty(&inode) {
+                debug!("WondFS: rmdir dir not empty");
+                break 'check libc::ENOTEMPTY;
+            }
+            if !directory::dir_unlink(&mut parent_inode, ino, name) {
+                debug!("WondFS: {} not success", op);
+                break 'check libc::EIO;
+            }
+            let now = time_now();
+            let mut stat = parent_inode.read().unwrap().get_stat();
+            // the removed directory held a link to its parent by ".."
+            if is_dir {
+                stat.n_link -= 1;
+            }
+            stat.last_modified = now;
+            stat.last_metadata_changed = now;
+            parent_inode.write().unwrap().modify_stat(stat);
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.n_link = if is_dir { 0 } else { stat.n_link - 1 };
+            stat.last_metadata_changed = now;
+            inode.write().unwrap().modify_stat(stat);
+            // open files outlive their last link, they are freed once forgotten and released
+            if stat.n_link == 0 {
+                self.inode_manager.core_manager.lock().unwrap().add_orphan_inode(ino);
+            }
+            0
+        };
+        self.inode_manager.i_put(parent_inode);
+        self.inode_manager.i_put(inode);
+        match error {
+            0 => Ok(()),
+            error => Err(error),
+        }
+    }
+
+    // Add the entry newname of newparent for the inode ino
+    fn link_entry(&mut self, req: &impl Caller, ino: u32, newparent: u32, newname: String) -> Result<FileAttr, i32> {
+        if newname.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: link name too long");
+            return Err(libc::ENAMETOOLONG);
+        }
+        if self.is_read_only(newparent) {
+            debug!("WondFS: link snapshot is read only");
+            return Err(libc::EROFS);
+        }
+        if self.is_crypt_locked(newparent) {
+            debug!("WondFS: link key not available");
+            return Err(libc::ENOKEY);
+        }
+        let mut parent_inode = match self.inode_manager.i_get(newparent) {
+            Some(inode) => inode,
+            None => {
+                debug!("WondFS: link parent not exists");
+                return Err(ENOENT);
+            },
+        };
+        let inode = match self.inode_manager.i_get(ino) {
+            Some(inode) => inode,
+            None => {
+                debug!("WondFS: link inode not exists");
+                self.inode_manager.i_put(parent_inode);
+                return Err(ENOENT);
+            },
+        };
+        let result = 'check: {
+            if inode.read().unwrap().file_type == inode::InodeFileType::Directory {
+                debug!("WondFS: link directory not allowed");
+                break 'check Err(libc::EPERM);
+            }
+            if !self.check_inode_access(&parent_inode, req, libc::W_OK | libc::X_OK) {
+                debug!("WondFS: link no permission to access");
+                break 'check Err(libc::EACCES);
+            }
+            if !self.is_crypt_policy_kept(&parent_inode, &inode) {
+                debug!("WondFS: link encryption policy differs");
+                break 'check Err(libc::EXDEV);
+            }
+            if directory::dir_lookup(&parent_inode, newname.clone()).is_some() {
+                debug!("WondFS: link name has exist");
+                break 'check Err(libc::EEXIST);
+            }
+            if !directory::dir_link(&mut parent_inode, ino, newname) {
+                debug!("WondFS: link not success");
+                break 'check Err(libc::EIO);
+            }
+            let now = time_now();
+            let mut stat = parent_inode.read().unwrap().get_stat();
+            stat.last_modified = now;
+            stat.last_metadata_changed = now;
+            parent_inode.write().unwrap().modify_stat(stat);
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.n_link += 1;
+            stat.last_metadata_changed = now;
+            inode.write().unwrap().modify_stat(stat);
+            self.inode_manager.i_lookup(&inode);
+            Ok(transfer_stat_to_attr(stat))
+        };
+        self.inode_manager.i_put(parent_inode);
+        self.inode_manager.i_put(inode);
+        result
+    }
+
+    fn copy_range(&mut self, src: &inode_manager::InodeLink, src_offset: u64, dst: &inode_manager::InodeLink, dst_offset: u64, len: u64, fallback: bool) -> Result<u64, i32> {
+        for inode in [src, dst] {
+            match inode.read().unwrap().file_type {
+                inode::InodeFileType::File => (),
+                inode::InodeFileType::Directory => return Err(libc::EISDIR),
+                _ => return Err(libc::EINVAL),
+            }
+        }
+        let src_size = src.read().unwrap().size;
+        if src_offset >= src_size || len == 0 {
+            return Ok(0);
+        }
+        let len = min(len, src_size - src_offset);
+        if dst_offset.checked_add(len).map_or(true, |end| end > inode::MAX_FILE_SIZE) {
+            return Err(libc::EFBIG);
+        }
+        if Arc::ptr_eq(src, dst) && src_offset < dst_offset + len && dst_offset < src_offset + len {
+            return Err(libc::EINVAL);
+        }
+        // src stays locked while its pages are shared, two inodes are locked in ino order
+        let src_ino = src.read().unwrap().ino;
+        let dst_ino = dst.read().unwrap().ino;
+        let shared = if Arc::ptr_eq(src, dst) {
+            let src_inode = src.read().unwrap().copy_inode();
+            dst.write().unwrap().reflink(dst_offset, &src_inode, src_offset, len)
+        } else if src_ino < dst_ino {
+            let src_inode = src.read().unwrap();
+            dst.write().unwrap().reflink(dst_offset, &src_inode, src_offset, len)
+        } else {
+            let mut dst_inode = dst.write().unwrap();
+            dst_inode.reflink(dst_offset, &src.read().unwrap(), src_offset, len)
+        };
+        if !shared {
+            if !fallback {
+                return Err(libc::EINVAL);
+            }
+            let chunk = inode::MAX_ENTRY_PAGES * inode::PAGE_SIZE;
+            let mut done = 0;
+            while done < len {
+                let size = min(chunk, len - done);
+                let mut data = vec![];
+                src.write().unwrap().read(src_offset + done, size, &mut data);
+                data.resize(size as usize, 0);
+                if !dst.write().unwrap().write(dst_offset + done, size, &data) {
+                    return Err(libc::EIO);
+                }
+                done += size;
+            }
+        }
+        let mut stat = dst.read().unwrap().get_stat();
+        stat.last_modified = time_now();
+        stat.last_metadata_changed = stat.last_modified;
+        dst.write().unwrap().modify_stat(stat);
+        Ok(len)
+    }
 }
 
 // System Layer Main Interface Function
@@ -56,71 +636,72 @@ impl Filesystem for WondFS {
     fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), libc::c_int> {
         trace!("WondFS: init function called");
         trace!("WondFS: req: {:?}, config: {:?}", _req, _config);
-        self.inode_manager.core_manager.borrow_mut().mount();
-        if true {
-            let mut inode = self.inode_manager.i_alloc().unwrap();
-            assert!(inode.borrow().ino == FUSE_ROOT_ID as u32);
-            let mut stat = inode.borrow().get_stat();
-            stat.file_type = inode::InodeFileType::Directory;
-            stat.size = 0;
-            stat.ref_cnt = 0;
-            stat.n_link = 2;
-            stat.mode = 0o777;
-            stat.uid = 0;
-            stat.gid = 0;
-            stat.last_accessed = time_now();
-            stat.last_modified = time_now();
-            stat.last_metadata_changed = time_now();
-            inode.borrow_mut().modify_stat(stat);
-            directory::dir_link(&mut inode, FUSE_ROOT_ID as u32, ".".to_string());
-            self.inode_manager.i_put(inode);
+        if _config.add_capabilities(FUSE_ATOMIC_O_TRUNC).is_err() {
+            debug!("WondFS: init kernel truncates before open");
         }
+        self.mount();
         Ok(())
     }
 
+    /// Clean up file system and flush everything to disk on unmount
+    fn destroy(&mut self, _req: &Request<'_>) {
+        trace!("WondFS: destroy function called");
+        self.inode_manager.sync();
+        self.inode_manager.core_manager.lock().unwrap().sync();
+    }
+
     /// Look up a directory entry by name and get its attributes
     fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &std::ffi::OsStr, reply: ReplyEntry) {
         trace!("WondFS: lookup function called");
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
+        if self.is_crypt_locked(parent) {
+            debug!("WondFS: lookup key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let parent_inode = self.inode_manager.i_get(parent);
         if parent_inode.is_none() {
             debug!("WondFS: lookup parent not exists");
             reply.error(ENOENT);
             return;
         }
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::X_OK,
-        // ) {
-        //     debug!("WondFS: lookup no permission to access");
-        //     reply.error(libc::ENOENT);
-        //     return;
-        // }
+        if !self.check_inode_access(parent_inode.as_ref().unwrap(), _req, libc::X_OK) {
+            debug!("WondFS: lookup no permission to access");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EACCES);
+            return;
+        }
         let ino = directory::dir_lookup(parent_inode.as_ref().unwrap(), name);
         if ino.is_none() {
             debug!("WondFS: lookup name not exists");
+            self.inode_manager.i_put(parent_inode.unwrap());
             reply.error(ENOENT);
             return;
         }
         let inode = self.inode_manager.i_get(ino.unwrap().0 as u32);
         if inode.is_none() {
             debug!("WondFS: lookup inode not exists");
+            self.inode_manager.i_put(parent_inode.unwrap());
             reply.error(ENOENT);
             return;
         }
-        let stat = inode.as_ref().unwrap().borrow().get_stat();
+        let stat = inode.as_ref().unwrap().read().unwrap().get_stat();
         let attr = transfer_stat_to_attr(stat);
+        self.inode_manager.i_lookup(inode.as_ref().unwrap());
         self.inode_manager.i_put(parent_inode.unwrap());
         self.inode_manager.i_put(inode.unwrap());
         reply.entry(&TTL, &attr, 0);
     }
 
+    /// Forget about an inode, the kernel drops nlookup of the lookups it counted
+    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
+        trace!("WondFS: forget function called");
+        trace!("WondFS: ino: {}, nlookup: {}", _ino, _nlookup);
+        self.inode_manager.i_forget(_ino as u32, _nlookup);
+    }
+
     /// Get file attributes
     fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
         trace!("WondFS: getattr function called");
@@ -129,7 +710,7 @@ impl Filesystem for WondFS {
         let inode = self.inode_manager.i_get(ino);
         match inode {
             Some(inode) => {
-                let stat = inode.borrow().get_stat();
+                let stat = inode.read().unwrap().get_stat();
                 let attr = transfer_stat_to_attr(stat);
                 self.inode_manager.i_put(inode);
                 reply.attr(&TTL, &attr);
@@ -146,6 +727,16 @@ impl Filesystem for WondFS {
         trace!("WondFS: setattr function called");
         let ino = _ino as u32;
         trace!("WondFS: ino: {}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}, atime: {:?}, mtime: {:?}, ctime: {:?}, fh: {:?}, crtime: {:?}, chgtime: {:?}, bkuptime: {:?}, flags: {:?}", ino, _mode, _uid, _gid, _size, _atime, _mtime, _ctime, _fh, _crtime, _chgtime, _bkuptime, _flags);
+        if self.is_read_only(ino) {
+            debug!("WondFS: setattr snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if _size.is_some() && self.is_crypt_locked(ino) {
+            debug!("WondFS: setattr key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let inode = self.inode_manager.i_get(ino);
         if inode.is_none() {
             debug!("WondFS: setattr inode not exists");
@@ -153,68 +744,129 @@ impl Filesystem for WondFS {
             return;
         }
         let inode = inode.unwrap();
-        if let Some(mode) = _mode {
-            debug!("WondFS: setattr mode change not implemented");
-            reply.error(ENOSYS);
-            return;
-        }
-        if _uid.is_some() || _gid.is_some() {
-            debug!("WondFS: setattr uid | gid change not implemented");
-            reply.error(ENOSYS);
+        let uid = _req.uid();
+        let stat = inode.read().unwrap().get_stat();
+        // every check runs before anything changes, a refused request leaves the inode as it was
+        let error = 'check: {
+            if _mode.is_some() && uid != 0 && uid != stat.uid {
+                debug!("WondFS: setattr chmod not owner");
+                break 'check libc::EPERM;
+            }
+            if let Some(new_uid) = _uid {
+                // only root may give a file away
+                if uid != 0 && new_uid != stat.uid {
+                    debug!("WondFS: setattr chown no permission to change uid");
+                    break 'check libc::EPERM;
+                }
+            }
+            if let Some(new_gid) = _gid {
+                // the owner may only pick one of its own groups
+                if uid != 0 && (uid != stat.uid || (new_gid != stat.gid && !in_group(new_gid, _req.gid(), &get_groups(_req.pid())))) {
+                    debug!("WondFS: setattr chown no permission to change gid");
+                    break 'check libc::EPERM;
+                }
+            }
+            if let Some(size) = _size {
+                let writable = match _fh {
+                    Some(handle) => check_file_handle_write(handle),
+                    None => self.check_inode_access(&inode, _req, libc::W_OK),
+                };
+                if !writable {
+                    debug!("WondFS: setattr no permission to change size");
+                    break 'check libc::EACCES;
+                }
+                if stat.file_type != inode::InodeFileType::File {
+                    debug!("WondFS: setattr size of a non regular file");
+                    break 'check if stat.file_type == inode::InodeFileType::Directory { libc::EISDIR } else { libc::EINVAL };
+                }
+                if size > inode::MAX_FILE_SIZE {
+                    debug!("WondFS: setattr size too large");
+                    break 'check libc::EFBIG;
+                }
+            }
+            if _atime.is_some() || _mtime.is_some() {
+                let owner = uid == 0 || uid == stat.uid;
+                let specific = matches!(_atime, Some(TimeOrNow::SpecificTime(_))) || matches!(_mtime, Some(TimeOrNow::SpecificTime(_)));
+                // setting explicit times needs ownership, touching to now only needs write access
+                if !owner && (specific || !self.check_inode_access(&inode, _req, libc::W_OK)) {
+                    debug!("WondFS: setattr utimens no permission");
+                    break 'check if specific { libc::EPERM } else { libc::EACCES };
+                }
+            }
+            0
+        };
+        if error != 0 {
+            self.inode_manager.i_put(inode);
+            reply.error(error);
             return;
         }
+        // the size goes first, it is the only change that can still fail
         if let Some(size) = _size {
             trace!("WondFS: setattr truncate() called with {} {}", ino, size);
-            // if let Some(handle) = _fh {
-            //     if check_file_handle_write(handle) {
-            //         let ret = inode.borrow_mut().truncate_to_end(size as u32);
-            //         if !ret {
-            //             debug!("WondFS: setattr truncate internal error");
-            //             reply.error(ENOENT);
-            //             return;
-            //         }
-            //     } else {
-            //         debug!("WondFS: setattr no permission to change size");
-            //         reply.error(ENOENT);
-            //         return;
-            //     }
-            // } else {
-            //     let ret = inode.borrow_mut().truncate_to_end(size as u32);
-            //     if !ret {
-            //         debug!("WondFS: setattr truncate internal error");
-            //         reply.error(ENOENT);
-            //         return;
-            //     }
-            // }
-            let ret = inode.borrow_mut().truncate_to_end(size as u32);
-            if !ret {
-                debug!("WondFS: setattr truncate internal error");
-                reply.error(ENOENT);
+            if let Err(error) = self.truncate_inode(&inode, size, _req) {
+                debug!("WondFS: setattr truncate not success");
+                self.inode_manager.i_put(inode);
+                reply.error(error);
                 return;
             }
         }
+        if let Some(mode) = _mode {
+            trace!("WondFS: setattr chmod() called with {}, mode={:o}", ino, mode);
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.mode = (mode & 0o7777) as u16;
+            if uid != 0 && !in_group(stat.gid, _req.gid(), &get_groups(_req.pid())) {
+                stat.mode &= !(libc::S_ISGID as u16);
+            }
+            stat.last_metadata_changed = time_now();
+            inode.write().unwrap().modify_stat(stat);
+            if let Some(mut acl) = self.get_acl(&inode, xattr::POSIX_ACL_ACCESS) {
+                acl_chmod(&mut acl, stat.mode);
+                inode.write().unwrap().set_xattr(xattr::POSIX_ACL_ACCESS, &encode_acl(&acl));
+            }
+        }
+        if _uid.is_some() || _gid.is_some() {
+            trace!("WondFS: setattr chown() called with {}, uid={:?}, gid={:?}", ino, _uid, _gid);
+            let mut stat = inode.read().unwrap().get_stat();
+            if let Some(new_uid) = _uid {
+                stat.uid = new_uid;
+            }
+            if let Some(new_gid) = _gid {
+                stat.gid = new_gid;
+            }
+            if stat.file_type != inode::InodeFileType::Directory {
+                stat.mode = clear_suid_sgid(stat.mode);
+            }
+            stat.last_metadata_changed = time_now();
+            inode.write().unwrap().modify_stat(stat);
+        }
         let now = time_now();
         if let Some(atime) = _atime {
             trace!("WondFS: setattr utimens() called with {}, atime={:?}", ino, atime);
-            let mut stat = inode.borrow().get_stat();
+            let mut stat = inode.read().unwrap().get_stat();
             stat.last_accessed = match atime {
                 TimeOrNow::SpecificTime(time) => time_from_system_time(&time),
                 TimeOrNow::Now => now,
             };
             stat.last_metadata_changed = now;
-            inode.borrow_mut().modify_stat(stat);
+            inode.write().unwrap().modify_stat(stat);
         }
         if let Some(mtime) = _mtime {
             trace!("WondFS: setattr utimens() called with {}, mtime={:?}", ino, mtime);
-            let mut stat = inode.borrow().get_stat();
+            let mut stat = inode.read().unwrap().get_stat();
             stat.last_modified = match mtime {
                 TimeOrNow::SpecificTime(time) => time_from_system_time(&time),
                 TimeOrNow::Now => now,
             };
             stat.last_metadata_changed = now;
-            inode.borrow_mut().modify_stat(stat);
+            inode.write().unwrap().modify_stat(stat);
         }
-        let stat = inode.borrow().get_stat();
+        if let Some(ctime) = _ctime {
+            // only sent by a kernel caching writes, which keeps the times itself
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.last_metadata_changed = time_from_system_time(&ctime);
+            inode.write().unwrap().modify_stat(stat);
+        }
+        let stat = inode.read().unwrap().get_stat();
         let attr = transfer_stat_to_attr(stat);
         self.inode_manager.i_put(inode);
         reply.attr(&TTL, &attr);
@@ -223,15 +875,32 @@ impl Filesystem for WondFS {
     /// Create a file node
     fn mknod(&mut self, _req: &Request<'_>, _parent: u64, _name: &std::ffi::OsStr, mut _mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
         trace!("WondFS: mknod function called");
-        let file_type = _mode & libc::S_IFMT as u32;
-        if file_type != libc::S_IFREG as u32 && file_type != libc::S_IFDIR as u32 {
-            debug!("WondFS: mknod implementation is incomplete. Only supports regular files, and directories. Got {:o}", _mode);
-            reply.error(ENOSYS);
-            return;
-        }
+        let file_type = match as_file_kind(_mode) {
+            Some(file_type) => file_type,
+            None => {
+                debug!("WondFS: mknod unknown file type {:o}", _mode);
+                reply.error(libc::EINVAL);
+                return;
+            },
+        };
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
+        if name.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: mknod name too long");
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        if self.is_read_only(parent) {
+            debug!("WondFS: mknod snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(parent) {
+            debug!("WondFS: mknod key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let mut parent_inode = self.inode_manager.i_get(parent);
         if parent_inode.is_none() {
             reply.error(ENOENT);
@@ -239,60 +908,63 @@ impl Filesystem for WondFS {
             return;
         }
         if directory::dir_lookup(parent_inode.as_ref().unwrap(), name.clone()).is_some() {
-            reply.error(libc::ENOENT);
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EEXIST);
             debug!("WondFS: mknod name has exist");
             return;
         }
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::X_OK,
-        // ) {
-        //     debug!("WondFS: mknod no permission to access");
-        //     reply.error(libc::ENOENT);
-        //     return;
-        // }
-        let mut stat = parent_inode.as_ref().unwrap().borrow().get_stat();
+        if !self.check_inode_access(parent_inode.as_ref().unwrap(), _req, libc::W_OK | libc::X_OK) {
+            debug!("WondFS: mknod no permission to access");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EACCES);
+            return;
+        }
+        let mut stat = parent_inode.as_ref().unwrap().read().unwrap().get_stat();
         stat.last_modified = time_now();
         stat.last_metadata_changed = time_now();
-        parent_inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        // if _req.uid() != 0 {
-        //     _mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
-        // }
+        parent_inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
         let mut inode = self.inode_manager.i_alloc();
         if inode.is_none() {
             debug!("WondFS: mknod alloc inode error");
-            reply.error(ENOENT);
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::ENOSPC);
             return;
         }
-        let ino = inode.as_ref().unwrap().borrow().ino;
-        let mut stat = inode.as_ref().unwrap().borrow().get_stat();
-        stat.file_type = as_file_kind(_mode);
+        let ino = inode.as_ref().unwrap().read().unwrap().ino;
+        let mut stat = inode.as_ref().unwrap().read().unwrap().get_stat();
+        stat.file_type = file_type;
         stat.size = 0;
         stat.ref_cnt = 0;
-        stat.mode = 0o777;
-        stat.uid = 0;
-        stat.gid = 0;
+        let acl = self.init_owner(_req, parent_inode.as_ref().unwrap(), &mut stat, _mode, _umask);
         stat.last_accessed = time_now();
         stat.last_modified = time_now();
         stat.last_metadata_changed = time_now();
-        if stat.file_type == inode::InodeFileType::File {
-            stat.n_link = 1;
+        if stat.file_type == inode::InodeFileType::CharDevice || stat.file_type == inode::InodeFileType::BlockDevice {
+            stat.rdev = _rdev;
         }
         if stat.file_type == inode::InodeFileType::Directory {
             stat.n_link = 2;
+        } else {
+            stat.n_link = 1;
         }
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        if inode.as_ref().unwrap().borrow().file_type == inode::InodeFileType::Directory {
+        inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
+        self.inherit_acl(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), acl);
+        self.inherit_compress_policy(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), &name);
+        self.inherit_crypt_context(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap());
+        if inode.as_ref().unwrap().read().unwrap().file_type == inode::InodeFileType::Directory {
             directory::dir_link(inode.as_mut().unwrap(), ino, ".".to_string());
             directory::dir_link(inode.as_mut().unwrap(), parent, "..".to_string());
         }
-        directory::dir_link(parent_inode.as_mut().unwrap(), ino, name);
-        let stat = inode.as_ref().unwrap().borrow().get_stat();
+        if !self.link_new_inode(parent_inode.as_mut().unwrap(), inode.as_ref().unwrap(), name) {
+            debug!("WondFS: mknod link not success");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            self.inode_manager.i_put(inode.unwrap());
+            reply.error(libc::EIO);
+            return;
+        }
+        let stat = inode.as_ref().unwrap().read().unwrap().get_stat();
         let attr = transfer_stat_to_attr(stat);
+        self.inode_manager.i_lookup(inode.as_ref().unwrap());
         self.inode_manager.i_put(parent_inode.unwrap());
         self.inode_manager.i_put(inode.unwrap());
         reply.entry(&TTL, &attr, 0);
@@ -304,6 +976,25 @@ impl Filesystem for WondFS {
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
+        if name.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: mkdir name too long");
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        if parent == self.snapshot_dir {
+            self.snapshot_mkdir(_req, name, reply);
+            return;
+        }
+        if self.is_read_only(parent) {
+            debug!("WondFS: mkdir snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(parent) {
+            debug!("WondFS: mkdir key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let mut parent_inode = self.inode_manager.i_get(parent);
         if parent_inode.is_none() {
             reply.error(ENOENT);
@@ -311,56 +1002,56 @@ impl Filesystem for WondFS {
             return;
         }
         if directory::dir_lookup(parent_inode.as_ref().unwrap(), name.clone()).is_some() {
-            reply.error(ENOENT);
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EEXIST);
             debug!("WondFS: mkdir name has exist");
             return;
         }
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::X_OK,
-        // ) {
-        //     debug!("WondFS: mkdir no permission to access");
-        //     reply.error(libc::ENOENT);
-        //     return;
-        // }
-        let mut stat = parent_inode.as_ref().unwrap().borrow().get_stat();
-        stat.last_modified = time_now();
-        stat.last_metadata_changed = time_now();
-        parent_inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        // if _req.uid() != 0 {
-        //     _mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
-        // }
-        // if parent_inode.as_ref().unwrap().borrow().mode & libc::S_ISGID as u16 != 0 {
-        //     _mode |= libc::S_ISGID as u32;
-        // }
+        if !self.check_inode_access(parent_inode.as_ref().unwrap(), _req, libc::W_OK | libc::X_OK) {
+            debug!("WondFS: mkdir no permission to access");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EACCES);
+            return;
+        }
         let mut inode = self.inode_manager.i_alloc();
         if inode.is_none() {
-            debug!("WondFS: mknod alloc inode error");
-            reply.error(ENOENT);
+            debug!("WondFS: mkdir alloc inode error");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::ENOSPC);
             return;
         }
-        let ino = inode.as_ref().unwrap().borrow().ino;
-        let mut stat = inode.as_ref().unwrap().borrow().get_stat();
+        let ino = inode.as_ref().unwrap().read().unwrap().ino;
+        let mut stat = inode.as_ref().unwrap().read().unwrap().get_stat();
         stat.file_type = inode::InodeFileType::Directory;
         stat.size = 0;
         stat.ref_cnt = 0;
         stat.n_link = 2;
-        stat.mode = 0o777;
-        stat.uid = 0;
-        stat.gid = 0;
+        let acl = self.init_owner(_req, parent_inode.as_ref().unwrap(), &mut stat, _mode, _umask);
         stat.last_accessed = time_now();
         stat.last_modified = time_now();
         stat.last_metadata_changed = time_now();
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
+        inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
+        self.inherit_acl(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), acl);
+        self.inherit_compress_policy(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), &name);
+        self.inherit_crypt_context(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap());
         directory::dir_link(inode.as_mut().unwrap(), ino, ".".to_string());
         directory::dir_link(inode.as_mut().unwrap(), parent, "..".to_string());
-        directory::dir_link(parent_inode.as_mut().unwrap(), ino, name);
-        let stat = inode.as_ref().unwrap().borrow().get_stat();
+        if !self.link_new_inode(parent_inode.as_mut().unwrap(), inode.as_ref().unwrap(), name) {
+            debug!("WondFS: mkdir link not success");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            self.inode_manager.i_put(inode.unwrap());
+            reply.error(libc::EIO);
+            return;
+        }
+        // ".." of the new directory links to the parent
+        let mut stat = parent_inode.as_ref().unwrap().read().unwrap().get_stat();
+        stat.n_link += 1;
+        stat.last_modified = time_now();
+        stat.last_metadata_changed = time_now();
+        parent_inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
+        let stat = inode.as_ref().unwrap().read().unwrap().get_stat();
         let attr = transfer_stat_to_attr(stat);
+        self.inode_manager.i_lookup(inode.as_ref().unwrap());
         self.inode_manager.i_put(parent_inode.unwrap());
         self.inode_manager.i_put(inode.unwrap());
         reply.entry(&TTL, &attr, 0);
@@ -372,68 +1063,10 @@ impl Filesystem for WondFS {
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
-        let mut parent_inode = self.inode_manager.i_get(parent);
-        if parent_inode.is_none() {
-            reply.error(ENOENT);
-            debug!("WondFS: unlink parent not exists");
-            return;
-        }
-        let ino = directory::dir_lookup(parent_inode.as_ref().unwrap(), name.clone());
-        if ino.is_none() {
-            reply.error(ENOENT);
-            debug!("WondFS: unlink name not exists");
-            return;
-        }
-        let ino = ino.unwrap().0 as u32;
-        let inode = self.inode_manager.i_get(ino);
-        if inode.is_none() {
-            debug!("WondFS: unlink inode not exists");
-            reply.error(ENOENT);
-            return;
-        }
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::W_OK,
-        // ) {
-        //     debug!("WondFS: unlink no permission to access");
-        //     reply.error(libc::ENOENT);
-        //     return;
-        // }
-        // let uid = _req.uid();
-        // if parent_inode.as_ref().unwrap().borrow().mode & libc::S_ISVTX as u16 != 0
-        //     && uid != 0
-        //     && uid != parent_inode.as_ref().unwrap().borrow().uid
-        //     && uid != inode.as_ref().unwrap().borrow().uid
-        // {
-        //     // "Sticky bit" handling
-        //     debug!("WondFS: unlink no permission to access");
-        //     reply.error(ENOENT);
-        //     return;
-        // }
-        let mut stat = parent_inode.as_ref().unwrap().borrow().get_stat();
-        stat.last_modified = time_now();
-        stat.last_metadata_changed = time_now();
-        parent_inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        let ret = directory::dir_unlink(parent_inode.as_mut().unwrap(), ino, name);
-        if !ret {
-            debug!("WondFS: unlink not success");
-            reply.error(ENOENT);
-            return;
-        }
-        let mut stat = inode.as_ref().unwrap().borrow_mut().get_stat();
-        stat.n_link -= 1;
-        stat.last_metadata_changed = time_now();
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        if inode.as_ref().unwrap().borrow().get_stat().n_link == 0 {
-            inode.as_ref().unwrap().borrow_mut().delete();
+        match self.remove_entry(_req, parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error),
         }
-        self.inode_manager.i_put(parent_inode.unwrap());
-        self.inode_manager.i_put(inode.unwrap());
-        reply.ok();
     }
 
     /// Remove a directory
@@ -442,76 +1075,169 @@ impl Filesystem for WondFS {
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
+        if parent == self.snapshot_dir {
+            self.snapshot_rmdir(_req, name, reply);
+            return;
+        }
+        match self.remove_entry(_req, parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error),
+        }
+    }
+    
+    // Rename a file.
+    fn rename(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
+        trace!("WondFS: rename function called");
+        let parent = _parent as u32;
+        let name = _name.to_str().unwrap().to_string();
+        let newparent = _newparent as u32;
+        let newname = _newname.to_str().unwrap().to_string();
+        trace!("WondFS: parent: {}, name: {}, newparent: {}, newname: {}, flags: {}", parent, name, newparent, newname, _flags);
+        if self.is_read_only(parent) || self.is_read_only(newparent) {
+            debug!("WondFS: rename snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_snapshot_dir_entry(parent, &name) || self.is_snapshot_dir_entry(newparent, &newname) {
+            debug!("WondFS: rename snapshot directory is busy");
+            reply.error(libc::EBUSY);
+            return;
+        }
+        if self.is_crypt_locked(parent) || self.is_crypt_locked(newparent) {
+            debug!("WondFS: rename key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
+        if _flags & libc::RENAME_EXCHANGE as u32 != 0 {
+            debug!("WondFS: rename exchange not implemented");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if newname.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: rename name too long");
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
         let mut parent_inode = self.inode_manager.i_get(parent);
         if parent_inode.is_none() {
-            debug!("WondFS: rmdir parent not exists");
+            debug!("WondFS: rename parent not exists");
             reply.error(ENOENT);
             return;
         }
         let ino = directory::dir_lookup(parent_inode.as_ref().unwrap(), name.clone());
         if ino.is_none() {
-            debug!("WondFS: rmdir name not exists");
-            reply.error(libc::ENOENT);
-            return;
-        }
-        let ino = ino.unwrap().0 as u32;
-        let inode = self.inode_manager.i_get(ino);
-        if inode.is_none() {
-            debug!("WondFS: rmdir inode not exists");
+            debug!("WondFS: rename name not exists");
+            self.inode_manager.i_put(parent_inode.unwrap());
             reply.error(ENOENT);
             return;
         }
-        if inode.as_ref().unwrap().borrow().size > 28 {
-            debug!("WondFS: rmdir dir not empty");
+        let ino = ino.unwrap().0;
+        let mut newparent_inode = self.inode_manager.i_get(newparent);
+        if newparent_inode.is_none() {
+            debug!("WondFS: rename newparent not exists");
+            self.inode_manager.i_put(parent_inode.unwrap());
             reply.error(ENOENT);
             return;
         }
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::X_OK,
-        // ) {
-        //     debug!("WondFS: unlink no permission to access");
-        //     reply.error(libc::ENOENT);
-        //     return;
-        // }
-        // let uid = _req.uid();
-        // if parent_inode.as_ref().unwrap().borrow().mode & libc::S_ISVTX as u16 != 0
-        //     && uid != 0
-        //     && uid != parent_inode.as_ref().unwrap().borrow().uid
-        //     && uid != inode.as_ref().unwrap().borrow().uid
-        // {
-        //     // "Sticky bit" handling
-        //     debug!("WondFS: unlink no permission to access");
-        //     reply.error(ENOENT);
-        //     return;
-        // }
-        let mut stat = parent_inode.as_ref().unwrap().borrow().get_stat();
-        stat.last_modified = time_now();
-        stat.last_metadata_changed = time_now();
-        parent_inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        let ret = directory::dir_unlink(parent_inode.as_mut().unwrap(), ino, name);
-        if !ret {
-            debug!("WondFS: unlink not success");
-            reply.error(ENOENT);
-            return;
+        let mut inode = self.inode_manager.i_get(ino).unwrap();
+        let mut inodes = vec![parent_inode.take().unwrap(), newparent_inode.take().unwrap(), Arc::clone(&inode)];
+        let error = 'check: {
+            if !self.check_inode_access(&inodes[0], _req, libc::W_OK | libc::X_OK)
+                || !self.check_inode_access(&inodes[1], _req, libc::W_OK | libc::X_OK)
+            {
+                debug!("WondFS: rename no permission to access");
+                break 'check libc::EACCES;
+            }
+            if !check_sticky(inodes[0].read().unwrap().uid, inodes[0].read().unwrap().mode, inode.read().unwrap().uid, _req.uid()) {
+                debug!("WondFS: rename sticky directory not owner");
+                break 'check libc::EPERM;
+            }
+            if !self.is_crypt_policy_kept(&inodes[1], &inode) {
+                debug!("WondFS: rename encryption policy differs");
+                break 'check libc::EXDEV;
+            }
+            if inode.read().unwrap().is_crypt_locked() {
+                debug!("WondFS: rename key not available");
+                break 'check libc::ENOKEY;
+            }
+            let is_dir = inode.read().unwrap().file_type == inode::InodeFileType::Directory;
+            if is_dir && parent != newparent && self.is_ancestor(ino, newparent) {
+                debug!("WondFS: rename directory into itself");
+                break 'check libc::EINVAL;
+            }
+            let mut replaced_dir = false;
+            if let Some((target, _)) = directory::dir_lookup(&inodes[1], newname.clone()) {
+                if target == ino {
+                    break 'check 0;
+                }
+                if _flags & libc::RENAME_NOREPLACE as u32 != 0 {
+                    debug!("WondFS: rename target exists");
+                    break 'check libc::EEXIST;
+                }
+                let target_inode = self.inode_manager.i_get(target).unwrap();
+                inodes.push(Arc::clone(&target_inode));
+                if !check_sticky(inodes[1].read().unwrap().uid, inodes[1].read().unwrap().mode, target_inode.read().unwrap().uid, _req.uid()) {
+                    debug!("WondFS: rename sticky directory not owner of target");
+                    break 'check libc::EPERM;
+                }
+                let target_is_dir = target_inode.read().unwrap().file_type == inode::InodeFileType::Directory;
+                if is_dir && !target_is_dir {
+                    break 'check libc::ENOTDIR;
+                }
+                if !is_dir && target_is_dir {
+                    break 'check libc::EISDIR;
+                }
+                if target_is_dir && !directory::dir_is_empty(&target_inode) {
+                    debug!("WondFS: rename target dir not empty");
+                    break 'check libc::ENOTEMPTY;
+                }
+                directory::dir_unlink(&mut inodes[1], target, newname.clone());
+                replaced_dir = target_is_dir;
+                let mut stat = target_inode.read().unwrap().get_stat();
+                stat.n_link = if target_is_dir { 0 } else { stat.n_link - 1 };
+                stat.last_metadata_changed = time_now();
+                target_inode.write().unwrap().modify_stat(stat);
+                if stat.n_link == 0 {
+                    self.inode_manager.core_manager.lock().unwrap().add_orphan_inode(target);
+                }
+            }
+            directory::dir_unlink(&mut inodes[0], ino, name.clone());
+            directory::dir_link(&mut inodes[1], ino, newname.clone());
+            if is_dir && parent != newparent {
+                directory::dir_unlink(&mut inode, parent, "..".to_string());
+                directory::dir_link(&mut inode, newparent, "..".to_string());
+            }
+            let now = time_now();
+            for (index, link) in inodes[0..2].iter().enumerate() {
+                let mut stat = link.read().unwrap().get_stat();
+                // ".." of a moved directory links to its new parent, a replaced directory drops its link
+                if is_dir && parent != newparent {
+                    if index == 0 {
+                        stat.n_link -= 1;
+                    } else {
+                        stat.n_link += 1;
+                    }
+                }
+                if index == 1 && replaced_dir {
+                    stat.n_link -= 1;
+                }
+                stat.last_modified = now;
+                stat.last_metadata_changed = now;
+                link.write().unwrap().modify_stat(stat);
+            }
+            let mut stat = inode.read().unwrap().get_stat();
+            stat.last_metadata_changed = now;
+            inode.write().unwrap().modify_stat(stat);
+            0
+        };
+        for link in inodes {
+            self.inode_manager.i_put(link);
+        }
+        if error == 0 {
+            reply.ok();
+        } else {
+            reply.error(error);
         }
-        let mut stat = inode.as_ref().unwrap().borrow_mut().get_stat();
-        stat.n_link = 0;
-        stat.last_metadata_changed = time_now();
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        inode.as_ref().unwrap().borrow_mut().delete();
-        self.inode_manager.i_put(parent_inode.unwrap());
-        self.inode_manager.i_put(inode.unwrap());
-        reply.ok();
-    }
-    
-    // Rename a file.
-    fn rename(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
     }
 
     // Create a hard link.
@@ -521,32 +1247,10 @@ impl Filesystem for WondFS {
         let newparent = _newparent as u32;
         let newname = _newname.to_str().unwrap().to_string();
         trace!("WondFS: ino: {}, newparent: {}, newname: {}", ino, newparent, newname);
-        let mut parent_inode = self.inode_manager.i_get(newparent);
-        if parent_inode.is_none() {
-            reply.error(ENOENT);
-            debug!("WondFS: link parent not exists");
-            return;
-        }
-        let inode = self.inode_manager.i_get(ino);
-        if inode.is_none() {
-            reply.error(ENOENT);
-            debug!("WondFS: link inode not exists");
-            return;
+        match self.link_entry(_req, ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(error),
         }
-        let ret = directory::dir_link(parent_inode.as_mut().unwrap(), ino, newname);
-        if !ret {
-            debug!("WondFS: link not success");
-            reply.error(ENOENT);
-            return;
-        }
-        let mut stat = inode.as_ref().unwrap().borrow_mut().get_stat();
-        stat.n_link += 1;
-        stat.last_metadata_changed = time_now();
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        self.inode_manager.i_put(parent_inode.unwrap());
-        self.inode_manager.i_put(inode.unwrap());
-        let attr = transfer_stat_to_attr(stat);
-        reply.entry(&TTL, &attr, 0);
     }
 
     // Open a file.
@@ -554,45 +1258,68 @@ impl Filesystem for WondFS {
         trace!("WondFS: open function called");
         let ino = _ino as u32;
         trace!("WondFS: ino: {}", ino);
-        // let (access_mask, read, write) = match _flags & libc::O_ACCMODE {
-        //     libc::O_RDONLY => {
-        //         if _flags & libc::O_TRUNC != 0 {
-        //             debug!("WondFS: open access_mask error");
-        //             reply.error(libc::EACCES);
-        //             return;
-        //         }
-        //         (libc::R_OK, true, false)
-        //     }
-        //     libc::O_WRONLY => (libc::W_OK, false, true),
-        //     libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-        //     _ => {
-        //         debug!("WondFS: open access_mask error");
-        //         reply.error(libc::EINVAL);
-        //         return;
-        //     }
-        // };
+        let (mut access_mask, read, write) = match _flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (libc::R_OK, true, false),
+            libc::O_WRONLY => (libc::W_OK, false, true),
+            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            _ => {
+                debug!("WondFS: open access_mask error");
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if _flags & libc::O_TRUNC != 0 {
+            access_mask |= libc::W_OK;
+        }
+        if access_mask & libc::W_OK != 0 && self.is_read_only(ino) {
+            debug!("WondFS: open snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(ino) {
+            debug!("WondFS: open key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let inode = self.inode_manager.i_get(ino);
         match inode {
             Some(inode) => {
-                // if check_access(
-                //     inode.borrow().uid,
-                //     inode.borrow().gid,
-                //     inode.borrow().mode,
-                //     _req.uid(),
-                //     _req.gid(),
-                //     access_mask,
-                // ) {
-                //     let mut stat = inode.borrow_mut().get_stat();
-                //     stat.ref_cnt += 1;
-                //     inode.borrow_mut().modify_stat(stat);
-                //     reply.opened(self.allocate_next_file_handle(read, write), 1);
-                // } else {
-                //     debug!("WondFS: open no permission to access");
-                //     reply.error(ENOENT);
-                // }
-                inode.borrow_mut().ref_cnt += 1;
+                if !self.check_inode_access(&inode, _req, access_mask) {
+                    debug!("WondFS: open no permission to access");
+                    self.inode_manager.i_put(inode);
+                    reply.error(libc::EACCES);
+                    return;
+                }
+                let stat = inode.read().unwrap().get_stat();
+                let is_dir = stat.file_type == inode::InodeFileType::Directory;
+                let error = if _flags & libc::O_DIRECTORY != 0 && !is_dir {
+                    libc::ENOTDIR
+                } else if is_dir && write {
+                    libc::EISDIR
+                } else if _flags & libc::O_NOATIME != 0 && _req.uid() != 0 && _req.uid() != stat.uid {
+                    // only the owner may hide its reads
+                    libc::EPERM
+                } else {
+                    0
+                };
+                if error != 0 {
+                    debug!("WondFS: open flags not allowed, flags: {:o}", _flags);
+                    self.inode_manager.i_put(inode);
+                    reply.error(error);
+                    return;
+                }
+                let is_file = stat.file_type == inode::InodeFileType::File;
+                if _flags & libc::O_TRUNC != 0 && is_file {
+                    if let Err(error) = self.truncate_inode(&inode, 0, _req) {
+                        debug!("WondFS: open truncate not success");
+                        self.inode_manager.i_put(inode);
+                        reply.error(error);
+                        return;
+                    }
+                }
+                self.inode_manager.i_dup(&inode);
                 self.inode_manager.i_put(inode);
-                reply.opened(self.allocate_next_file_handle(true, true), 1);
+                reply.opened(self.allocate_next_file_handle(read, write, _flags), 1);
             },
             None => {
                 debug!("WondFS: open ino not exists");
@@ -605,22 +1332,26 @@ impl Filesystem for WondFS {
     fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
         trace!("WondFS: read function called");
         let ino =  _ino as u32;
-        let offset = _offset as u32;
-        let size = _size as u32;
+        let offset = _offset as u64;
+        let size = _size as u64;
         trace!("WondFS: ino: {}, offset: {}, size: {}", ino, offset, size);
-        // if !check_file_handle_read(_fh) {
-        //     debug!("WondFS: read no permission to read");
-        //     reply.error(ENOENT);
-        //     return;
-        // }
+        if !check_file_handle_read(_fh) {
+            debug!("WondFS: read handle not opened for reading");
+            reply.error(libc::EBADF);
+            return;
+        }
         let inode = self.inode_manager.i_get(ino);
         match inode {
             Some(inode) => {
                 let mut data = vec![];
-                let read_size = min(size, inode.borrow().get_stat().size);
+                let read_size = min(size, inode.read().unwrap().get_stat().size);
                 let ret;
-                ret = inode.borrow_mut().read(offset, read_size, &mut data);
-                // if offset >= inode.borrow().get_stat().size {
+                ret = inode.write().unwrap().read(offset, read_size, &mut data);
+                let past_end = offset >= inode.read().unwrap().size;
+                if ret >= 0 {
+                    self.update_atime(&inode, ino, _fh);
+                }
+                // if offset >= inode.read().unwrap().get_stat().size {
                 //     debug!("WondFS: read inode error");
                 //     reply.error(ENOENT);
                 //     return;
@@ -628,8 +1359,12 @@ impl Filesystem for WondFS {
                 self.inode_manager.i_put(inode);
                 if ret >= 0 {
                     reply.data(&data);
+                } else if !past_end {
+                    debug!("WondFS: read stored data can't be decrypted or decompressed");
+                    reply.error(libc::EIO);
                 } else {
-                    reply.error(ENOENT);
+                    // reading at or past the end of file returns no data
+                    reply.data(&[]);
                 }
             },
             None => {
@@ -643,29 +1378,118 @@ impl Filesystem for WondFS {
     fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
         trace!("WondFS: write function called");
         let ino = _ino as u32;
-        let offset = _offset as u32;
+        let offset = _offset as u64;
         let data = _data;
         trace!("WondFS: ino: {}, offset: {}, data: {:?}", ino, offset, data);
-        // if !check_file_handle_write(_fh) {
-        //     debug!("WondFS: write no permission to write");
-        //     reply.error(ENOENT);
-        //     return;
-        // }
+        if self.is_read_only(ino) {
+            debug!("WondFS: write snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if _offset < 0 {
+            debug!("WondFS: write negative offset");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if offset + data.len() as u64 > inode::MAX_FILE_SIZE {
+            debug!("WondFS: write beyond max file size");
+            reply.error(libc::EFBIG);
+            return;
+        }
+        if !check_file_handle_write(_fh) {
+            debug!("WondFS: write handle not opened for writing");
+            reply.error(libc::EBADF);
+            return;
+        }
+        let inode = self.inode_manager.i_get(ino);
+        match inode {
+            Some(inode) => {
+                let mut guard = inode.write().unwrap();
+                // appending handles always write at the end, wherever the kernel thinks it is
+                let offset = if check_file_handle_append(_fh) { guard.size } else { offset };
+                let error = if offset + data.len() as u64 > inode::MAX_FILE_SIZE {
+                    libc::EFBIG
+                } else if guard.is_crypt_locked() {
+                    libc::ENOKEY
+                } else if !guard.write(offset, data.len() as u64, &data.to_vec()) {
+                    libc::EIO
+                } else {
+                    0
+                };
+                drop(guard);
+                if error == 0 && _req.uid() != 0 {
+                    // writing by a non-root user drops the set-id bits
+                    let mut stat = inode.read().unwrap().get_stat();
+                    let mode = clear_suid_sgid(stat.mode);
+                    if mode != stat.mode {
+                        stat.mode = mode;
+                        stat.last_metadata_changed = time_now();
+                        inode.write().unwrap().modify_stat(stat);
+                    }
+                }
+                self.inode_manager.i_put(inode);
+                if error == 0 {
+                    reply.written(data.len() as u32);
+                } else {
+                    debug!("WondFS: write not success");
+                    reply.error(error);
+                }
+            },
+            None => {
+                debug!("WondFS: write ino not exists");
+                reply.error(libc::ENOENT);
+            },
+        }
+    }
+
+    // Flush method, called on each close() of an opened file.
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        trace!("WondFS: flush function called");
+        trace!("WondFS: ino: {}, fh: {}", _ino, _fh);
+        // close writes back the same state fsync does, so a closed file survives a crash
+        self.inode_manager.sync();
+        self.inode_manager.core_manager.lock().unwrap().sync();
+        reply.ok();
+    }
+
+    // Reposition read/write file offset, only SEEK_DATA and SEEK_HOLE need the extent list.
+    fn lseek(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _whence: i32, reply: ReplyLseek) {
+        trace!("WondFS: lseek function called");
+        let ino = _ino as u32;
+        trace!("WondFS: ino: {}, offset: {}, whence: {}", ino, _offset, _whence);
+        if _offset < 0 {
+            debug!("WondFS: lseek negative offset");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if _whence == libc::SEEK_SET {
+            reply.offset(_offset);
+            return;
+        }
+        if _whence != libc::SEEK_DATA && _whence != libc::SEEK_HOLE {
+            debug!("WondFS: lseek whence not supported");
+            reply.error(libc::EINVAL);
+            return;
+        }
         let inode = self.inode_manager.i_get(ino);
-        match inode {
-            Some(inode) => {
-                let ret = inode.borrow_mut().write(offset, data.len() as u32, &data.to_vec());
-                self.inode_manager.i_put(inode);
-                if ret {
-                    reply.written(data.len() as u32);
-                } else {
-                    debug!("WondFS: write inode error");
-                    reply.error(libc::ENOENT)
-                }
-            },
+        if inode.is_none() {
+            debug!("WondFS: lseek ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        let offset = _offset as u64;
+        let ret = if _whence == libc::SEEK_DATA {
+            inode.read().unwrap().seek_data(offset)
+        } else {
+            inode.read().unwrap().seek_hole(offset)
+        };
+        self.inode_manager.i_put(inode);
+        match ret {
+            Some(offset) => reply.offset(offset as i64),
             None => {
-                debug!("WondFS: write ino not exists");
-                reply.error(libc::ENOENT);
+                debug!("WondFS: lseek offset beyond data");
+                reply.error(libc::ENXIO);
             },
         }
     }
@@ -681,54 +1505,55 @@ impl Filesystem for WondFS {
             reply.error(ENOENT);
             return;
         }
-        inode.as_ref().unwrap().borrow_mut().ref_cnt -= 1;
+        // drop the reference taken by open along with this one
+        self.inode_manager.i_put(Arc::clone(inode.as_ref().unwrap()));
         self.inode_manager.i_put(inode.unwrap());
         reply.ok();
     }
 
+    // Synchronize file contents.
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        trace!("WondFS: fsync function called");
+        trace!("WondFS: ino: {}, fh: {}, datasync: {}", _ino, _fh, _datasync);
+        self.inode_manager.sync();
+        self.inode_manager.core_manager.lock().unwrap().sync();
+        reply.ok();
+    }
+
     // Open a directory.
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
         trace!("WondFS: opendir function called");
         let ino = _ino as u32;
         trace!("WondFS: ino: {}", ino);
-        // let (access_mask, read, write) = match _flags & libc::O_ACCMODE {
-        //     libc::O_RDONLY => {
-        //         if _flags & libc::O_TRUNC != 0 {
-        //             debug!("WondFS: opendir access_mask error");
-        //             reply.error(libc::EACCES);
-        //             return;
-        //         }
-        //         (libc::R_OK, true, false)
-        //     }
-        //     libc::O_WRONLY => (libc::W_OK, false, true),
-        //     libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-        //     _ => {
-        //         debug!("WondFS: opendir access_mask error");
-        //         reply.error(libc::EINVAL);
-        //         return;
-        //     }
-        // };
+        if self.is_crypt_locked(ino) {
+            debug!("WondFS: opendir key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let inode = self.inode_manager.i_get(ino);
         match inode {
             Some(inode) => {
-                // if check_access(
-                //     inode.borrow().uid,
-                //     inode.borrow().gid,
-                //     inode.borrow().mode,
-                //     _req.uid(),
-                //     _req.gid(),
-                //     access_mask,
-                // ) {
-                //     let mut stat = inode.borrow_mut().get_stat();
-                //     stat.ref_cnt += 1;
-                //     inode.borrow_mut().modify_stat(stat);
-                //     reply.opened(self.allocate_next_file_handle(read, write), 1);
-                // } else {
-                //     debug!("WondFS: opendir no permission to access");
-                //     reply.error(ENOENT);
-                // }
-                inode.borrow_mut().ref_cnt += 1;
-                reply.opened(self.allocate_next_file_handle(true, true), 1);
+                let stat = inode.read().unwrap().get_stat();
+                if stat.file_type != inode::InodeFileType::Directory {
+                    debug!("WondFS: opendir not a directory");
+                    self.inode_manager.i_put(inode);
+                    reply.error(libc::ENOTDIR);
+                    return;
+                }
+                if !self.check_inode_access(&inode, _req, libc::R_OK) {
+                    debug!("WondFS: opendir no permission to access");
+                    self.inode_manager.i_put(inode);
+                    reply.error(libc::EACCES);
+                    return;
+                }
+                if _flags & libc::O_NOATIME != 0 && _req.uid() != 0 && _req.uid() != stat.uid {
+                    debug!("WondFS: opendir noatime not owner");
+                    self.inode_manager.i_put(inode);
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                self.inode_manager.i_dup(&inode);
+                reply.opened(self.allocate_next_file_handle(true, false, _flags), 1);
                 self.inode_manager.i_put(inode);
             },
             None => {
@@ -751,20 +1576,30 @@ impl Filesystem for WondFS {
             return;
         }
         let mut data = vec![];
-        inode.as_ref().unwrap().borrow_mut().read_all(&mut data);
+        inode.as_ref().unwrap().write().unwrap().read_all(&mut data);
         let iter = directory::DirectoryParser::new(&data);
         // TOOD: something wrong
         for (index, entry) in iter.skip(offset as usize).enumerate() {
+            if entry.ino == 0 {
+                continue;
+            }
+            let entry_inode = self.inode_manager.i_get(entry.ino);
+            if entry_inode.is_none() {
+                continue;
+            }
+            let kind = entry_inode.as_ref().unwrap().read().unwrap().file_type;
+            self.inode_manager.i_put(entry_inode.unwrap());
             let buffer_full: bool = reply.add(
                 entry.ino as u64,
                 offset as i64 + index as i64 + 1,
-                FileType::RegularFile,
+                kind.into(),
                 OsStr::from_bytes(entry.file_name.as_bytes()),
             );
             if buffer_full {
                 break;
             }
         }
+        self.update_atime(inode.as_ref().unwrap(), ino, _fh);
         self.inode_manager.i_put(inode.unwrap());
         reply.ok()
     }
@@ -780,11 +1615,21 @@ impl Filesystem for WondFS {
             reply.error(ENOENT);
             return;
         }
-        inode.as_ref().unwrap().borrow_mut().ref_cnt -= 1;
+        // drop the reference taken by opendir along with this one
+        self.inode_manager.i_put(Arc::clone(inode.as_ref().unwrap()));
         self.inode_manager.i_put(inode.unwrap());
         reply.ok();
     }
 
+    // Synchronize directory contents.
+    fn fsyncdir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        trace!("WondFS: fsyncdir function called");
+        trace!("WondFS: ino: {}, fh: {}, datasync: {}", _ino, _fh, _datasync);
+        self.inode_manager.sync();
+        self.inode_manager.core_manager.lock().unwrap().sync();
+        reply.ok();
+    }
+
     // Check file access permission.
     fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         trace!("WondFS: access function called");
@@ -796,14 +1641,14 @@ impl Filesystem for WondFS {
             reply.error(ENOENT);
             return;
         }
-        // if check_access(inode.as_ref().unwrap().borrow().uid, inode.as_ref().unwrap().borrow().gid, inode.as_ref().unwrap().borrow().mode, _req.uid(), _req.gid(), _mask) {
-        //     reply.ok();
-        // } else {
-        //     debug!("WondFS: access no permission to access");
-        //     reply.error(libc::EACCES);
-        // }
+        let ret = self.check_inode_access(inode.as_ref().unwrap(), _req, _mask);
         self.inode_manager.i_put(inode.unwrap());
-        reply.ok();
+        if ret {
+            reply.ok();
+        } else {
+            debug!("WondFS: access no permission to access");
+            reply.error(libc::EACCES);
+        }
     }
 
     // Create and open a file.
@@ -812,6 +1657,29 @@ impl Filesystem for WondFS {
         let parent = _parent as u32;
         let name = _name.to_str().unwrap().to_string();
         trace!("WondFS: parent: {}, name: {}", parent, name);
+        if name.len() > directory::DIRECTORY_NAME_MAX {
+            debug!("WondFS: create name too long");
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        let file_type = match as_file_kind(_mode) {
+            Some(file_type) => file_type,
+            None => {
+                debug!("WondFS: create unknown file type {:o}", _mode);
+                reply.error(libc::EINVAL);
+                return;
+            },
+        };
+        if self.is_read_only(parent) {
+            debug!("WondFS: create snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(parent) {
+            debug!("WondFS: create key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
         let mut parent_inode = self.inode_manager.i_get(parent);
         if parent_inode.is_none() {
             debug!("WondFS: create parent not exists");
@@ -820,78 +1688,667 @@ impl Filesystem for WondFS {
         }
         let ino = directory::dir_lookup(parent_inode.as_ref().unwrap(), name.clone());
         if ino.is_some() {
+            // the kernel opens existing names itself, so this is an exclusive create racing another one
             debug!("WondFS: create name has exist");
-            reply.error(ENOENT);
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EEXIST);
+            return;
+        }
+        let (read, write) = match _flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            _ => {
+                debug!("WondFS: create read&write error");
+                self.inode_manager.i_put(parent_inode.unwrap());
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if !self.check_inode_access(parent_inode.as_ref().unwrap(), _req, libc::W_OK | libc::X_OK) {
+            debug!("WondFS: create no permission to access");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::EACCES);
             return;
         }
-        // let (read, write) = match _flags & libc::O_ACCMODE {
-        //     libc::O_RDONLY => (true, false),
-        //     libc::O_WRONLY => (false, true),
-        //     libc::O_RDWR => (true, true),
-        //     _ => {
-        //         debug!("WondFS: create read&write error");
-        //         reply.error(libc::EINVAL);
-        //         return;
-        //     }
-        // };
-        // if !check_access(
-        //     parent_inode.as_ref().unwrap().borrow().uid,
-        //     parent_inode.as_ref().unwrap().borrow().gid,
-        //     parent_inode.as_ref().unwrap().borrow().mode,
-        //     _req.uid(),
-        //     _req.gid(),
-        //     libc::W_OK,
-        // ) {
-        //     debug!("WondFS: create no permission to access");
-        //     reply.error(libc::EACCES);
-        //     return;
-        // }
-        let mut stat = parent_inode.as_ref().unwrap().borrow().get_stat();
+        let mut stat = parent_inode.as_ref().unwrap().read().unwrap().get_stat();
         stat.last_modified = time_now();
         stat.last_metadata_changed = time_now();
-        parent_inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        // if _req.uid() != 0 {
-        //     _mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
-        // }
+        parent_inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
         let mut inode = self.inode_manager.i_alloc();
         if inode.is_none() {
             debug!("WondFS: create inode alloc error");
-            reply.error(ENOENT);
+            self.inode_manager.i_put(parent_inode.unwrap());
+            reply.error(libc::ENOSPC);
             return;
         }
-        let ino = inode.as_ref().unwrap().borrow().ino;
-        let mut stat = inode.as_ref().unwrap().borrow().get_stat();
-        stat.file_type = as_file_kind(_mode);
+        let ino = inode.as_ref().unwrap().read().unwrap().ino;
+        let mut stat = inode.as_ref().unwrap().read().unwrap().get_stat();
+        stat.file_type = file_type;
         stat.size = 0;
         stat.ref_cnt = 1;
-        stat.mode = 0o777;
-        stat.uid = 0;
-        stat.gid = 0;
+        let acl = self.init_owner(_req, parent_inode.as_ref().unwrap(), &mut stat, _mode, _umask);
         stat.last_accessed = time_now();
         stat.last_modified = time_now();
         stat.last_metadata_changed = time_now();
-        if stat.file_type == inode::InodeFileType::File {
-            stat.n_link = 1;
-        }
         if stat.file_type == inode::InodeFileType::Directory {
             stat.n_link = 2;
+        } else {
+            stat.n_link = 1;
         }
-        inode.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        if inode.as_ref().unwrap().borrow().file_type == inode::InodeFileType::Directory {
+        inode.as_ref().unwrap().write().unwrap().modify_stat(stat);
+        self.inherit_acl(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), acl);
+        self.inherit_compress_policy(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap(), &name);
+        self.inherit_crypt_context(parent_inode.as_ref().unwrap(), inode.as_ref().unwrap());
+        if inode.as_ref().unwrap().read().unwrap().file_type == inode::InodeFileType::Directory {
             directory::dir_link(inode.as_mut().unwrap(), ino, ".".to_string());
             directory::dir_link(inode.as_mut().unwrap(), parent, "..".to_string());
         }
-        directory::dir_link(parent_inode.as_mut().unwrap(), ino, name);
-        let stat = inode.as_ref().unwrap().borrow().get_stat();
+        if !self.link_new_inode(parent_inode.as_mut().unwrap(), inode.as_ref().unwrap(), name) {
+            debug!("WondFS: create link not success");
+            self.inode_manager.i_put(parent_inode.unwrap());
+            self.inode_manager.i_put(inode.unwrap());
+            reply.error(libc::EIO);
+            return;
+        }
+        let stat = inode.as_ref().unwrap().read().unwrap().get_stat();
         let attr = transfer_stat_to_attr(stat);
+        // the entry is counted as a lookup and the handle holds its own reference until release
+        self.inode_manager.i_lookup(inode.as_ref().unwrap());
+        self.inode_manager.i_dup(inode.as_ref().unwrap());
         self.inode_manager.i_put(parent_inode.unwrap());
         self.inode_manager.i_put(inode.unwrap());
         reply.created(
             &TTL,
             &attr,
             0,
-            self.allocate_next_file_handle(true, true),
+            self.allocate_next_file_handle(read, write, _flags),
             0,
         );
     }
-}
\ No newline at end of file
+
+    // Get file system statistics.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        trace!("WondFS: statfs function called");
+        let stat = self.inode_manager.core_manager.lock().unwrap().statfs();
+        trace!("WondFS: stat: {:?}", stat);
+        reply.statfs(
+            stat.blocks,
+            stat.bfree,
+            stat.bavail,
+            stat.files,
+            stat.ffree,
+            stat.bsize,
+            directory::DIRECTORY_NAME_MAX as u32,
+            stat.bsize,
+        );
+    }
+
+    // Set an extended attribute.
+    fn setxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _value: &[u8], _flags: i32, _position: u32, reply: ReplyEmpty) {
+        trace!("WondFS: setxattr function called");
+        let ino = _ino as u32;
+        let name = _name.to_str().unwrap().to_string();
+        trace!("WondFS: ino: {}, name: {}, len: {}, flags: {}", ino, name, _value.len(), _flags);
+        if self.is_read_only(ino) {
+            debug!("WondFS: setxattr snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if _position != 0 {
+            debug!("WondFS: setxattr position not supported");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if _value.len() > xattr::XATTR_SIZE_MAX {
+            debug!("WondFS: setxattr value too large");
+            reply.error(libc::E2BIG);
+            return;
+        }
+        let namespace = match xattr::check_xattr_name(&name) {
+            Ok(namespace) => namespace,
+            Err(err) => {
+                debug!("WondFS: setxattr name not supported");
+                reply.error(err);
+                return;
+            },
+        };
+        let inode = self.inode_manager.i_get(ino);
+        if inode.is_none() {
+            debug!("WondFS: setxattr ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        let stat = inode.read().unwrap().get_stat();
+        let groups = get_groups(_req.pid());
+        let acl = self.get_acl(&inode, xattr::POSIX_ACL_ACCESS);
+        if let Err(err) = xattr::check_xattr_access(namespace, &name, &stat, acl.as_deref(), _req.uid(), _req.gid(), &groups, true) {
+            debug!("WondFS: setxattr no permission to access");
+            self.inode_manager.i_put(inode);
+            reply.error(err);
+            return;
+        }
+        let exists = inode.read().unwrap().get_xattr(&name).is_some();
+        if exists && _flags & libc::XATTR_CREATE != 0 {
+            debug!("WondFS: setxattr name has exist");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::EEXIST);
+            return;
+        }
+        if !exists && _flags & libc::XATTR_REPLACE != 0 {
+            debug!("WondFS: setxattr name not exists");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::ENODATA);
+            return;
+        }
+        let mut value = _value.to_vec();
+        let mut stat = inode.read().unwrap().get_stat();
+        if namespace == xattr::XattrNamespace::System {
+            let acl = match parse_acl(_value) {
+                Some(acl) => acl,
+                None => {
+                    debug!("WondFS: setxattr invalid acl");
+                    self.inode_manager.i_put(inode);
+                    reply.error(libc::EINVAL);
+                    return;
+                },
+            };
+            if name == xattr::POSIX_ACL_ACCESS {
+                stat.mode = acl_to_mode(&acl, stat.mode);
+                if acl_is_equiv_mode(&acl) {
+                    // a minimal ACL is fully described by the mode bits
+                    inode.write().unwrap().remove_xattr(&name);
+                    stat.last_metadata_changed = time_now();
+                    inode.write().unwrap().modify_stat(stat);
+                    self.inode_manager.i_put(inode);
+                    reply.ok();
+                    return;
+                }
+            }
+            value = encode_acl(&acl);
+        }
+        if name == compress::COMPRESS_XATTR && compress::CompressPolicy::parse(&value).is_none() {
+            debug!("WondFS: setxattr invalid compress policy");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::EINVAL);
+            return;
+        }
+        inode.write().unwrap().set_xattr(&name, &value);
+        stat.last_metadata_changed = time_now();
+        inode.write().unwrap().modify_stat(stat);
+        self.inode_manager.i_put(inode);
+        reply.ok();
+    }
+
+    // Get an extended attribute.
+    fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        trace!("WondFS: getxattr function called");
+        let ino = _ino as u32;
+        let name = _name.to_str().unwrap().to_string();
+        trace!("WondFS: ino: {}, name: {}, size: {}", ino, name, _size);
+        let namespace = match xattr::check_xattr_name(&name) {
+            Ok(namespace) => namespace,
+            Err(err) => {
+                debug!("WondFS: getxattr name not supported");
+                reply.error(err);
+                return;
+            },
+        };
+        let inode = self.inode_manager.i_get(ino);
+        if inode.is_none() {
+            debug!("WondFS: getxattr ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        let stat = inode.read().unwrap().get_stat();
+        let groups = get_groups(_req.pid());
+        let acl = self.get_acl(&inode, xattr::POSIX_ACL_ACCESS);
+        let ret = xattr::check_xattr_access(namespace, &name, &stat, acl.as_deref(), _req.uid(), _req.gid(), &groups, false);
+        let value = inode.read().unwrap().get_xattr(&name);
+        self.inode_manager.i_put(inode);
+        if let Err(err) = ret {
+            debug!("WondFS: getxattr no permission to access");
+            reply.error(err);
+            return;
+        }
+        match value {
+            Some(value) => {
+                if _size == 0 {
+                    reply.size(value.len() as u32);
+                } else if value.len() > _size as usize {
+                    reply.error(libc::ERANGE);
+                } else {
+                    reply.data(&value);
+                }
+            },
+            None => {
+                debug!("WondFS: getxattr name not exists");
+                reply.error(libc::ENODATA);
+            },
+        }
+    }
+
+    // List extended attribute names.
+    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
+        trace!("WondFS: listxattr function called");
+        let ino = _ino as u32;
+        trace!("WondFS: ino: {}, size: {}", ino, _size);
+        let inode = self.inode_manager.i_get(ino);
+        if inode.is_none() {
+            debug!("WondFS: listxattr ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        let names: Vec<String> = inode.read().unwrap().list_xattr()
+            .into_iter()
+            .filter(|name| xattr::xattr_visible(name, _req.uid()))
+            .collect();
+        self.inode_manager.i_put(inode);
+        let data = xattr::encode_xattr_names(&names);
+        if _size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > _size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&data);
+        }
+    }
+
+    // Remove an extended attribute.
+    fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        trace!("WondFS: removexattr function called");
+        let ino = _ino as u32;
+        let name = _name.to_str().unwrap().to_string();
+        trace!("WondFS: ino: {}, name: {}", ino, name);
+        if self.is_read_only(ino) {
+            debug!("WondFS: removexattr snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        let namespace = match xattr::check_xattr_name(&name) {
+            Ok(namespace) => namespace,
+            Err(err) => {
+                debug!("WondFS: removexattr name not supported");
+                reply.error(err);
+                return;
+            },
+        };
+        let inode = self.inode_manager.i_get(ino);
+        if inode.is_none() {
+            debug!("WondFS: removexattr ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        let stat = inode.read().unwrap().get_stat();
+        let groups = get_groups(_req.pid());
+        let acl = self.get_acl(&inode, xattr::POSIX_ACL_ACCESS);
+        if let Err(err) = xattr::check_xattr_access(namespace, &name, &stat, acl.as_deref(), _req.uid(), _req.gid(), &groups, true) {
+            debug!("WondFS: removexattr no permission to access");
+            self.inode_manager.i_put(inode);
+            reply.error(err);
+            return;
+        }
+        if !inode.write().unwrap().remove_xattr(&name) {
+            debug!("WondFS: removexattr name not exists");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::ENODATA);
+            return;
+        }
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.last_metadata_changed = time_now();
+        inode.write().unwrap().modify_stat(stat);
+        self.inode_manager.i_put(inode);
+        reply.ok();
+    }
+
+    // Control device, WONDFS_IOC_REFLINK shares a page aligned range of another file,
+    // WONDFS_IOC_SET_ENCRYPTION_POLICY encrypts an empty directory.
+    fn ioctl(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: u32, _cmd: u32, _in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        trace!("WondFS: ioctl function called");
+        let ino = _ino as u32;
+        trace!("WondFS: ino: {}, cmd: {:#x}", ino, _cmd);
+        if _cmd == WONDFS_IOC_SET_ENCRYPTION_POLICY {
+            self.set_encryption_policy(_req, ino, reply);
+            return;
+        }
+        if _cmd != WONDFS_IOC_REFLINK {
+            debug!("WondFS: ioctl cmd not supported");
+            reply.error(libc::ENOTTY);
+            return;
+        }
+        if _in_data.len() < 32 {
+            debug!("WondFS: ioctl reflink args too short");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let args = _in_data[..32].chunks(8).map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap())).collect::<Vec<u64>>();
+        let (src_ino, src_offset, dst_offset, len) = (args[0] as u32, args[1], args[2], args[3]);
+        if self.is_read_only(ino) {
+            debug!("WondFS: ioctl snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if !check_file_handle_write(_fh) {
+            debug!("WondFS: ioctl reflink no permission to write");
+            reply.error(libc::EBADF);
+            return;
+        }
+        let src = self.inode_manager.i_get(src_ino);
+        if src.is_none() {
+            debug!("WondFS: ioctl reflink src ino not exists");
+            reply.error(libc::EBADF);
+            return;
+        }
+        let src = src.unwrap();
+        if !self.check_inode_access(&src, _req, libc::R_OK) {
+            debug!("WondFS: ioctl reflink no permission to read src");
+            self.inode_manager.i_put(src);
+            reply.error(libc::EACCES);
+            return;
+        }
+        let dst = self.inode_manager.i_get(ino);
+        if dst.is_none() {
+            debug!("WondFS: ioctl reflink ino not exists");
+            self.inode_manager.i_put(src);
+            reply.error(ENOENT);
+            return;
+        }
+        let dst = dst.unwrap();
+        let ret = self.copy_range(&src, src_offset, &dst, dst_offset, len, false);
+        self.inode_manager.i_put(src);
+        self.inode_manager.i_put(dst);
+        match ret {
+            Ok(_) => reply.ioctl(0, &[]),
+            Err(err) => {
+                debug!("WondFS: ioctl reflink error");
+                reply.error(err);
+            },
+        }
+    }
+
+    // Copy data between files, pages are shared when the range allows it.
+    fn copy_file_range(&mut self, _req: &Request<'_>, _ino_in: u64, _fh_in: u64, _offset_in: i64, _ino_out: u64, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u32, reply: ReplyWrite) {
+        trace!("WondFS: copy_file_range function called");
+        let ino_in = _ino_in as u32;
+        let ino_out = _ino_out as u32;
+        trace!("WondFS: ino_in: {}, offset_in: {}, ino_out: {}, offset_out: {}, len: {}", ino_in, _offset_in, ino_out, _offset_out, _len);
+        if self.is_read_only(ino_out) {
+            debug!("WondFS: copy_file_range snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(ino_in) || self.is_crypt_locked(ino_out) {
+            debug!("WondFS: copy_file_range key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
+        if _flags != 0 || _offset_in < 0 || _offset_out < 0 {
+            debug!("WondFS: copy_file_range invalid args");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if !check_file_handle_read(_fh_in) || !check_file_handle_write(_fh_out) {
+            debug!("WondFS: copy_file_range bad file handle");
+            reply.error(libc::EBADF);
+            return;
+        }
+        let src = self.inode_manager.i_get(ino_in);
+        if src.is_none() {
+            debug!("WondFS: copy_file_range ino_in not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let src = src.unwrap();
+        let dst = self.inode_manager.i_get(ino_out);
+        if dst.is_none() {
+            debug!("WondFS: copy_file_range ino_out not exists");
+            self.inode_manager.i_put(src);
+            reply.error(ENOENT);
+            return;
+        }
+        let dst = dst.unwrap();
+        // the reply carries a u32, keep the clamped len page aligned
+        let len = min(_len, u32::MAX as u64 & !(inode::PAGE_SIZE - 1));
+        let ret = self.copy_range(&src, _offset_in as u64, &dst, _offset_out as u64, len, true);
+        self.inode_manager.i_put(src);
+        self.inode_manager.i_put(dst);
+        match ret {
+            Ok(written) => reply.written(written as u32),
+            Err(err) => {
+                debug!("WondFS: copy_file_range error");
+                reply.error(err);
+            },
+        }
+    }
+
+    // Deallocate space for a file, only hole punching is supported.
+    fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _length: i64, _mode: i32, reply: ReplyEmpty) {
+        trace!("WondFS: fallocate function called");
+        let ino = _ino as u32;
+        trace!("WondFS: ino: {}, offset: {}, length: {}, mode: {}", ino, _offset, _length, _mode);
+        if self.is_read_only(ino) {
+            debug!("WondFS: fallocate snapshot is read only");
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.is_crypt_locked(ino) {
+            debug!("WondFS: fallocate key not available");
+            reply.error(libc::ENOKEY);
+            return;
+        }
+        if _offset < 0 || _length <= 0 {
+            debug!("WondFS: fallocate invalid range");
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if _offset.checked_add(_length).map_or(true, |end| end as u64 > inode::MAX_FILE_SIZE) {
+            debug!("WondFS: fallocate range too large");
+            reply.error(libc::EFBIG);
+            return;
+        }
+        let offset = _offset as u64;
+        let length = _length as u64;
+        // pages are written out of place and may be compressed or shared, so space can't be
+        // reserved ahead of the data and only punching holes is supported
+        if _mode != libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE {
+            debug!("WondFS: fallocate mode not supported");
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+        if !check_file_handle_write(_fh) {
+            debug!("WondFS: fallocate no permission to write");
+            reply.error(libc::EBADF);
+            return;
+        }
+        let inode = self.inode_manager.i_get(ino);
+        if inode.is_none() {
+            debug!("WondFS: fallocate ino not exists");
+            reply.error(ENOENT);
+            return;
+        }
+        let inode = inode.unwrap();
+        if inode.read().unwrap().file_type != inode::InodeFileType::File {
+            debug!("WondFS: fallocate not a regular file");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::ENODEV);
+            return;
+        }
+        if !inode.write().unwrap().punch_hole(offset, length) {
+            debug!("WondFS: fallocate internal error");
+            self.inode_manager.i_put(inode);
+            reply.error(libc::EIO);
+            return;
+        }
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.last_modified = time_now();
+        stat.last_metadata_changed = stat.last_modified;
+        inode.write().unwrap().modify_stat(stat);
+        self.inode_manager.i_put(inode);
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Root;
+
+    impl Caller for Root {
+        fn uid(&self) -> u32 {
+            0
+        }
+
+        fn gid(&self) -> u32 {
+            0
+        }
+
+        fn pid(&self) -> u32 {
+            std::process::id()
+        }
+    }
+
+    struct User(u32);
+
+    impl Caller for User {
+        fn uid(&self) -> u32 {
+            self.0
+        }
+
+        fn gid(&self) -> u32 {
+            self.0
+        }
+
+        fn pid(&self) -> u32 {
+            std::process::id()
+        }
+    }
+
+    fn make_entry(fs: &mut WondFS, parent: u32, name: &str, file_type: inode::InodeFileType) -> u32 {
+        let mut parent_inode = fs.inode_manager.i_get(parent).unwrap();
+        let mut inode = fs.inode_manager.i_alloc().unwrap();
+        let ino = inode.read().unwrap().ino;
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.file_type = file_type;
+        stat.n_link = if file_type == inode::InodeFileType::Directory { 2 } else { 1 };
+        stat.mode = 0o755;
+        inode.write().unwrap().modify_stat(stat);
+        if file_type == inode::InodeFileType::Directory {
+            directory::dir_link(&mut inode, ino, ".".to_string());
+            directory::dir_link(&mut inode, parent, "..".to_string());
+            let mut parent_stat = parent_inode.read().unwrap().get_stat();
+            parent_stat.n_link += 1;
+            parent_inode.write().unwrap().modify_stat(parent_stat);
+        }
+        directory::dir_link(&mut parent_inode, ino, name.to_string());
+        // the kernel keeps the entry it was replied
+        fs.inode_manager.i_lookup(&inode);
+        fs.inode_manager.i_put(inode);
+        fs.inode_manager.i_put(parent_inode);
+        ino
+    }
+
+    fn get_n_link(fs: &WondFS, ino: u32) -> u8 {
+        let inode = fs.inode_manager.i_get(ino).unwrap();
+        let n_link = inode.read().unwrap().n_link;
+        fs.inode_manager.i_put(inode);
+        n_link
+    }
+
+    #[test]
+    fn unlink_and_link() {
+        let mut fs = WondFS::new();
+        fs.mount();
+        let root = FUSE_ROOT_ID as u32;
+        let ino = make_entry(&mut fs, root, "a", inode::InodeFileType::File);
+        let ffree = fs.inode_manager.core_manager.lock().unwrap().statfs().ffree;
+
+        // failed operations give back every reference they took
+        assert_eq!(fs.link_entry(&Root, ino, root, "a".to_string()).err(), Some(libc::EEXIST));
+        assert_eq!(fs.link_entry(&Root, ino, root, "a".repeat(directory::DIRECTORY_NAME_MAX + 1)).err(), Some(libc::ENAMETOOLONG));
+        assert_eq!(fs.remove_entry(&Root, root, "b".to_string(), false), Err(ENOENT));
+        assert_eq!(fs.remove_entry(&Root, root, "a".to_string(), true), Err(libc::ENOTDIR));
+        assert_eq!(fs.inode_manager.get_ref_cnt(root), 0);
+        assert_eq!(fs.inode_manager.get_ref_cnt(ino), 1);
+
+        let attr = fs.link_entry(&Root, ino, root, "b".to_string()).unwrap();
+        assert_eq!(attr.nlink, 2);
+        assert_eq!(fs.remove_entry(&Root, root, "a".to_string(), false), Ok(()));
+        assert_eq!(fs.remove_entry(&Root, root, "b".to_string(), false), Ok(()));
+        assert_eq!(fs.remove_entry(&Root, root, "b".to_string(), false), Err(ENOENT));
+
+        // the unlinked file lives until the kernel forgets it
+        assert!(fs.inode_manager.core_manager.lock().unwrap().is_orphan_inode(ino));
+        fs.inode_manager.i_forget(ino, 2);
+        assert_eq!(fs.inode_manager.get_ref_cnt(ino), 0);
+        let core = fs.inode_manager.core_manager.lock().unwrap();
+        assert!(!core.is_orphan_inode(ino));
+        assert_eq!(core.statfs().ffree, ffree + 1);
+    }
+
+    #[test]
+    fn rmdir() {
+        let mut fs = WondFS::new();
+        fs.mount();
+        let root = FUSE_ROOT_ID as u32;
+        let n_link = get_n_link(&fs, root);
+        let dir = make_entry(&mut fs, root, "d", inode::InodeFileType::Directory);
+        assert_eq!(get_n_link(&fs, root), n_link + 1);
+        let file = make_entry(&mut fs, dir, "f", inode::InodeFileType::File);
+        fs.inode_manager.i_forget(file, 1);
+
+        assert_eq!(fs.remove_entry(&Root, root, "d".to_string(), false), Err(libc::EISDIR));
+        assert_eq!(fs.remove_entry(&Root, root, "d".to_string(), true), Err(libc::ENOTEMPTY));
+        assert_eq!(fs.remove_entry(&Root, dir, "f".to_string(), true), Err(libc::ENOTDIR));
+        assert_eq!(fs.inode_manager.get_ref_cnt(dir), 1);
+        assert_eq!(fs.remove_entry(&Root, dir, "f".to_string(), false), Ok(()));
+        assert_eq!(fs.remove_entry(&Root, root, "d".to_string(), true), Ok(()));
+        assert_eq!(fs.inode_manager.get_ref_cnt(dir), 1);
+        assert_eq!(fs.inode_manager.get_ref_cnt(root), 0);
+        assert_eq!(get_n_link(&fs, root), n_link);
+        fs.inode_manager.i_forget(dir, 1);
+        assert!(!fs.inode_manager.core_manager.lock().unwrap().is_orphan_inode(dir));
+    }
+
+    #[test]
+    fn remove_permission() {
+        let mut fs = WondFS::new();
+        fs.mount();
+        let root = FUSE_ROOT_ID as u32;
+        let dir = make_entry(&mut fs, root, "d", inode::InodeFileType::Directory);
+        let sub = make_entry(&mut fs, dir, "s", inode::InodeFileType::Directory);
+        make_entry(&mut fs, sub, "f", inode::InodeFileType::File);
+
+        // without write access to the parent the type and contents of an entry stay hidden
+        assert_eq!(fs.remove_entry(&User(1000), dir, "s".to_string(), false), Err(libc::EACCES));
+        assert_eq!(fs.remove_entry(&User(1000), dir, "s".to_string(), true), Err(libc::EACCES));
+
+        // the same for entries of a sticky directory owned by somebody else
+        let inode = fs.inode_manager.i_get(dir).unwrap();
+        let mut stat = inode.read().unwrap().get_stat();
+        stat.mode = 0o1777;
+        inode.write().unwrap().modify_stat(stat);
+        fs.inode_manager.i_put(inode);
+        assert_eq!(fs.remove_entry(&User(1000), dir, "s".to_string(), false), Err(libc::EPERM));
+        assert_eq!(fs.remove_entry(&User(1000), dir, "s".to_string(), true), Err(libc::EPERM));
+        assert_eq!(fs.remove_entry(&Root, dir, "s".to_string(), true), Err(libc::ENOTEMPTY));
+        assert_eq!(fs.inode_manager.get_ref_cnt(dir), 1);
+        assert_eq!(fs.inode_manager.get_ref_cnt(sub), 1);
+    }
+
+    #[test]
+    fn truncate() {
+        let mut fs = WondFS::new();
+        fs.mount();
+        let ino = make_entry(&mut fs, FUSE_ROOT_ID as u32, "a", inode::InodeFileType::File);
+        let inode = fs.inode_manager.i_get(ino).unwrap();
+        assert_eq!(fs.truncate_inode(&inode, inode::MAX_FILE_SIZE + 1, &Root), Err(libc::EFBIG));
+        assert_eq!(fs.truncate_inode(&inode, 100, &Root), Ok(()));
+        assert_eq!(inode.read().unwrap().size, 100);
+        fs.inode_manager.i_put(inode);
+    }
+}
@@ -13,6 +13,10 @@ impl From<inode::InodeFileType> for fuser::FileType {
         match kind {
             inode::InodeFileType::File => fuser::FileType::RegularFile,
             inode::InodeFileType::Directory => fuser::FileType::Directory,
+            inode::InodeFileType::Fifo => fuser::FileType::NamedPipe,
+            inode::InodeFileType::Socket => fuser::FileType::Socket,
+            inode::InodeFileType::CharDevice => fuser::FileType::CharDevice,
+            inode::InodeFileType::BlockDevice => fuser::FileType::BlockDevice,
         }
     }
 }
@@ -25,8 +29,8 @@ impl From<inode::InodeFileType> for fuser::FileType {
 pub fn transfer_stat_to_attr(stat: inode::InodeStat) -> FileAttr {
     FileAttr {
         ino: stat.ino as u64,
-        size: stat.size as u64,
-        blocks: ((stat.size + 512 - 1) / 512) as u64,
+        size: stat.size,
+        blocks: (stat.size + 512 - 1) / 512,
         atime: system_time_from_time(stat.last_accessed.0, stat.last_accessed.1),
         mtime: system_time_from_time(stat.last_modified.0, stat.last_modified.1),
         ctime: system_time_from_time(
@@ -38,7 +42,7 @@ pub fn transfer_stat_to_attr(stat: inode::InodeStat) -> FileAttr {
         nlink: stat.n_link as u32,
         uid: stat.uid,
         gid: stat.gid,
-        rdev: 0,
+        rdev: stat.rdev,
         flags: 0,
         blksize: 512,
         padding: 0,
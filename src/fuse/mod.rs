@@ -1,3 +1,4 @@
 pub mod fuse;
 pub mod permission;
-pub mod fuse_helper;
\ No newline at end of file
+pub mod fuse_helper;
+pub mod xattr;
\ No newline at end of file
@@ -1,32 +1,69 @@
 use std::{fs::File, io::{BufReader, BufRead}};
 
+use fuser::Request;
 use crate::inode::inode;
 
 pub const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 pub const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+pub const FILE_HANDLE_APPEND_BIT: u64 = 1 << 61;
+pub const FILE_HANDLE_NOATIME_BIT: u64 = 1 << 60;
 
-pub fn as_file_kind(mut mode: u32) -> inode::InodeFileType {
+// Credentials of the process calling a file system operation
+pub trait Caller {
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+    fn pid(&self) -> u32;
+}
+
+impl Caller for Request<'_> {
+    fn uid(&self) -> u32 {
+        Request::uid(self)
+    }
+
+    fn gid(&self) -> u32 {
+        Request::gid(self)
+    }
+
+    fn pid(&self) -> u32 {
+        Request::pid(self)
+    }
+}
+
+pub fn as_file_kind(mut mode: u32) -> Option<inode::InodeFileType> {
     mode &= libc::S_IFMT as u32;
     if mode == libc::S_IFREG as u32 {
-        return inode::InodeFileType::File;
+        Some(inode::InodeFileType::File)
     } else if mode == libc::S_IFDIR as u32 {
-        return inode::InodeFileType::Directory;
+        Some(inode::InodeFileType::Directory)
+    } else if mode == libc::S_IFIFO as u32 {
+        Some(inode::InodeFileType::Fifo)
+    } else if mode == libc::S_IFSOCK as u32 {
+        Some(inode::InodeFileType::Socket)
+    } else if mode == libc::S_IFCHR as u32 {
+        Some(inode::InodeFileType::CharDevice)
+    } else if mode == libc::S_IFBLK as u32 {
+        Some(inode::InodeFileType::BlockDevice)
     } else {
-        unimplemented!("{}", mode);
+        None
     }
 }
 
 pub fn get_groups(pid: u32) -> Vec<u32> {
     {
         let path = format!("/proc/{}/task/{}/status", pid, pid);
-        let file = File::open(path).unwrap();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return vec![],
+        };
         for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
             if line.starts_with("Groups:") {
-                return line["Groups: ".len()..]
-                    .split(' ')
-                    .filter(|x| !x.trim().is_empty())
-                    .map(|x| x.parse::<u32>().unwrap())
+                return line["Groups:".len()..]
+                    .split_whitespace()
+                    .filter_map(|x| x.parse::<u32>().ok())
                     .collect();
             }
         }
@@ -41,8 +78,28 @@ pub fn creation_gid(parent: &inode::InodeStat, gid: u32) -> u32 {
     gid
 }
 
-pub fn creation_mode(mode: u32) -> u16 {
-    (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as u16
+pub fn creation_mode(mode: u32, umask: u32) -> u16 {
+    (mode & !umask & 0o7777) as u16
+}
+
+pub fn in_group(file_gid: u32, gid: u32, groups: &[u32]) -> bool {
+    gid == file_gid || groups.contains(&file_gid)
+}
+
+pub fn clear_suid_sgid(mode: u16) -> u16 {
+    let mut mode = mode & !(libc::S_ISUID as u16);
+    // without group exec the setgid bit marks mandatory locking, keep it
+    if mode & libc::S_IXGRP as u16 != 0 {
+        mode &= !(libc::S_ISGID as u16);
+    }
+    mode
+}
+
+pub fn check_sticky(dir_uid: u32, dir_mode: u16, file_uid: u32, uid: u32) -> bool {
+    if dir_mode & libc::S_ISVTX as u16 == 0 {
+        return true;
+    }
+    uid == 0 || uid == dir_uid || uid == file_uid
 }
 
 pub fn check_file_handle_read(file_handle: u64) -> bool {
@@ -53,12 +110,21 @@ pub fn check_file_handle_write(file_handle: u64) -> bool {
     (file_handle & FILE_HANDLE_WRITE_BIT) != 0
 }
 
+pub fn check_file_handle_append(file_handle: u64) -> bool {
+    (file_handle & FILE_HANDLE_APPEND_BIT) != 0
+}
+
+pub fn check_file_handle_noatime(file_handle: u64) -> bool {
+    (file_handle & FILE_HANDLE_NOATIME_BIT) != 0
+}
+
 pub fn check_access(
     file_uid: u32,
     file_gid: u32,
     file_mode: u16,
     uid: u32,
     gid: u32,
+    groups: &[u32],
     mut access_mask: i32,
 ) -> bool {
     // F_OK tests for existence of file
@@ -77,10 +143,240 @@ pub fn check_access(
     }
     if uid == file_uid {
         access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
+    } else if in_group(file_gid, gid, groups) {
         access_mask -= access_mask & (file_mode >> 3);
     } else {
         access_mask -= access_mask & file_mode;
     }
     return access_mask == 0;
-}
\ No newline at end of file
+}
+
+pub const ACL_EA_VERSION: u32 = 2;
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+pub const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+pub fn parse_acl(buf: &[u8]) -> Option<Vec<AclEntry>> {
+    if buf.len() < 4 || (buf.len() - 4) % 8 != 0 {
+        return None;
+    }
+    if u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != ACL_EA_VERSION {
+        return None;
+    }
+    let mut acl = vec![];
+    for chunk in buf[4..].chunks(8) {
+        let entry = AclEntry {
+            tag: u16::from_le_bytes([chunk[0], chunk[1]]),
+            perm: u16::from_le_bytes([chunk[2], chunk[3]]),
+            id: u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+        };
+        if entry.perm & !0o7 != 0 {
+            return None;
+        }
+        acl.push(entry);
+    }
+    let count = |tag: u16| acl.iter().filter(|entry| entry.tag == tag).count();
+    if count(ACL_USER_OBJ) != 1 || count(ACL_GROUP_OBJ) != 1 || count(ACL_OTHER) != 1 || count(ACL_MASK) > 1 {
+        return None;
+    }
+    if count(ACL_USER) + count(ACL_GROUP) > 0 && count(ACL_MASK) == 0 {
+        return None;
+    }
+    for entry in acl.iter() {
+        match entry.tag {
+            ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER | ACL_USER | ACL_GROUP => (),
+            _ => return None,
+        }
+        let named = entry.tag == ACL_USER || entry.tag == ACL_GROUP;
+        if named && entry.id == ACL_UNDEFINED_ID {
+            return None;
+        }
+        if named && acl.iter().filter(|other| other.tag == entry.tag && other.id == entry.id).count() > 1 {
+            return None;
+        }
+    }
+    acl.sort_by_key(|entry| (entry.tag, entry.id));
+    Some(acl)
+}
+
+pub fn encode_acl(acl: &[AclEntry]) -> Vec<u8> {
+    let mut acl = acl.to_vec();
+    acl.sort_by_key(|entry| (entry.tag, entry.id));
+    let mut buf = ACL_EA_VERSION.to_le_bytes().to_vec();
+    for entry in acl.iter() {
+        buf.extend_from_slice(&entry.tag.to_le_bytes());
+        buf.extend_from_slice(&entry.perm.to_le_bytes());
+        let id = if entry.tag == ACL_USER || entry.tag == ACL_GROUP { entry.id } else { ACL_UNDEFINED_ID };
+        buf.extend_from_slice(&id.to_le_bytes());
+    }
+    buf
+}
+
+pub fn check_acl_access(
+    acl: &[AclEntry],
+    file_uid: u32,
+    file_gid: u32,
+    file_mode: u16,
+    uid: u32,
+    gid: u32,
+    groups: &[u32],
+    access_mask: i32,
+) -> bool {
+    if access_mask == libc::F_OK || uid == 0 {
+        // root keeps the mode bit semantics
+        return check_access(file_uid, file_gid, file_mode, uid, gid, groups, access_mask);
+    }
+    let granted = |perm: u16| access_mask & !(perm as i32) == 0;
+    let mask = acl.iter().find(|entry| entry.tag == ACL_MASK).map(|entry| entry.perm).unwrap_or(0o7);
+    if uid == file_uid {
+        return acl.iter().any(|entry| entry.tag == ACL_USER_OBJ && granted(entry.perm));
+    }
+    if let Some(entry) = acl.iter().find(|entry| entry.tag == ACL_USER && entry.id == uid) {
+        return granted(entry.perm & mask);
+    }
+    let mut matched = false;
+    for entry in acl.iter() {
+        let member = match entry.tag {
+            ACL_GROUP_OBJ => in_group(file_gid, gid, groups),
+            ACL_GROUP => in_group(entry.id, gid, groups),
+            _ => false,
+        };
+        if member {
+            if granted(entry.perm & mask) {
+                return true;
+            }
+            matched = true;
+        }
+    }
+    if matched {
+        return false;
+    }
+    acl.iter().any(|entry| entry.tag == ACL_OTHER && granted(entry.perm))
+}
+
+pub fn acl_is_equiv_mode(acl: &[AclEntry]) -> bool {
+    acl.iter().all(|entry| entry.tag == ACL_USER_OBJ || entry.tag == ACL_GROUP_OBJ || entry.tag == ACL_OTHER)
+}
+
+pub fn acl_to_mode(acl: &[AclEntry], mode: u16) -> u16 {
+    let perm = |tag: u16| acl.iter().find(|entry| entry.tag == tag).map(|entry| entry.perm);
+    let group = perm(ACL_MASK).or(perm(ACL_GROUP_OBJ)).unwrap_or(0);
+    (mode & !0o777) | perm(ACL_USER_OBJ).unwrap_or(0) << 6 | group << 3 | perm(ACL_OTHER).unwrap_or(0)
+}
+
+pub fn acl_chmod(acl: &mut Vec<AclEntry>, mode: u16) {
+    let has_mask = acl.iter().any(|entry| entry.tag == ACL_MASK);
+    for entry in acl.iter_mut() {
+        match entry.tag {
+            ACL_USER_OBJ => entry.perm = (mode >> 6) & 0o7,
+            ACL_MASK => entry.perm = (mode >> 3) & 0o7,
+            ACL_GROUP_OBJ if !has_mask => entry.perm = (mode >> 3) & 0o7,
+            ACL_OTHER => entry.perm = mode & 0o7,
+            _ => (),
+        }
+    }
+}
+
+pub fn acl_create_masq(acl: &mut Vec<AclEntry>, mode: u16) -> u16 {
+    let has_mask = acl.iter().any(|entry| entry.tag == ACL_MASK);
+    for entry in acl.iter_mut() {
+        match entry.tag {
+            ACL_USER_OBJ => entry.perm &= (mode >> 6) & 0o7,
+            ACL_MASK => entry.perm &= (mode >> 3) & 0o7,
+            ACL_GROUP_OBJ if !has_mask => entry.perm &= (mode >> 3) & 0o7,
+            ACL_OTHER => entry.perm &= mode & 0o7,
+            _ => (),
+        }
+    }
+    acl_to_mode(acl, mode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn basics() {
+        assert!(check_access(1000, 1000, 0o640, 1000, 1000, &[], libc::R_OK | libc::W_OK));
+        assert!(!check_access(1000, 1000, 0o640, 1001, 1001, &[], libc::R_OK));
+        assert!(check_access(1000, 100, 0o640, 1001, 1001, &[100], libc::R_OK));
+        assert!(!check_access(1000, 100, 0o640, 1001, 1001, &[100], libc::W_OK));
+        assert!(check_access(1000, 1000, 0o000, 0, 0, &[], libc::R_OK | libc::W_OK));
+        assert!(!check_access(1000, 1000, 0o644, 0, 0, &[], libc::X_OK));
+        assert!(check_access(1000, 1000, 0o744, 0, 0, &[], libc::X_OK));
+        assert!(check_access(1000, 1000, 0o000, 1001, 1001, &[], libc::F_OK));
+        // owner class wins even if other class is more permissive
+        assert!(!check_access(1000, 1000, 0o077, 1000, 1000, &[], libc::R_OK));
+    }
+
+    #[test]
+    fn mode() {
+        assert_eq!(creation_mode(libc::S_IFREG as u32 | 0o666, 0o022), 0o644);
+        assert_eq!(creation_mode(0o4777, 0o002), 0o4775);
+        assert_eq!(clear_suid_sgid(0o6755), 0o755);
+        assert_eq!(clear_suid_sgid(0o2644), 0o2644);
+        assert!(check_sticky(0, 0o777, 1000, 1001));
+        assert!(!check_sticky(0, 0o1777, 1000, 1001));
+        assert!(check_sticky(0, 0o1777, 1000, 1000));
+        assert!(check_sticky(1001, 0o1777, 1000, 1001));
+        assert!(check_sticky(1002, 0o1777, 1000, 0));
+        let mut parent = crate::inode::inode::Inode::new().get_stat();
+        parent.gid = 50;
+        assert_eq!(creation_gid(&parent, 1000), 1000);
+        parent.mode = 0o2775;
+        assert_eq!(creation_gid(&parent, 1000), 50);
+        assert_eq!(as_file_kind(libc::S_IFIFO as u32 | 0o644), Some(inode::InodeFileType::Fifo));
+        assert_eq!(as_file_kind(0o644), None);
+    }
+
+    #[test]
+    fn acl() {
+        let entry = |tag, perm, id| AclEntry { tag, perm, id };
+        let acl = vec![
+            entry(ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+            entry(ACL_USER_OBJ, 0o6, ACL_UNDEFINED_ID),
+            entry(ACL_USER, 0o7, 1001),
+            entry(ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            entry(ACL_GROUP, 0o6, 200),
+            entry(ACL_MASK, 0o6, ACL_UNDEFINED_ID),
+        ];
+        let buf = encode_acl(&acl);
+        assert_eq!(buf.len(), 4 + 6 * 8);
+        let acl = parse_acl(&buf).unwrap();
+        assert_eq!(acl[0].tag, ACL_USER_OBJ);
+        assert_eq!(encode_acl(&acl), buf);
+        assert!(parse_acl(&buf[0..buf.len() - 8]).is_none());
+        assert!(parse_acl(&buf[0..12]).is_none());
+
+        assert!(check_acl_access(&acl, 1000, 100, 0o660, 1000, 100, &[], libc::R_OK | libc::W_OK));
+        assert!(!check_acl_access(&acl, 1000, 100, 0o660, 1000, 100, &[], libc::X_OK));
+        // named user entry is limited by the mask
+        assert!(check_acl_access(&acl, 1000, 100, 0o660, 1001, 1001, &[], libc::W_OK));
+        assert!(!check_acl_access(&acl, 1000, 100, 0o660, 1001, 1001, &[], libc::X_OK));
+        assert!(check_acl_access(&acl, 1000, 100, 0o660, 1002, 1002, &[200], libc::W_OK));
+        assert!(!check_acl_access(&acl, 1000, 100, 0o660, 1002, 100, &[], libc::W_OK));
+        assert!(check_acl_access(&acl, 1000, 100, 0o660, 1002, 100, &[], libc::R_OK));
+        assert!(!check_acl_access(&acl, 1000, 100, 0o660, 1003, 1003, &[], libc::R_OK));
+
+        assert_eq!(acl_to_mode(&acl, 0o100000), 0o100660);
+        let mut acl_1 = acl.clone();
+        acl_chmod(&mut acl_1, 0o740);
+        assert_eq!(acl_to_mode(&acl_1, 0), 0o740);
+        assert_eq!(acl_1.iter().find(|e| e.tag == ACL_GROUP_OBJ).unwrap().perm, 0o4);
+        let mut acl_2 = acl.clone();
+        assert_eq!(acl_create_masq(&mut acl_2, 0o644), 0o640);
+        assert!(!acl_is_equiv_mode(&acl_2));
+        assert!(acl_is_equiv_mode(&acl_2[0..1]));
+    }
+}
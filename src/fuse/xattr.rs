@@ -0,0 +1,165 @@
+use crate::inode::inode;
+use crate::fuse::permission::*;
+use crate::crypt::crypt;
+
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+pub const POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+pub const POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum XattrNamespace {
+    User,
+    Trusted,
+    Security,
+    System,
+}
+
+pub fn parse_namespace(name: &str) -> Option<XattrNamespace> {
+    if name.starts_with("user.") {
+        Some(XattrNamespace::User)
+    } else if name.starts_with("trusted.") {
+        Some(XattrNamespace::Trusted)
+    } else if name.starts_with("security.") {
+        Some(XattrNamespace::Security)
+    } else if name.starts_with("system.") {
+        Some(XattrNamespace::System)
+    } else {
+        None
+    }
+}
+
+pub fn check_xattr_name(name: &str) -> Result<XattrNamespace, i32> {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(libc::ERANGE);
+    }
+    let namespace = match parse_namespace(name) {
+        Some(namespace) => namespace,
+        None => return Err(libc::EOPNOTSUPP),
+    };
+    if name.ends_with('.') && name.matches('.').count() == 1 {
+        return Err(libc::EINVAL);
+    }
+    // only the POSIX ACLs live in the system namespace
+    if namespace == XattrNamespace::System && name != POSIX_ACL_ACCESS && name != POSIX_ACL_DEFAULT {
+        return Err(libc::EOPNOTSUPP);
+    }
+    Ok(namespace)
+}
+
+pub fn check_xattr_access(
+    namespace: XattrNamespace,
+    name: &str,
+    stat: &inode::InodeStat,
+    acl: Option<&[AclEntry]>,
+    uid: u32,
+    gid: u32,
+    groups: &[u32],
+    write: bool,
+) -> Result<(), i32> {
+    match namespace {
+        XattrNamespace::Trusted => {
+            if uid != 0 {
+                return Err(if write { libc::EPERM } else { libc::ENODATA });
+            }
+        },
+        XattrNamespace::Security => {
+            if write && uid != 0 {
+                return Err(libc::EPERM);
+            }
+        },
+        XattrNamespace::System => {
+            if name == POSIX_ACL_DEFAULT && stat.file_type != inode::InodeFileType::Directory {
+                return Err(if write { libc::EACCES } else { libc::ENODATA });
+            }
+            if write && uid != 0 && uid != stat.uid {
+                return Err(libc::EPERM);
+            }
+        },
+        XattrNamespace::User => {
+            if stat.file_type != inode::InodeFileType::File && stat.file_type != inode::InodeFileType::Directory {
+                return Err(if write { libc::EPERM } else { libc::ENODATA });
+            }
+            if write
+                && stat.file_type == inode::InodeFileType::Directory
+                && !check_sticky(stat.uid, stat.mode, stat.uid, uid)
+            {
+                return Err(libc::EPERM);
+            }
+            let access_mask = if write { libc::W_OK } else { libc::R_OK };
+            let allowed = match acl {
+                Some(acl) => check_acl_access(acl, stat.uid, stat.gid, stat.mode, uid, gid, groups, access_mask),
+                None => check_access(stat.uid, stat.gid, stat.mode, uid, gid, groups, access_mask),
+            };
+            if !allowed {
+                return Err(libc::EACCES);
+            }
+        },
+    }
+    Ok(())
+}
+
+pub fn xattr_visible(name: &str, uid: u32) -> bool {
+    // the encryption context is internal, check_xattr_name rejects it as well
+    name != crypt::CRYPT_XATTR && (parse_namespace(name) != Some(XattrNamespace::Trusted) || uid == 0)
+}
+
+pub fn encode_xattr_names(names: &[String]) -> Vec<u8> {
+    let mut buf = vec![];
+    for name in names.iter() {
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn basics() {
+        assert_eq!(check_xattr_name("user.mime"), Ok(XattrNamespace::User));
+        assert_eq!(check_xattr_name("trusted.x"), Ok(XattrNamespace::Trusted));
+        assert_eq!(check_xattr_name(POSIX_ACL_ACCESS), Ok(XattrNamespace::System));
+        assert_eq!(check_xattr_name("system.foo"), Err(libc::EOPNOTSUPP));
+        assert_eq!(check_xattr_name("other.x"), Err(libc::EOPNOTSUPP));
+        assert_eq!(check_xattr_name("user."), Err(libc::EINVAL));
+        assert_eq!(check_xattr_name(""), Err(libc::ERANGE));
+        assert_eq!(check_xattr_name(&"u".repeat(300)), Err(libc::ERANGE));
+        assert_eq!(encode_xattr_names(&["user.a".to_string(), "user.bc".to_string()]), b"user.a\0user.bc\0".to_vec());
+        assert!(!xattr_visible("trusted.x", 1000));
+        assert!(xattr_visible("trusted.x", 0));
+        assert!(!xattr_visible(crypt::CRYPT_XATTR, 0));
+    }
+
+    #[test]
+    fn access() {
+        let mut stat = inode::Inode::new().get_stat();
+        stat.uid = 1000;
+        stat.gid = 1000;
+        stat.mode = 0o644;
+        let user = XattrNamespace::User;
+        assert_eq!(check_xattr_access(user, "user.a", &stat, None, 1000, 1000, &[], true), Ok(()));
+        assert_eq!(check_xattr_access(user, "user.a", &stat, None, 1001, 1001, &[], false), Ok(()));
+        assert_eq!(check_xattr_access(user, "user.a", &stat, None, 1001, 1001, &[], true), Err(libc::EACCES));
+        assert_eq!(check_xattr_access(XattrNamespace::Trusted, "trusted.a", &stat, None, 1000, 1000, &[], false), Err(libc::ENODATA));
+        assert_eq!(check_xattr_access(XattrNamespace::Security, "security.a", &stat, None, 1000, 1000, &[], true), Err(libc::EPERM));
+        assert_eq!(check_xattr_access(XattrNamespace::System, POSIX_ACL_ACCESS, &stat, None, 1000, 1000, &[], true), Ok(()));
+        assert_eq!(check_xattr_access(XattrNamespace::System, POSIX_ACL_DEFAULT, &stat, None, 1000, 1000, &[], true), Err(libc::EACCES));
+        // a named user entry of the ACL grants what the mode bits don't
+        let entry = |tag, perm, id| AclEntry { tag, perm, id };
+        let acl = vec![
+            entry(ACL_USER_OBJ, 0o6, ACL_UNDEFINED_ID),
+            entry(ACL_USER, 0o6, 1001),
+            entry(ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            entry(ACL_MASK, 0o6, ACL_UNDEFINED_ID),
+            entry(ACL_OTHER, 0o4, ACL_UNDEFINED_ID),
+        ];
+        assert_eq!(check_xattr_access(user, "user.a", &stat, Some(&acl), 1001, 1001, &[], true), Ok(()));
+        assert_eq!(check_xattr_access(user, "user.a", &stat, Some(&acl), 1002, 1002, &[], true), Err(libc::EACCES));
+        stat.file_type = inode::InodeFileType::Fifo;
+        assert_eq!(check_xattr_access(user, "user.a", &stat, None, 1000, 1000, &[], true), Err(libc::EPERM));
+    }
+}
@@ -164,6 +164,18 @@ impl BlockInfo {
         self.last_erase_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok().unwrap().as_secs() as u32;
     }
 
+    pub fn get_clean_num(&self) -> u32 {
+        self.clean_num
+    }
+
+    pub fn get_dirty_num(&self) -> u32 {
+        self.dirty_num
+    }
+
+    pub fn get_used_num(&self) -> u32 {
+        self.used_num
+    }
+
     pub fn get_utilize_ratio(&self) -> f32 {
         (self.clean_num + self.used_num) as f32 / self.dirty_num as f32
     }
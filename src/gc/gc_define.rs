@@ -1,5 +1,6 @@
 pub const HOTAGEKEY: u32 = 60 * 60 * 24;       // 1day
 pub const COLDAGEKEY: u32 = 60 * 60 * 24 * 14; // 14day
+pub const GCRESERVEDPAGENUM: u32 = 128;        // 前台GC搬移所需的预留页
 
 // GC Strategy Type
 pub enum GCStrategy {
@@ -2,6 +2,7 @@
 // GC Manager
 //
 
+use std::collections::HashMap;
 use crate::gc::gc_define::*;
 use crate::gc::gc_event;
 use crate::gc::block_table;
@@ -106,6 +107,28 @@ impl GCManager {
         self.block_table.erase_block(block_no);
     }
 
+    /// Get page number of block table
+    /// params:
+    /// ()
+    /// return:
+    /// total page number
+    pub fn get_page_num(&self) -> u32 {
+        self.block_table.size * 128
+    }
+
+    /// Get free page number, dirty pages can be reclaimed by GC
+    /// params:
+    /// ()
+    /// return:
+    /// clean and dirty page number
+    pub fn get_free_page_num(&self) -> u32 {
+        let mut num = 0;
+        for block in self.block_table.table.iter() {
+            num += block.get_clean_num() + block.get_dirty_num();
+        }
+        num
+    }
+
 
 }
 
@@ -121,14 +144,9 @@ impl GCManager {
             let status = self.block_table.get_page(address);
             match status {
                 PageUsedStatus::Busy(ino) => {
+                    // shared pages make one extent span several inodes, so runs are kept whole
                     if last_entry.is_some() {
-                        if last_entry.unwrap().0 == ino {
-                            size += 1;
-                        } else {
-                            last_entry.as_mut().unwrap().1 = size;
-                            used_entries.push(last_entry.unwrap());
-                            last_entry = Some((ino, 0, address, 0));
-                        }
+                        size += 1;
                     } else {
                         last_entry = Some((ino, 0, address, 0));
                         size = 1;
@@ -148,9 +166,12 @@ impl GCManager {
             last_entry.as_mut().unwrap().1 = size;
             used_entries.push(last_entry.unwrap());
         }
+        // pages are only marked busy when the moves run, so destinations given out in this group are counted here
+        let mut taken = HashMap::new();
         for entry in used_entries.iter_mut() {
-            let d_address = self.find_next_pos_to_write_except(entry.1, block_no);
-            entry.3 = d_address.unwrap();
+            let d_address = self.find_next_pos_to_write_except(entry.1, block_no, &taken).unwrap();
+            *taken.entry(d_address / 128).or_insert(0) += entry.1;
+            entry.3 = d_address;
         }
         let mut gc_group = gc_event::GCEventGroup::new();
         let mut index = 0;
@@ -205,10 +226,11 @@ impl GCManager {
         }
     }
 
-    fn find_next_pos_to_write_except(&self, size: u32, block_no: u32) -> Option<u32> {
+    fn find_next_pos_to_write_except(&self, size: u32, block_no: u32, taken: &HashMap<u32, u32>) -> Option<u32> {
         for block in self.block_table.table.iter() {
-            if block.reserved_size >= size && block.block_no != block_no {
-                let offset = block.reserved_offset;
+            let used = taken.get(&block.block_no).copied().unwrap_or(0);
+            if block.reserved_size >= used + size && block.block_no != block_no {
+                let offset = block.reserved_offset + used;
                 return Some((block.block_no * 128) as u32 + offset);
             }
         }
@@ -256,8 +278,32 @@ mod test {
         assert_eq!(manager.get_page(0), PageUsedStatus::Busy(0));
         assert_eq!(manager.find_next_pos_to_write(128), Some(128));
 
+        assert_eq!(manager.get_page_num(), 20 * 128);
+        assert_eq!(manager.get_free_page_num(), 20 * 128 - 5);
+        manager.set_page(4, PageUsedStatus::Dirty);
+        assert_eq!(manager.get_free_page_num(), 20 * 128 - 4);
+        manager.set_page(4, PageUsedStatus::Busy(0));
+
         let event = manager.new_gc_event(GCStrategy::Forward);
         assert_eq!(event.events[0], gc_event::GCEvent::Move(gc_event::MoveGCEvent{ index: 0, ino: 0, size: 5, o_address: 0, d_address: 128 }));
         assert_eq!(event.events[1], gc_event::GCEvent::Erase(gc_event::EraseGCEvent{ index: 1, block_no: 0 }));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn interleaved() {
+        let mut manager = GCManager::new();
+        // pages of two inodes, runs are split by dirty pages only
+        let pages = [Some(1), Some(2), None, Some(2), Some(2), None, Some(1), Some(2), Some(1)];
+        for (address, ino) in pages.iter().enumerate() {
+            manager.set_page(address as u32, PageUsedStatus::Busy(ino.unwrap_or(3)));
+            if ino.is_none() {
+                manager.set_page(address as u32, PageUsedStatus::Dirty);
+            }
+        }
+        let event = manager.new_gc_event(GCStrategy::Forward);
+        assert_eq!(event.events[0], gc_event::GCEvent::Move(gc_event::MoveGCEvent{ index: 0, ino: 1, size: 2, o_address: 0, d_address: 128 }));
+        assert_eq!(event.events[1], gc_event::GCEvent::Move(gc_event::MoveGCEvent{ index: 1, ino: 2, size: 2, o_address: 3, d_address: 130 }));
+        assert_eq!(event.events[2], gc_event::GCEvent::Move(gc_event::MoveGCEvent{ index: 2, ino: 1, size: 3, o_address: 6, d_address: 132 }));
+        assert_eq!(event.events[3], gc_event::GCEvent::Erase(gc_event::EraseGCEvent{ index: 3, block_no: 0 }));
+    }
+}
@@ -2,29 +2,61 @@
 // Inode Layer
 //
 
-use std::sync::Mutex;
 use std::cmp::{max, min};
 use crate::inode::inode_event;
 use crate::inode::inode_manager;
+use crate::compress::compress::{self, CompressPolicy, CompressType};
+use crate::crypt::crypt;
 
 // Inode File Type
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum InodeFileType {
-    File,       // 普通文件
-    Directory,  // 目录文件
+    File,        // 普通文件
+    Directory,   // 目录文件
+    Fifo,        // 管道文件
+    Socket,      // 套接字文件
+    CharDevice,  // 字符设备文件
+    BlockDevice, // 块设备文件
 }
 
+// Access time update policy
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AtimePolicy {
+    NoAtime,     // never update on access
+    Relatime,    // update when behind mtime or ctime, or older than RELATIME_INTERVAL
+    StrictAtime, // update on every access
+}
+
+// Seconds an access time may lag behind under relatime
+pub const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
+// Page size of file data
+pub const PAGE_SIZE: u64 = 4096;
+
+// Max file size, entry page counts and addresses are u32
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64 * PAGE_SIZE;
+
+// Max page count of an entry, an entry never crosses a block
+pub const MAX_ENTRY_PAGES: u64 = 128;
+
+// Max page count of an entry written with compression or encryption on, a sealed entry is rewritten whole
+pub const COMPRESS_ENTRY_PAGES: u64 = 16;
+
+// Max data size kept inline in the inode record instead of in pages
+pub const INLINE_DATA_MAX: u64 = 2048;
+
 // Inode Stat
 #[derive(Copy, Clone)]
 pub struct InodeStat {
     pub file_type: InodeFileType,
     pub ino: u32,
-    pub size: u32,
+    pub size: u64,
     pub ref_cnt: u8,
     pub n_link: u8,
     pub mode: u16,
     pub uid: u32,
     pub gid: u32,
+    pub rdev: u32,
     pub last_accessed: (i64, u32),
     pub last_modified: (i64, u32),
     pub last_metadata_changed: (i64, u32),
@@ -34,28 +66,43 @@ pub struct InodeStat {
 #[derive(Copy, Clone)]
 pub struct InodeEntry {
     pub valid: bool,
-    pub offset: u32,
-    pub len: u32,      // 以Byte为单位
+    pub offset: u64,
+    pub len: u64,      // 以Byte为单位
     pub size: u32,     // 以Page为单位
     pub address: u32,
+    pub compress_type: CompressType,
+    pub compressed_len: u64,
+    pub encrypted: bool,
+    pub crypt_nonce: [u8; 12],
+    pub crypt_tag: [u8; 16],
+}
+
+impl InodeEntry {
+    // compressed and encrypted entries are only read and written whole
+    pub fn is_sealed(&self) -> bool {
+        self.compress_type != CompressType::None || self.encrypted
+    }
 }
 
 // Inode Layer Main Structure
 pub struct Inode {
     pub file_type: InodeFileType,
     pub ino: u32,
-    pub size: u32,
+    pub size: u64,
     pub ref_cnt: u8,
     pub n_link: u8,
     pub mode: u16,
     pub uid: u32,
     pub gid: u32,
+    pub rdev: u32,
     pub last_accessed: (i64, u32),
     pub last_modified: (i64, u32),
     pub last_metadata_changed: (i64, u32),
-    pub lock: Mutex<bool>,
     pub data: Vec<InodeEntry>,
+    pub inline_data: Vec<u8>,
+    pub xattr: Vec<(String, Vec<u8>)>,
     pub core: Option<inode_manager::CoreLink>,
+    pub dirty: bool, // changed in memory only, written back by sync
 }
 
 // Inode Layer Simple Interface Function
@@ -67,11 +114,14 @@ impl Inode {
             size: 0,
             uid: 0,
             gid: 0,
+            rdev: 0,
             n_link: 0,
             data: vec![],
+            inline_data: vec![],
+            xattr: vec![],
             ref_cnt: 0,
-            lock: Mutex::new(false),
             core: None,
+            dirty: false,
             mode: 0,
             last_accessed: (0, 0),
             last_modified: (0, 0),
@@ -86,11 +136,14 @@ impl Inode {
             size: self.size,
             uid: self.uid,
             gid: self.gid,
+            rdev: self.rdev,
             n_link: self.n_link,
             data: self.data.clone(),
+            inline_data: self.inline_data.clone(),
+            xattr: self.xattr.clone(),
             ref_cnt: self.ref_cnt,
-            lock: Mutex::new(false),
             core: None,
+            dirty: false,
             mode: self.mode,
             last_accessed: self.last_accessed,
             last_modified: self.last_modified,
@@ -99,10 +152,10 @@ impl Inode {
     }
 
     pub fn debug(&self) {
-        // println!("Inode::Debug:{}", self.ino);
-        // for (index, entry) in self.data.iter().enumerate() {
-        //     println!("{} offset: {} len: {} size: {} address: {} valid: {}", index, entry.offset, entry.len, entry.size, entry.address, entry.valid);
-        // }
+        trace!("Inode: debug ino: {}", self.ino);
+        for (index, entry) in self.data.iter().enumerate() {
+            trace!("Inode: debug {} offset: {} len: {} size: {} address: {} valid: {}", index, entry.offset, entry.len, entry.size, entry.address, entry.valid);
+        }
     }
 }
 
@@ -113,7 +166,7 @@ impl Inode {
     /// buf - data buffer
     /// return:
     /// read data byte count
-    pub fn read_all(&mut self, buf: &mut Vec<u8>) -> i32 {
+    pub fn read_all(&mut self, buf: &mut Vec<u8>) -> i64 {
         self.read(0, self.size, buf)
     }
 
@@ -123,143 +176,109 @@ impl Inode {
     /// len - data len
     /// buf - data buffer
     /// return:
-    /// read data byte count
-    pub fn read(&mut self, offset: u32, len: u32, buf: &mut Vec<u8>) -> i32 {
+    /// read data byte count, -1 past the end or if stored data can't be decrypted or decompressed
+    pub fn read(&mut self, offset: u64, len: u64, buf: &mut Vec<u8>) -> i64 {
         buf.clear();
-        let mut len = len;
-        let mut count = 0;
-        let mut flag = false;
         if offset >= self.size {
             return -1;
         }
-        if offset + len > self.size {
-            len = self.size - offset;
+        let end = min(offset + len, self.size);
+        // ranges not covered by any entry are holes and read as zeros
+        buf.resize((end - offset) as usize, 0);
+        let inline_end = min(self.inline_data.len() as u64, end);
+        if offset < inline_end {
+            buf[..(inline_end - offset) as usize].copy_from_slice(&self.inline_data[offset as usize..inline_end as usize]);
         }
         for entry in self.data.clone().iter() {
-            if entry.offset + entry.len < offset {
+            let start = max(entry.offset, offset);
+            let stop = min(entry.offset + entry.len, end);
+            if start >= stop {
                 continue;
             }
-            let cur_count;
-            if !flag {
-                flag = true;
-                cur_count = min(len, entry.offset + entry.len - offset);
-            } else {
-                cur_count = min(len, entry.len);
-            }
-            let data = self.read_entry(&entry, 0, cur_count);
-            for byte in data.into_iter() {
-                buf.push(byte);
-            }
-            len -= cur_count;
-            count += cur_count as i32;
-            if len == 0 {
-                break;
+            let data = match self.read_entry(entry, start - entry.offset, stop - entry.offset) {
+                Some(data) => data,
+                None => {
+                    buf.clear();
+                    return -1;
+                },
+            };
+            buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(&data);
+        }
+        (end - offset) as i64
+    }
+
+    /// Find next data position in inode
+    /// params:
+    /// offset - search start offset
+    /// return:
+    /// start of the next data range, none if no data follows
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        if offset < self.inline_data.len() as u64 {
+            return Some(offset);
+        }
+        self.data.iter()
+            .filter(|entry| entry.offset + entry.len > offset)
+            .map(|entry| max(entry.offset, offset))
+            .min()
+    }
+
+    /// Find next hole position in inode
+    /// params:
+    /// offset - search start offset
+    /// return:
+    /// start of the next hole, the end of file counts as a hole
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let mut entries = self.data.clone();
+        entries.sort_by_key(|entry| entry.offset);
+        let mut pos = max(offset, self.inline_data.len() as u64);
+        for entry in entries.iter() {
+            if entry.offset <= pos && entry.offset + entry.len > pos {
+                pos = entry.offset + entry.len;
             }
         }
-        count
+        Some(min(pos, self.size))
     }
 
-    /// Write data in inode
+    /// Write data in inode, only the pages covered by data are replaced
     /// params:
     /// offset - data offset
     /// len - data len
     /// buf - data buffer
     /// return:
     /// write if success
-    pub fn write(&mut self, offset: u32, len: u32, buf: &Vec<u8>) -> bool {
-        let mut event_group = inode_event::InodeEventGroup::new();
-        event_group.inode = self.copy_inode();
-        let mut index = 0;
-        let mut flag = false;
-        let new_entry = InodeEntry {
-            offset,
-            len,
-            valid: false,
-            size: len,
-            address: 0,
-        };
-        let mut second_entry = None;
-        let mut second_o_entry = None;
-        let mut second_index = 0;
-        if offset > self.size {
+    pub fn write(&mut self, offset: u64, len: u64, buf: &Vec<u8>) -> bool {
+        if offset + len > MAX_FILE_SIZE || self.is_crypt_locked() {
             return false;
         }
-        for entry in self.data.iter() {
-            if entry.offset + entry.len <= new_entry.offset {
-                index += 1;
-                continue
-            } else if entry.offset >= new_entry.offset + new_entry.len {
-                continue
-            } else {
-                let valid_prev = max(0, new_entry.offset as i32 - entry.offset as i32) as u32;
-                let valid_suffix = max(0, entry.offset as i32 + entry.len as i32 - new_entry.offset as i32 - new_entry.len as i32) as u32;
-                if valid_prev == 0 {
-                    let event = inode_event::DeleteContentInodeEvent {
-                        index,
-                        size: entry.size,
-                        v_address: entry.address,
-                    };
-                    event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
-                } else {
-                    let event = inode_event::TruncateContentInodeEvent {
-                        index,
-                        offset: entry.offset,
-                        len: valid_prev,
-                        size: valid_prev / 4096 + 1,
-                        o_size: entry.size,
-                        v_address: entry.address,
-                    };
-                    event_group.events.push(inode_event::InodeEvent::TruncateContent(event));
-                }
-                index += 1;
-                if !flag {
-                    let event = inode_event::AddContentInodeEvent {
-                        index,
-                        offset,
-                        len,
-                        size: len / 4096 + 1,
-                        content: buf.clone(),
-                    };
-                    event_group.events.push(inode_event::InodeEvent::AddContent(event));
-                    index += 1;
-                    flag = true;
-                }
-                if valid_suffix > 0 {
-                    second_o_entry= Some(entry.clone());
-                    second_entry = Some(InodeEntry {
-                        offset: entry.offset + entry.len - valid_suffix,
-                        len: valid_suffix,
-                        valid: false,
-                        size: valid_suffix / 4096 + 1,
-                        address: 0,
-                    });
-                    second_index = index;
-                }
-            }
+        if len == 0 {
+            return true;
         }
-        if !flag {
-            let event = inode_event::AddContentInodeEvent {
-                index: self.data.len() as u32,
-                offset: new_entry.offset,
-                len: new_entry.len,
-                size: len / 4096 + 1,
-                content: buf.clone(),
-            };
-            event_group.events.push(inode_event::InodeEvent::AddContent(event));
+        if self.inline_allowed() && offset + len <= INLINE_DATA_MAX {
+            let mut data = self.inline_data.clone();
+            data.resize(max(data.len(), (offset + len) as usize), 0);
+            let mut content = buf.iter().take(len as usize).cloned().collect::<Vec<u8>>();
+            content.resize(len as usize, 0);
+            data[offset as usize..(offset + len) as usize].copy_from_slice(&content);
+            return self.update_inline_data(data, None);
         }
-        if second_entry.is_some() {
-            let second_entry = second_entry.unwrap();
-            let data = self.read_entry(&second_o_entry.unwrap(), second_entry.offset - second_o_entry.unwrap().offset, second_entry.offset + second_entry.len - second_o_entry.unwrap().offset);
-            let event = inode_event::AddContentInodeEvent {
-                index: second_index,
-                offset: second_entry.offset,
-                len: second_entry.len,
-                size: second_entry.size,
-                content: data,
-            };
-            event_group.events.push(inode_event::InodeEvent::AddContent(event));
-        }
-        let inode = self.core.as_mut().unwrap().borrow_mut().dispose_event_group(event_group).unwrap();
+        self.migrate_inline_data();
+        let first = offset / PAGE_SIZE;
+        let last = (offset + len - 1) / PAGE_SIZE;
+        // read-modify-write of the two edge pages at most
+        let mut content = self.read_range(first * PAGE_SIZE, offset);
+        content.extend(buf.iter().take(len as usize));
+        content.resize((offset + len - first * PAGE_SIZE) as usize, 0);
+        content.append(&mut self.read_range(offset + len, min((last + 1) * PAGE_SIZE, self.size)));
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        self.replace_pages(&mut event_group, first, last, self.content_events(first * PAGE_SIZE, &content));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
         self.update_by_another_inode(inode);
         true
     }
@@ -271,103 +290,34 @@ impl Inode {
     /// buf - data buffer
     /// return:
     /// insert if success
-    pub fn insert(&mut self, offset: u32, len: u32, buf: &Vec<u8>) -> bool {
-        let mut event_group = inode_event::InodeEventGroup::new();
-        event_group.inode = self.copy_inode();
-        let mut index = 0;
-        let mut flag = false;
-        let new_entry = InodeEntry {
-            offset,
-            len,
-            valid: false,
-            size: len,
-            address: 0,
-        };
-        let mut second_entry = None;
-        let mut second_o_entry = None;
-        let mut second_index = 0;
-        if offset > self.size {
+    pub fn insert(&mut self, offset: u64, len: u64, buf: &Vec<u8>) -> bool {
+        if offset > self.size || self.size + len > MAX_FILE_SIZE || self.is_crypt_locked() {
             return false;
         }
-        for entry in self.data.iter_mut() {
-            if flag {
-                let event = inode_event::ChangeContentInodeEvent {
-                    index: index,
-                    offset: entry.offset + len,
-                    v_address: entry.address,
-                };
-                event_group.events.push(inode_event::InodeEvent::ChangeContent(event));
-            } else {
-                if new_entry.offset < entry.offset + entry.len {
-                    flag = true;
-                    let valid_prev = max(0, new_entry.offset - entry.offset);
-                    let valid_suffix = max(0, entry.offset as i32 + entry.len as i32 - new_entry.offset as i32) as u32;
-                    if valid_prev == 0 {
-                        let event = inode_event::DeleteContentInodeEvent {
-                            index,
-                            size: entry.size,
-                            v_address: entry.address,
-                        };
-                        event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
-                    } else {
-                        let event = inode_event::TruncateContentInodeEvent {
-                            index,
-                            offset: entry.offset,
-                            len: valid_prev,
-                            size: valid_prev / 4096 + 1,
-                            o_size: entry.size,
-                            v_address: entry.address,
-                        };
-                        event_group.events.push(inode_event::InodeEvent::TruncateContent(event));
-                    }
-                    index  += 1;
-                    let event = inode_event::AddContentInodeEvent {
-                        index,
-                        offset,
-                        len,
-                        size: len / 4096 + 1,
-                        content: buf.clone(),
-                    };
-                    event_group.events.push(inode_event::InodeEvent::AddContent(event));
-                    index += 1;
-                    if valid_suffix > 0 {
-                        second_o_entry= Some(entry.clone());
-                        second_entry = Some(InodeEntry {
-                            offset: entry.offset + entry.len + len - valid_suffix,
-                            len: valid_suffix,
-                            valid: false,
-                            size: valid_suffix / 4096 + 1,
-                            address: 0,
-                        });
-                        second_index = index;
-                    }
-                }
-            }
-            index += 1;
-        }
-        if !flag {
-            let event = inode_event::AddContentInodeEvent {
-                index: self.data.len() as u32,
-                offset: new_entry.offset,
-                len: new_entry.len,
-                size: len / 4096 + 1,
-                content: buf.clone(),
-            };
-            event_group.events.push(inode_event::InodeEvent::AddContent(event));
+        if len == 0 {
+            return true;
         }
-        if second_entry.is_some() {
-            let second_entry = second_entry.unwrap();
-            let data = self.read_entry(&second_o_entry.unwrap(), second_entry.offset - len - second_o_entry.unwrap().offset, second_entry.offset + second_entry.len - len - second_o_entry.unwrap().offset);
-            let event = inode_event::AddContentInodeEvent {
-                index: second_index,
-                offset: second_entry.offset,
-                len: second_entry.len,
-                size: second_entry.size,
-                content: data,
-            };
-            event_group.events.push(inode_event::InodeEvent::AddContent(event));
+        if self.inline_allowed() && self.size + len <= INLINE_DATA_MAX {
+            let mut data = self.read_range(0, offset);
+            data.extend(buf.iter().take(len as usize));
+            data.resize((offset + len) as usize, 0);
+            data.append(&mut self.read_range(offset, self.size));
+            return self.update_inline_data(data, Some(self.size + len));
         }
-        let inode = self.core.as_mut().unwrap().borrow_mut().dispose_event_group(event_group).unwrap();
+        self.migrate_inline_data();
+        let first = offset / PAGE_SIZE;
+        let mut content = self.read_range(first * PAGE_SIZE, offset);
+        content.extend(buf.iter().take(len as usize));
+        content.resize((offset + len - first * PAGE_SIZE) as usize, 0);
+        content.append(&mut self.read_range(offset, self.size));
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        self.replace_pages(&mut event_group, first, u64::MAX, self.content_events(first * PAGE_SIZE, &content));
+        let event = inode_event::SetSizeInodeEvent {
+            size: self.size + len,
+        };
+        event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
         self.update_by_another_inode(inode);
         true
     }
@@ -378,86 +328,216 @@ impl Inode {
     /// len - truncate data len
     /// return:
     /// truncate if success
-    pub fn truncate(&mut self, offset: u32, len: u32) -> bool {
+    pub fn truncate(&mut self, offset: u64, len: u64) -> bool {
+        let end = min(offset + len, self.size);
+        if offset >= end {
+            return true;
+        }
+        if self.is_crypt_locked() {
+            return false;
+        }
+        if end == self.size {
+            return self.truncate_to_end(offset);
+        }
+        if self.data.is_empty() {
+            let mut data = self.inline_data.clone();
+            let inline_end = min(end as usize, data.len());
+            if (offset as usize) < inline_end {
+                data.drain(offset as usize..inline_end);
+            }
+            return self.update_inline_data(data, Some(self.size - (end - offset)));
+        }
+        let first = offset / PAGE_SIZE;
+        let mut content = self.read_range(first * PAGE_SIZE, offset);
+        content.append(&mut self.read_range(end, self.size));
         let mut event_group = inode_event::InodeEventGroup::new();
         event_group.inode = self.copy_inode();
-        let mut new_entry = None;
-        let mut new_o_entry = None;
-        let mut new_index = 0;
-        let mut index = 0;
-        for entry in self.data.iter_mut() {
-            if entry.offset + entry.len <= offset {
-                index += 1;
-                continue
-            } else if entry.offset >= offset + len {
-                let event = inode_event::ChangeContentInodeEvent {
-                    index,
-                    offset: entry.offset - len,
+        self.replace_pages(&mut event_group, first, u64::MAX, self.content_events(first * PAGE_SIZE, &content));
+        let event = inode_event::SetSizeInodeEvent {
+            size: self.size - (end - offset),
+        };
+        event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
+    /// Truncate data to end in inode
+    /// params:
+    /// offset - data offset
+    /// return:
+    /// truncate if success
+    pub fn truncate_to_end(&mut self, offset: u64) -> bool {
+        if offset >= self.size {
+            return true;
+        }
+        if self.is_crypt_locked() {
+            return false;
+        }
+        if self.data.is_empty() {
+            let mut data = self.inline_data.clone();
+            data.truncate(offset as usize);
+            return self.update_inline_data(data, Some(offset));
+        }
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        for (index, entry) in self.data.clone().iter().enumerate() {
+            if entry.offset < offset && entry.offset + entry.len > offset && entry.is_sealed() {
+                // the kept part of a sealed entry is written again, after all other events
+                let event = inode_event::DeleteContentInodeEvent {
+                    index: index as u32,
+                    size: entry.size,
                     v_address: entry.address,
                 };
-                event_group.events.push(inode_event::InodeEvent::ChangeContent(event));
-                index += 1;
-                continue
-            } else {
-                let valid_prev = max(0, offset as i32 - entry.offset as i32) as u32;
-                let valid_suffix = max(0, entry.offset as i32 + entry.len as i32 - offset as i32 - len as i32) as u32;
-                if valid_prev == 0 {
-                    let event = inode_event::DeleteContentInodeEvent {
-                        index,
-                        size: entry.size,
-                        v_address: entry.address,
-                    };
-                    event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
-                } else {
-                    let event = inode_event::TruncateContentInodeEvent {
-                        index: (index as u32),
-                        offset: entry.offset,
-                        len: valid_prev,
-                        size: valid_prev / 4096 + 1,
-                        o_size: entry.size,
-                        v_address: entry.address,
-                    };
-                    event_group.events.push(inode_event::InodeEvent::TruncateContent(event));
-                }
-                if valid_suffix > 0 {
-                    new_o_entry = Some(entry.clone());
-                    new_entry = Some(InodeEntry {
-                        offset: entry.offset + entry.len - valid_suffix - len,
-                        len: valid_suffix,
-                        valid: false,
-                        size: valid_suffix / 4096 + 1,
-                        address: 0,
-                    });
-                    new_index = index;
-                    index += 1;
-                }
-                index += 1;
+                event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
+                let prefix = self.read_range(entry.offset, offset);
+                let events = self.content_events(entry.offset, &prefix);
+                Inode::push_events(&mut event_group, self.data.len() as u32, events);
+            } else if entry.offset >= offset {
+                let event = inode_event::DeleteContentInodeEvent {
+                    index: index as u32,
+                    size: entry.size,
+                    v_address: entry.address,
+                };
+                event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
+            } else if entry.offset + entry.len > offset {
+                // bytes after len in the last kept page are never read back
+                let len = offset - entry.offset;
+                let event = inode_event::TruncateContentInodeEvent {
+                    index: index as u32,
+                    offset: entry.offset,
+                    len,
+                    size: len.div_ceil(PAGE_SIZE) as u32,
+                    o_size: entry.size,
+                    v_address: entry.address,
+                };
+                event_group.events.push(inode_event::InodeEvent::TruncateContent(event));
             }
         }
-        if new_entry.is_some() {
-            let new_entry = new_entry.unwrap();
-            let data = self.read_entry(&new_o_entry.unwrap(), new_entry.offset + len - new_o_entry.unwrap().offset, new_entry.offset + new_entry.len + len - new_o_entry.unwrap().offset);
-            let event = inode_event::AddContentInodeEvent {
-                index: new_index,
-                offset: new_entry.offset,
-                len: new_entry.len,
-                size: new_entry.size,
-                content: data,
-            };
-            event_group.events.push(inode_event::InodeEvent::AddContent(event));
+        let event = inode_event::SetSizeInodeEvent {
+            size: offset,
+        };
+        event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
+    /// Set file size, growing leaves a hole at the end
+    /// params:
+    /// size - new file size
+    /// return:
+    /// set if success
+    pub fn set_size(&mut self, size: u64) -> bool {
+        if size < self.size {
+            return self.truncate_to_end(size);
         }
-        let inode = self.core.as_mut().unwrap().borrow_mut().dispose_event_group(event_group).unwrap();
+        if size == self.size {
+            return true;
+        }
+        if size > MAX_FILE_SIZE {
+            return false;
+        }
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let event = inode_event::SetSizeInodeEvent {
+            size,
+        };
+        event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
         self.update_by_another_inode(inode);
         true
     }
 
-    /// Truncate data to end in inode
+    /// Deallocate data range in inode, the range reads as zeros afterwards
     /// params:
-    /// offset - data offset
+    /// offset - hole offset
+    /// len - hole len
     /// return:
-    /// truncate if success
-    pub fn truncate_to_end(&mut self, offset: u32) -> bool {
-        self.truncate(offset, self.size - offset)
+    /// punch if success
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> bool {
+        let end = min(offset + len, self.size);
+        if offset >= end {
+            return true;
+        }
+        if self.is_crypt_locked() {
+            return false;
+        }
+        if self.data.is_empty() {
+            let mut data = self.inline_data.clone();
+            let inline_end = min(end as usize, data.len());
+            if (offset as usize) >= inline_end {
+                return true;
+            }
+            data[offset as usize..inline_end].fill(0);
+            return self.update_inline_data(data, None);
+        }
+        // whole pages are dropped, the partial edge pages are zeroed
+        let first = offset.div_ceil(PAGE_SIZE);
+        let last = if end == self.size { end.div_ceil(PAGE_SIZE) } else { end / PAGE_SIZE };
+        if first >= last {
+            return self.zero_range(offset, end);
+        }
+        if !self.zero_range(offset, first * PAGE_SIZE) || !self.zero_range(last * PAGE_SIZE, end) {
+            return false;
+        }
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        self.replace_pages(&mut event_group, first, last - 1, vec![]);
+        if event_group.events.is_empty() {
+            return true;
+        }
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
+    /// Share data pages of another inode, pages are copied on the next write
+    /// params:
+    /// offset - data offset, page aligned
+    /// src - source inode, a copy of self for ranges of the same file
+    /// src_offset - source data offset, page aligned
+    /// len - data len, may end off a page only at the end of both files
+    /// return:
+    /// share if success
+    pub fn reflink(&mut self, offset: u64, src: &Inode, src_offset: u64, len: u64) -> bool {
+        if offset % PAGE_SIZE != 0 || src_offset % PAGE_SIZE != 0 || !src.inline_data.is_empty() {
+            return false;
+        }
+        // encrypted pages are bound to the key and place of their file
+        if self.is_encrypted() || src.is_encrypted() {
+            return false;
+        }
+        if src_offset >= src.size || len == 0 {
+            return true;
+        }
+        let len = min(len, src.size - src_offset);
+        if offset + len > MAX_FILE_SIZE {
+            return false;
+        }
+        // a partial last page would carry bytes past the range along
+        if len % PAGE_SIZE != 0 && (src_offset + len != src.size || offset + len < self.size) {
+            return false;
+        }
+        self.migrate_inline_data();
+        let first = offset / PAGE_SIZE;
+        let last = (offset + len - 1) / PAGE_SIZE;
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let events = match self.share_events(src, offset, src_offset, len) {
+            Some(events) => events,
+            None => return false,
+        };
+        self.replace_pages(&mut event_group, first, last, events);
+        if offset + len > self.size {
+            let event = inode_event::SetSizeInodeEvent {
+                size: offset + len,
+            };
+            event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        }
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
     }
 
     /// Get inode stat
@@ -472,6 +552,7 @@ impl Inode {
             size: self.size,
             uid: self.uid,
             gid: self.gid,
+            rdev: self.rdev,
             ref_cnt: self.ref_cnt,
             n_link: self.n_link,
             mode: self.mode,
@@ -501,10 +582,15 @@ impl Inode {
             size: stat.size,
             uid: stat.uid,
             gid: stat.gid,
+            rdev: stat.rdev,
             n_link: stat.n_link,
+            mode: stat.mode,
+            last_accessed: stat.last_accessed,
+            last_modified: stat.last_modified,
+            last_metadata_changed: stat.last_metadata_changed,
         };
         event_group.events.push(inode_event::InodeEvent::ModifyStat(event));
-        let inode = self.core.as_mut().unwrap().borrow_mut().dispose_event_group(event_group).unwrap();
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
         self.update_by_another_inode(inode);
         true
     }
@@ -521,6 +607,7 @@ impl Inode {
             size: self.size,
             uid: self.uid,
             gid: self.gid,
+            rdev: self.rdev,
             ref_cnt: self.ref_cnt,
             n_link: self.n_link + 1,
             mode: self.mode,
@@ -531,6 +618,113 @@ impl Inode {
         self.modify_stat(stat)
     }
 
+    /// Get extended attribute value
+    /// params:
+    /// name - attribute name
+    /// return:
+    /// attribute value if exists
+    pub fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        self.xattr.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone())
+    }
+
+    /// Get compression policy for new data
+    /// params:
+    /// ()
+    /// return:
+    /// policy from the compress xattr, the mount default without one
+    pub fn get_compress_policy(&self) -> CompressPolicy {
+        match self.get_xattr(compress::COMPRESS_XATTR).and_then(|value| CompressPolicy::parse(&value)) {
+            Some(policy) => policy,
+            None => self.core.as_ref().unwrap().lock().unwrap().get_compress_policy(),
+        }
+    }
+
+    /// Check whether contents are encrypted
+    /// params:
+    /// ()
+    /// return:
+    /// encrypted if the inode carries an encryption context
+    pub fn is_encrypted(&self) -> bool {
+        self.get_xattr(crypt::CRYPT_XATTR).is_some()
+    }
+
+    /// Check whether the key of encrypted contents is missing
+    /// params:
+    /// ()
+    /// return:
+    /// locked if contents are encrypted with a key not supplied at mount
+    pub fn is_crypt_locked(&self) -> bool {
+        match self.get_xattr(crypt::CRYPT_XATTR) {
+            Some(context) => !self.core.as_ref().unwrap().lock().unwrap().can_crypt(&context),
+            None => false,
+        }
+    }
+
+    /// Encrypt contents, existing data is written again
+    /// params:
+    /// context - encryption context
+    /// return:
+    /// encrypt if success
+    pub fn set_crypt_context(&mut self, context: &[u8]) -> bool {
+        if self.is_encrypted() || !self.core.as_ref().unwrap().lock().unwrap().can_crypt(context) {
+            return false;
+        }
+        let mut data = vec![];
+        self.read_all(&mut data);
+        if !self.truncate_to_end(0) || !self.set_xattr(crypt::CRYPT_XATTR, context) {
+            return false;
+        }
+        self.write(0, data.len() as u64, &data)
+    }
+
+    /// List extended attribute names
+    /// params:
+    /// ()
+    /// return:
+    /// attribute names in insertion order
+    pub fn list_xattr(&self) -> Vec<String> {
+        self.xattr.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Set extended attribute, replace value if exists
+    /// params:
+    /// name - attribute name
+    /// value - attribute value
+    /// return:
+    /// set if success
+    pub fn set_xattr(&mut self, name: &str, value: &[u8]) -> bool {
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let event = inode_event::SetXattrInodeEvent {
+            name: name.to_string(),
+            value: value.to_vec(),
+        };
+        event_group.events.push(inode_event::InodeEvent::SetXattr(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
+    /// Remove extended attribute
+    /// params:
+    /// name - attribute name
+    /// return:
+    /// remove if attribute exists
+    pub fn remove_xattr(&mut self, name: &str) -> bool {
+        if self.get_xattr(name).is_none() {
+            return false;
+        }
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let event = inode_event::RemoveXattrInodeEvent {
+            name: name.to_string(),
+        };
+        event_group.events.push(inode_event::InodeEvent::RemoveXattr(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
     /// Delete inode totally
     /// params:
     /// ()
@@ -540,23 +734,308 @@ impl Inode {
         let mut event_group = inode_event::InodeEventGroup::new();
         event_group.inode = self.copy_inode();
         event_group.need_delete = true;
-        if self.core.as_mut().unwrap().borrow_mut().dispose_event_group(event_group).is_some() {
+        if self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).is_some() {
             panic!("Inode: delete internal error");
         }
+        // pages are gone, the in-memory copy must not be written back or served again
+        self.data.clear();
+        self.inline_data.clear();
+        self.n_link = 0;
+        self.dirty = false;
+        true
+    }
+
+    /// Update access time in memory only, it is written back with the next change or sync
+    /// params:
+    /// policy - atime policy of the mount
+    /// now - access time
+    /// return:
+    /// whether atime changed
+    pub fn update_atime(&mut self, policy: AtimePolicy, now: (i64, u32)) -> bool {
+        let update = match policy {
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::StrictAtime => true,
+            AtimePolicy::Relatime => self.last_accessed <= self.last_modified
+                || self.last_accessed <= self.last_metadata_changed
+                || now.0 - self.last_accessed.0 >= RELATIME_INTERVAL,
+        };
+        if !update || now == self.last_accessed {
+            return false;
+        }
+        self.last_accessed = now;
+        self.dirty = true;
+        true
+    }
+
+    /// Write back changes made in memory only
+    /// params:
+    /// ()
+    /// return:
+    /// whether anything was written
+    pub fn sync(&mut self) -> bool {
+        if !self.dirty {
+            return false;
+        }
+        let inode = self.copy_inode();
+        self.core.as_mut().unwrap().lock().unwrap().update_inode(inode);
+        self.dirty = false;
         true
     }
 }
 
 // Inode Layer Internal Function
 impl Inode {
-    fn read_entry(&mut self, entry: &InodeEntry, start: u32, end: u32) -> Vec<u8> {
+    fn update_inline_data(&mut self, data: Vec<u8>, size: Option<u64>) -> bool {
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let event = inode_event::SetInlineDataInodeEvent {
+            data,
+        };
+        event_group.events.push(inode_event::InodeEvent::SetInlineData(event));
+        if let Some(size) = size {
+            let event = inode_event::SetSizeInodeEvent {
+                size,
+            };
+            event_group.events.push(inode_event::InodeEvent::SetSize(event));
+        }
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+        true
+    }
+
+    fn migrate_inline_data(&mut self) {
+        // inline data always starts at offset 0 and only lives in inodes without entries
+        if self.inline_data.is_empty() {
+            return;
+        }
+        let mut event_group = inode_event::InodeEventGroup::new();
+        event_group.inode = self.copy_inode();
+        let data = self.inline_data.clone();
+        event_group.events.append(&mut self.content_events(0, &data));
+        let event = inode_event::SetInlineDataInodeEvent {
+            data: vec![],
+        };
+        event_group.events.push(inode_event::InodeEvent::SetInlineData(event));
+        let inode = self.core.as_mut().unwrap().lock().unwrap().dispose_event_group(event_group).unwrap();
+        self.update_by_another_inode(inode);
+    }
+
+    fn inline_allowed(&self) -> bool {
+        // inline data lives in the inode record, which is never encrypted
+        self.data.is_empty() && !self.is_encrypted()
+    }
+
+    fn read_range(&mut self, start: u64, end: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        if start < end {
+            self.read(start, end - start, &mut buf);
+            buf.resize((end - start) as usize, 0);
+        }
+        buf
+    }
+
+    fn zero_range(&mut self, start: u64, end: u64) -> bool {
+        // a range without data is a hole already
+        match self.seek_data(start) {
+            Some(pos) if pos < end => self.write(start, end - start, &vec![0; (end - start) as usize]),
+            _ => true,
+        }
+    }
+
+    fn replace_pages(&mut self, event_group: &mut inode_event::InodeEventGroup, first: u64, last: u64, events: Vec<inode_event::InodeEvent>) {
+        let mut index = 0;
+        let mut added = false;
+        let mut events = Some(events);
+        for entry in self.data.clone().iter() {
+            let entry_first = entry.offset / PAGE_SIZE;
+            let entry_last = entry_first + entry.len.div_ceil(PAGE_SIZE) - 1;
+            if entry_last < first {
+                index += 1;
+                continue;
+            }
+            if entry_first > last {
+                if !added {
+                    index = Inode::push_events(event_group, index, events.take().unwrap());
+                    added = true;
+                }
+                index += 1;
+                continue;
+            }
+            if entry.is_sealed() {
+                // a sealed entry is dropped whole, its pages outside the range are written again
+                let event = inode_event::DeleteContentInodeEvent {
+                    index,
+                    size: entry.size,
+                    v_address: entry.address,
+                };
+                event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
+                index += 1;
+                if entry_first < first {
+                    let prefix = self.read_range(entry.offset, first * PAGE_SIZE);
+                    index = Inode::push_events(event_group, index, self.content_events(entry.offset, &prefix));
+                }
+                if !added {
+                    index = Inode::push_events(event_group, index, events.take().unwrap());
+                    added = true;
+                }
+                if entry_last > last {
+                    let suffix = self.read_range((last + 1) * PAGE_SIZE, entry.offset + entry.len);
+                    index = Inode::push_events(event_group, index, self.content_events((last + 1) * PAGE_SIZE, &suffix));
+                }
+                continue;
+            }
+            // pages before first and after last stay where they are
+            let valid_prev = first.saturating_sub(entry_first);
+            let drop = min(last, entry_last) + 1 - max(first, entry_first);
+            if valid_prev == 0 {
+                let event = inode_event::DeleteContentInodeEvent {
+                    index,
+                    size: drop as u32,
+                    v_address: entry.address,
+                };
+                event_group.events.push(inode_event::InodeEvent::DeleteContent(event));
+            } else {
+                let event = inode_event::TruncateContentInodeEvent {
+                    index,
+                    offset: entry.offset,
+                    len: valid_prev * PAGE_SIZE,
+                    size: valid_prev as u32,
+                    o_size: (valid_prev + drop) as u32,
+                    v_address: entry.address,
+                };
+                event_group.events.push(inode_event::InodeEvent::TruncateContent(event));
+            }
+            index += 1;
+            if !added {
+                index = Inode::push_events(event_group, index, events.take().unwrap());
+                added = true;
+            }
+            if entry_last > last {
+                let skip = last + 1 - entry_first;
+                let event = inode_event::SplitContentInodeEvent {
+                    index,
+                    offset: (last + 1) * PAGE_SIZE,
+                    len: entry.offset + entry.len - (last + 1) * PAGE_SIZE,
+                    size: entry.size - skip as u32,
+                    v_address: entry.address + skip as u32,
+                };
+                event_group.events.push(inode_event::InodeEvent::SplitContent(event));
+                index += 1;
+            }
+        }
+        if !added {
+            Inode::push_events(event_group, index, events.take().unwrap());
+        }
+    }
+
+    fn push_events(event_group: &mut inode_event::InodeEventGroup, index: u32, events: Vec<inode_event::InodeEvent>) -> u32 {
+        let mut index = index;
+        for mut event in events {
+            event.set_index(index);
+            event_group.events.push(event);
+            index += 1;
+        }
+        index
+    }
+
+    fn content_events(&self, offset: u64, content: &[u8]) -> Vec<inode_event::InodeEvent> {
+        let mut events = vec![];
+        let mut offset = offset;
+        let compress_policy = self.get_compress_policy();
+        let pages = match compress_policy {
+            CompressPolicy::Off if !self.is_encrypted() => MAX_ENTRY_PAGES,
+            _ => COMPRESS_ENTRY_PAGES,
+        };
+        for chunk in content.chunks((pages * PAGE_SIZE) as usize) {
+            let event = inode_event::AddContentInodeEvent {
+                index: 0,
+                offset,
+                len: chunk.len() as u64,
+                size: (chunk.len() as u64).div_ceil(PAGE_SIZE) as u32,
+                content: chunk.to_vec(),
+                compress_policy,
+            };
+            events.push(inode_event::InodeEvent::AddContent(event));
+            offset += chunk.len() as u64;
+        }
+        events
+    }
+
+    fn share_events(&self, src: &Inode, offset: u64, src_offset: u64, len: u64) -> Option<Vec<inode_event::InodeEvent>> {
+        let mut entries = src.data.clone();
+        entries.sort_by_key(|entry| entry.offset);
+        let mut events = vec![];
+        for entry in entries.iter() {
+            let start = max(entry.offset, src_offset);
+            let end = min(entry.offset + entry.len, src_offset + len);
+            if start >= end {
+                continue;
+            }
+            // compressed entries can only be shared whole
+            if entry.compress_type != CompressType::None {
+                if start != entry.offset || end != entry.offset + entry.len {
+                    return None;
+                }
+                let event = inode_event::ShareContentInodeEvent {
+                    index: 0,
+                    offset: offset + start - src_offset,
+                    len: entry.len,
+                    size: entry.size,
+                    v_address: entry.address,
+                    compress_type: entry.compress_type,
+                    compressed_len: entry.compressed_len,
+                };
+                events.push(inode_event::InodeEvent::ShareContent(event));
+                continue;
+            }
+            // entries are page aligned, so the shared range starts on a page of the entry
+            let skip = (start - entry.offset) / PAGE_SIZE;
+            let event = inode_event::ShareContentInodeEvent {
+                index: 0,
+                offset: offset + start - src_offset,
+                len: end - start,
+                size: (end - start).div_ceil(PAGE_SIZE) as u32,
+                v_address: entry.address + skip as u32,
+                compress_type: CompressType::None,
+                compressed_len: 0,
+            };
+            events.push(inode_event::InodeEvent::ShareContent(event));
+        }
+        Some(events)
+    }
+
+    fn read_entry(&mut self, entry: &InodeEntry, start: u64, end: u64) -> Option<Vec<u8>> {
+        if entry.is_sealed() {
+            let context = self.get_xattr(crypt::CRYPT_XATTR);
+            let core = self.core.as_mut().unwrap();
+            let mut data = vec![];
+            for i in 0..entry.size {
+                data.extend_from_slice(&core.lock().unwrap().read_data(entry.address + i));
+            }
+            data.truncate(match entry.compress_type {
+                CompressType::None => entry.len,
+                _ => entry.compressed_len,
+            } as usize);
+            if entry.encrypted && !core.lock().unwrap().decrypt(&context?, entry, &mut data) {
+                return None;
+            }
+            if entry.compress_type != CompressType::None {
+                data = core.lock().unwrap().decompress(&data, entry.compress_type);
+                // a broken stream decodes short
+                if data.len() < entry.len as usize {
+                    return None;
+                }
+            }
+            data.resize(entry.len as usize, 0);
+            return Some(data[start as usize..end as usize].to_vec());
+        }
         let start_index = start / 4096;
         let start_off = start % 4096;
         let end_index = (end - 1) / 4096;
         let end_off = (end - 1) % 4096;
         let mut pages = vec![];
         for i in start_index..end_index + 1 {
-            pages.push(self.core.as_mut().unwrap().borrow_mut().read_data(entry.address + i));
+            pages.push(self.core.as_mut().unwrap().lock().unwrap().read_data(entry.address + i as u32));
         }
         let mut res = vec![];
         if end_index - start_index > 0 {
@@ -576,7 +1055,7 @@ impl Inode {
         if end_index - start_index > 0 {
             res.append(&mut pages[(end_index - start_index) as usize][0..(end_off + 1) as usize].to_vec());
         }
-        res
+        Some(res)
     }
 
     fn update_by_another_inode(&mut self, inode: Inode) {
@@ -585,9 +1064,18 @@ impl Inode {
         self.size = inode.size;
         self.uid = inode.uid;
         self.gid = inode.gid;
+        self.rdev = inode.rdev;
         self.ref_cnt = inode.ref_cnt;
         self.n_link = inode.n_link;
+        self.mode = inode.mode;
+        self.last_accessed = inode.last_accessed;
+        self.last_modified = inode.last_modified;
+        self.last_metadata_changed = inode.last_metadata_changed;
         self.data = inode.data;
+        self.inline_data = inode.inline_data;
+        self.xattr = inode.xattr;
+        // every event group carries the whole inode, so nothing is left to write back
+        self.dirty = false;
     }
 }
 
@@ -604,8 +1092,8 @@ mod test {
 
     #[test]
     fn write() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         let mut buf_1 = vec![];
         for _ in 0..100 {
@@ -623,30 +1111,30 @@ mod test {
         for _ in 0..30 {
             buf_4.push(21);
         }
-        link.as_ref().unwrap().borrow_mut().write(0, 100, &buf_1);
-        link.as_ref().unwrap().borrow_mut().write(13, 27, &buf_2);
-        link.as_ref().unwrap().borrow_mut().write(89, 10, &buf_3);
-        link.as_mut().unwrap().borrow_mut().write(5, 30, &buf_4);
+        link.as_ref().unwrap().write().unwrap().write(0, 100, &buf_1);
+        link.as_ref().unwrap().write().unwrap().write(13, 27, &buf_2);
+        link.as_ref().unwrap().write().unwrap().write(89, 10, &buf_3);
+        link.as_mut().unwrap().write().unwrap().write(5, 30, &buf_4);
         let mut buf = vec![];
-        link.as_mut().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_mut().unwrap().write().unwrap().read_all(&mut buf);
         assert_eq!(buf.len(), 100);
-        link.as_mut().unwrap().borrow_mut().read(10, 80, &mut buf);
+        link.as_mut().unwrap().write().unwrap().read(10, 80, &mut buf);
         assert_eq!(buf.len(), 80);
         let mut buf_5 = vec![];
         for _ in 0..10000 {
             buf_5.push(37)
         }
-        link.as_mut().unwrap().borrow_mut().write(5, 10000, &buf_5);
-        link.as_mut().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_mut().unwrap().write().unwrap().write(5, 10000, &buf_5);
+        link.as_mut().unwrap().write().unwrap().read_all(&mut buf);
         assert_eq!(buf.len(), 10005);
-        link.as_mut().unwrap().borrow_mut().read(50, 8000, &mut buf);
+        link.as_mut().unwrap().write().unwrap().read(50, 8000, &mut buf);
         assert_eq!(buf.len(), 8000);
     }
 
     #[test]
     fn insert() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         let mut buf_1 = vec![];
         for _ in 0..100 {
@@ -664,21 +1152,21 @@ mod test {
         for _ in 0..30 {
             buf_4.push(21);
         }
-        link.as_ref().unwrap().borrow_mut().insert(0, 100, &buf_1);
-        link.as_ref().unwrap().borrow_mut().insert(40, 30, &buf_2);
-        link.as_ref().unwrap().borrow_mut().insert(45, 10, &buf_3);
-        link.as_mut().unwrap().borrow_mut().insert(35, 30, &buf_4);
+        link.as_ref().unwrap().write().unwrap().insert(0, 100, &buf_1);
+        link.as_ref().unwrap().write().unwrap().insert(40, 30, &buf_2);
+        link.as_ref().unwrap().write().unwrap().insert(45, 10, &buf_3);
+        link.as_mut().unwrap().write().unwrap().insert(35, 30, &buf_4);
         let mut buf = vec![];
-        link.as_mut().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_mut().unwrap().write().unwrap().read_all(&mut buf);
         assert_eq!(buf.len(), 170);
-        link.as_mut().unwrap().borrow_mut().read(10, 80, &mut buf);
+        link.as_mut().unwrap().write().unwrap().read(10, 80, &mut buf);
         assert_eq!(buf.len(), 80);
     }
 
     #[test]
     fn truncate() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let mut link = inode_manager.i_alloc();
         let mut buf_1 = vec![];
         for _ in 0..100 {
@@ -696,43 +1184,471 @@ mod test {
         for _ in 0..30 {
             buf_4.push(21);
         }
-        link.as_ref().unwrap().borrow_mut().insert(0, 100, &buf_1);
-        link.as_ref().unwrap().borrow_mut().insert(40, 30, &buf_2);
-        link.as_ref().unwrap().borrow_mut().insert(45, 10, &buf_3);
-        link.as_ref().unwrap().borrow_mut().truncate(30, 100);
+        link.as_ref().unwrap().write().unwrap().insert(0, 100, &buf_1);
+        link.as_ref().unwrap().write().unwrap().insert(40, 30, &buf_2);
+        link.as_ref().unwrap().write().unwrap().insert(45, 10, &buf_3);
+        link.as_ref().unwrap().write().unwrap().truncate(30, 100);
         let mut buf = vec![];
-        link.as_mut().unwrap().borrow_mut().read_all(&mut buf);
+        link.as_mut().unwrap().write().unwrap().read_all(&mut buf);
         assert_eq!(buf.len(), 40);
     }
 
+    #[test]
+    fn sparse() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        inode.write().unwrap().write(0, 10, &vec![1; 10]);
+        inode.write().unwrap().write(100, 10, &vec![2; 10]);
+        assert_eq!(inode.read().unwrap().size, 110);
+        let mut buf = vec![];
+        assert_eq!(inode.write().unwrap().read(5, 100, &mut buf), 100);
+        assert_eq!(&buf[..5], &[1; 5]);
+        assert!(buf[5..95].iter().all(|byte| *byte == 0));
+        assert_eq!(&buf[95..], &[2; 5]);
+        inode.write().unwrap().write(50, 10, &vec![3; 10]);
+        inode.write().unwrap().read(45, 20, &mut buf);
+        assert_eq!(&buf[5..15], &[3; 10]);
+        assert!(inode.write().unwrap().set_size(8192));
+        assert_eq!(inode.read().unwrap().size, 8192);
+        assert_eq!(inode.write().unwrap().read(8000, 500, &mut buf), 192);
+        assert!(buf.iter().all(|byte| *byte == 0));
+        assert!(inode.write().unwrap().set_size(105));
+        assert_eq!(inode.write().unwrap().read(0, 200, &mut buf), 105);
+        assert_eq!(inode.write().unwrap().read(105, 1, &mut buf), -1);
+        assert!(inode.write().unwrap().punch_hole(2, 53));
+        assert_eq!(inode.read().unwrap().size, 105);
+        inode.write().unwrap().read(0, 105, &mut buf);
+        assert_eq!(&buf[..2], &[1; 2]);
+        assert!(buf[2..55].iter().all(|byte| *byte == 0));
+        assert_eq!(&buf[55..60], &[3; 5]);
+        assert_eq!(&buf[100..], &[2; 5]);
+    }
+
+    #[test]
+    fn truncate_grow() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        assert!(inode.write().unwrap().write(0, 10000, &vec![4; 10000]));
+        // the cut tail of the kept page must not come back when the file grows again
+        assert!(inode.write().unwrap().set_size(1000));
+        assert!(inode.write().unwrap().set_size(9000));
+        let mut buf = vec![];
+        assert_eq!(inode.write().unwrap().read_all(&mut buf), 9000);
+        assert_eq!(&buf[..1000], &[4; 1000]);
+        assert!(buf[1000..].iter().all(|byte| *byte == 0));
+        // truncating to zero and writing less leaves no stale tail
+        assert!(inode.write().unwrap().set_size(0));
+        assert!(inode.write().unwrap().write(0, 3, &vec![5; 3]));
+        assert_eq!(inode.write().unwrap().read_all(&mut buf), 3);
+        assert_eq!(buf, vec![5; 3]);
+    }
+
+    #[test]
+    fn atime() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        let mut stat = link.read().unwrap().get_stat();
+        stat.n_link = 1;
+        stat.last_accessed = (1000, 0);
+        stat.last_modified = (2000, 0);
+        stat.last_metadata_changed = (2000, 0);
+        link.write().unwrap().modify_stat(stat);
+
+        // relatime catches up with a change once, then waits a day
+        assert!(!link.write().unwrap().update_atime(AtimePolicy::NoAtime, (3000, 0)));
+        assert!(link.write().unwrap().update_atime(AtimePolicy::Relatime, (3000, 0)));
+        assert!(!link.write().unwrap().update_atime(AtimePolicy::Relatime, (3001, 0)));
+        assert!(link.write().unwrap().update_atime(AtimePolicy::StrictAtime, (3001, 0)));
+        assert!(link.write().unwrap().update_atime(AtimePolicy::Relatime, (3001 + RELATIME_INTERVAL, 0)));
+
+        // accesses stay in memory until a sync or the next change
+        let raw_atime = |core: &inode_manager::CoreLink| core.lock().unwrap().get_raw_inode(ino).last_accessed;
+        let core = std::sync::Arc::clone(&inode_manager.core_manager);
+        assert_eq!(raw_atime(&core), (1000, 0));
+        assert!(link.read().unwrap().dirty);
+        inode_manager.sync();
+        assert_eq!(raw_atime(&core), (3001 + RELATIME_INTERVAL, 0));
+        assert!(!link.read().unwrap().dirty);
+        link.write().unwrap().update_atime(AtimePolicy::StrictAtime, (5 * RELATIME_INTERVAL, 0));
+        assert!(link.write().unwrap().write(0, 10, &vec![1; 10]));
+        assert!(!link.read().unwrap().dirty);
+        assert_eq!(raw_atime(&core), (5 * RELATIME_INTERVAL, 0));
+        inode_manager.i_put(link);
+    }
+
+    #[test]
+    fn large() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        let offset = 5 << 30;
+        assert!(inode.write().unwrap().write(offset, 10, &vec![7; 10]));
+        assert_eq!(inode.read().unwrap().size, offset + 10);
+        let mut buf = vec![];
+        assert_eq!(inode.write().unwrap().read(offset - 5, 20, &mut buf), 15);
+        assert_eq!(&buf[5..], &[7; 10]);
+        assert!(!inode.write().unwrap().write(MAX_FILE_SIZE - 5, 10, &vec![7; 10]));
+        assert!(!inode.write().unwrap().set_size(MAX_FILE_SIZE + 1));
+        assert_eq!(inode.read().unwrap().size, offset + 10);
+    }
+
+    #[test]
+    fn seek() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        inode.write().unwrap().write(4096, 4096, &vec![1; 4096]);
+        inode.write().unwrap().write(8192, 4096, &vec![2; 4096]);
+        inode.write().unwrap().write(20480, 10, &vec![3; 10]);
+        inode.write().unwrap().set_size(32768);
+        assert_eq!(inode.read().unwrap().seek_data(0), Some(4096));
+        assert_eq!(inode.read().unwrap().seek_data(5000), Some(5000));
+        assert_eq!(inode.read().unwrap().seek_data(12288), Some(20480));
+        assert_eq!(inode.read().unwrap().seek_data(20490), None);
+        assert_eq!(inode.read().unwrap().seek_hole(0), Some(0));
+        assert_eq!(inode.read().unwrap().seek_hole(4096), Some(12288));
+        assert_eq!(inode.read().unwrap().seek_hole(20485), Some(20490));
+        assert_eq!(inode.read().unwrap().seek_hole(32767), Some(32767));
+        assert_eq!(inode.read().unwrap().seek_hole(32768), None);
+    }
+
+    #[test]
+    fn overwrite() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        inode.write().unwrap().write(0, 4096, &vec![1; 4096]);
+        inode.write().unwrap().write(4096, 8192, &vec![2; 8192]);
+        assert_eq!(inode.read().unwrap().data.len(), 1);
+        assert_eq!(inode.read().unwrap().data[0].size, 3);
+        let address = inode.read().unwrap().data[0].address;
+        inode.write().unwrap().write(5000, 10, &vec![3; 10]);
+        let data = inode.read().unwrap().data.clone();
+        assert_eq!(data.len(), 3);
+        assert_eq!((data[0].offset, data[0].size, data[0].address), (0, 1, address));
+        assert_eq!((data[1].offset, data[1].len, data[1].size), (4096, 4096, 1));
+        assert_eq!((data[2].offset, data[2].size, data[2].address), (8192, 1, address + 2));
+        let mut buf = vec![];
+        inode.write().unwrap().read(4990, 30, &mut buf);
+        assert_eq!(&buf[..10], &[2; 10]);
+        assert_eq!(&buf[10..20], &[3; 10]);
+        assert_eq!(&buf[20..], &[2; 10]);
+        inode.write().unwrap().write(300000, 600000, &vec![4; 600000]);
+        assert!(inode.read().unwrap().data.iter().all(|entry| entry.size as u64 <= MAX_ENTRY_PAGES));
+        assert_eq!(inode.write().unwrap().read(300000, 600000, &mut buf), 600000);
+        assert!(buf.iter().all(|byte| *byte == 4));
+    }
+
+    #[test]
+    fn inline() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        inode.write().unwrap().write(0, 100, &vec![1; 100]);
+        inode.write().unwrap().insert(50, 10, &vec![2; 10]);
+        inode.write().unwrap().truncate(0, 5);
+        assert!(inode.read().unwrap().data.is_empty());
+        assert_eq!(inode.read().unwrap().inline_data.len(), 105);
+        assert_eq!(inode.read().unwrap().size, 105);
+        let mut buf = vec![];
+        inode.write().unwrap().read(40, 20, &mut buf);
+        assert_eq!(&buf[..5], &[1; 5]);
+        assert_eq!(&buf[5..15], &[2; 10]);
+        inode.write().unwrap().write(3000, 10, &vec![3; 10]);
+        assert!(inode.read().unwrap().inline_data.is_empty());
+        assert_eq!(inode.read().unwrap().data.len(), 1);
+        assert_eq!(inode.read().unwrap().size, 3010);
+        inode.write().unwrap().read(40, 20, &mut buf);
+        assert_eq!(&buf[5..15], &[2; 10]);
+        inode.write().unwrap().read(2990, 20, &mut buf);
+        assert_eq!(&buf[..10], &[0; 10]);
+        assert_eq!(&buf[10..], &[3; 10]);
+    }
+
+    #[test]
+    fn reflink() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let src_link = inode_manager.i_alloc();
+        let src = src_link.as_ref().unwrap();
+        let dst_link = inode_manager.i_alloc();
+        let dst = dst_link.as_ref().unwrap();
+        src.write().unwrap().write(0, 12288, &vec![1; 12288]);
+        src.write().unwrap().write(12288, 100, &vec![2; 100]);
+        dst.write().unwrap().write(0, 10, &vec![9; 10]);
+        assert!(!dst.write().unwrap().reflink(100, &src.read().unwrap().copy_inode(), 0, 4096));
+        let dst_inode = dst.read().unwrap().copy_inode();
+        assert!(!dst.write().unwrap().reflink(0, &dst_inode, 0, 10));
+        let src_inode = src.read().unwrap().copy_inode();
+        assert!(dst.write().unwrap().reflink(4096, &src_inode, 4096, 20000));
+        assert_eq!(dst.read().unwrap().size, 4096 + 8192 + 100);
+        assert!(dst.read().unwrap().inline_data.is_empty());
+        let mut buf = vec![];
+        assert_eq!(dst.write().unwrap().read(0, 20000, &mut buf), 4096 + 8192 + 100);
+        assert_eq!(&buf[..10], &[9; 10]);
+        assert_eq!(&buf[10..4096], &[0; 4086]);
+        assert!(buf[4096..12288].iter().all(|byte| *byte == 1));
+        assert_eq!(&buf[12288..], &[2; 100]);
+        // both inodes own the shared pages until one of them writes
+        let core = inode_manager.core_manager.clone();
+        let v_address = dst.read().unwrap().data.iter().find(|entry| entry.offset == 4096).unwrap().address;
+        let (src_ino, dst_ino) = (src.read().unwrap().ino, dst.read().unwrap().ino);
+        let src_v_address = src.read().unwrap().data[0].address + 1;
+        assert_eq!(core.lock().unwrap().get_data_owners(v_address), vec![src_ino, dst_ino]);
+        dst.write().unwrap().write(4096, 10, &vec![3; 10]);
+        assert_eq!(core.lock().unwrap().get_data_owners(src_v_address), vec![src_ino]);
+        src.write().unwrap().read(4096, 20, &mut buf);
+        assert_eq!(buf, vec![1; 20]);
+        dst.write().unwrap().read(4096, 20, &mut buf);
+        assert_eq!(&buf[..10], &[3; 10]);
+        assert_eq!(&buf[10..], &[1; 10]);
+        assert!(src.write().unwrap().reflink(0, &src_inode, 8192, 4096));
+        src.write().unwrap().read(0, 4096, &mut buf);
+        assert_eq!(buf, vec![1; 4096]);
+        assert_eq!(src.read().unwrap().size, 12388);
+    }
+
+    #[test]
+    fn compress() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        inode_manager.core_manager.lock().unwrap().set_compress_policy(CompressPolicy::Fast);
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        let text = "2024-01-01 wondfs: page written\n".repeat(3000).into_bytes();
+        inode.write().unwrap().write(0, text.len() as u64, &text);
+        let data = inode.read().unwrap().data.clone();
+        assert!(data.iter().all(|entry| entry.len.div_ceil(PAGE_SIZE) <= COMPRESS_ENTRY_PAGES));
+        assert!(data.iter().any(|entry| entry.compress_type != CompressType::None));
+        assert!(data.iter().map(|entry| entry.size as u64).sum::<u64>() < (text.len() as u64).div_ceil(PAGE_SIZE));
+        let mut buf = vec![];
+        assert_eq!(inode.write().unwrap().read_all(&mut buf), text.len() as i64);
+        assert_eq!(buf, text);
+        // overwriting inside a compressed entry rewrites its other pages
+        inode.write().unwrap().write(20000, 10, &vec![b'#'; 10]);
+        let mut expected = text.clone();
+        expected[20000..20010].fill(b'#');
+        inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, expected);
+        inode.write().unwrap().truncate_to_end(30000);
+        expected.truncate(30000);
+        inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, expected);
+        let src = inode.read().unwrap().copy_inode();
+        assert!(!inode.write().unwrap().reflink(65536, &src, 4096, 8192));
+        // incompressible data is stored raw
+        let mut seed = 0x2545f491u32;
+        let noise = (0..16384).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect::<Vec<u8>>();
+        inode.write().unwrap().write(65536, noise.len() as u64, &noise);
+        let entry = inode.read().unwrap().data.iter().find(|entry| entry.offset == 65536).cloned().unwrap();
+        assert!(entry.compress_type == CompressType::None);
+        inode.write().unwrap().read(65536, noise.len() as u64, &mut buf);
+        assert_eq!(buf, noise);
+        // a compressed entry that no longer decodes to its length can't be read
+        let index = inode.read().unwrap().data.iter().position(|entry| entry.compress_type != CompressType::None).unwrap();
+        let offset = inode.read().unwrap().data[index].offset;
+        let mut guard = inode.write().unwrap();
+        guard.data[index].compress_type = CompressType::Huffman;
+        guard.data[index].compressed_len = crate::compress::huffman::HUFFMAN_HEADER_SIZE as u64;
+        drop(guard);
+        assert_eq!(inode.write().unwrap().read(offset, 10, &mut buf), -1);
+    }
+
+    #[test]
+    fn compress_policy() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        let text = "2024-01-01 wondfs: page written\n".repeat(1000).into_bytes();
+        assert_eq!(inode.read().unwrap().get_compress_policy(), CompressPolicy::Off);
+        inode.write().unwrap().write(0, text.len() as u64, &text);
+        assert!(inode.read().unwrap().data.iter().all(|entry| entry.compress_type == CompressType::None));
+        // the xattr wins over the mount default
+        inode.write().unwrap().set_xattr(compress::COMPRESS_XATTR, b"max");
+        assert_eq!(inode.read().unwrap().get_compress_policy(), CompressPolicy::Max);
+        inode.write().unwrap().write(0, text.len() as u64, &text);
+        assert!(inode.read().unwrap().data.iter().all(|entry| entry.compress_type != CompressType::None));
+        let mut buf = vec![];
+        inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, text);
+        inode_manager.core_manager.lock().unwrap().set_compress_policy(CompressPolicy::Fast);
+        inode.write().unwrap().set_xattr(compress::COMPRESS_XATTR, b"off");
+        inode.write().unwrap().write(0, text.len() as u64, &text);
+        assert!(inode.read().unwrap().data.iter().all(|entry| entry.compress_type == CompressType::None));
+        inode.write().unwrap().remove_xattr(compress::COMPRESS_XATTR);
+        assert_eq!(inode.read().unwrap().get_compress_policy(), CompressPolicy::Fast);
+    }
+
+    #[test]
+    fn encrypt() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        inode_manager.core_manager.lock().unwrap().set_crypt_key([3; 32]);
+        let link = inode_manager.i_alloc();
+        let inode = link.as_ref().unwrap();
+        inode.write().unwrap().write(0, 5, &b"hello".to_vec());
+        let context = inode_manager.core_manager.lock().unwrap().new_crypt_context().unwrap();
+        assert!(inode.write().unwrap().set_crypt_context(&context));
+        assert!(!inode.write().unwrap().set_crypt_context(&context));
+        assert!(inode.read().unwrap().inline_data.is_empty());
+        let text = "customer record 0042\n".repeat(2000).into_bytes();
+        inode.write().unwrap().write(5, text.len() as u64, &text);
+        let mut expected = b"hello".to_vec();
+        expected.extend_from_slice(&text);
+        let data = inode.read().unwrap().data.clone();
+        assert!(data.iter().all(|entry| entry.encrypted && entry.len.div_ceil(PAGE_SIZE) <= COMPRESS_ENTRY_PAGES));
+        // pages below the inode layer only hold ciphertext
+        let page = inode_manager.core_manager.lock().unwrap().read_data(data[0].address);
+        assert_ne!(&page[..5], b"hello");
+        let mut buf = vec![];
+        inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, expected);
+        inode.write().unwrap().write(20000, 4, &b"####".to_vec());
+        expected[20000..20004].copy_from_slice(b"####");
+        inode.write().unwrap().truncate_to_end(30000);
+        expected.truncate(30000);
+        inode.write().unwrap().read_all(&mut buf);
+        assert_eq!(buf, expected);
+        let src = inode.read().unwrap().copy_inode();
+        assert!(!inode.write().unwrap().reflink(65536, &src, 0, 4096));
+
+        // compression runs before encryption
+        inode_manager.core_manager.lock().unwrap().set_compress_policy(CompressPolicy::Fast);
+        inode.write().unwrap().write(0, text.len() as u64, &text);
+        assert!(inode.read().unwrap().data.iter().any(|entry| entry.encrypted && entry.compress_type != CompressType::None));
+        inode.write().unwrap().read(0, text.len() as u64, &mut buf);
+        assert_eq!(buf, text);
+
+        // a tampered entry fails to open
+        let tag = inode.read().unwrap().data[0].crypt_tag;
+        inode.write().unwrap().data[0].crypt_tag[0] ^= 1;
+        assert_eq!(inode.write().unwrap().read(0, 10, &mut buf), -1);
+        inode.write().unwrap().data[0].crypt_tag = tag;
+
+        // without the right key nothing can be read or written
+        inode_manager.core_manager.lock().unwrap().set_crypt_key([4; 32]);
+        assert!(inode.read().unwrap().is_crypt_locked());
+        assert_eq!(inode.write().unwrap().read(0, 10, &mut buf), -1);
+        assert!(!inode.write().unwrap().write(0, 4, &b"oops".to_vec()));
+        inode_manager.core_manager.lock().unwrap().set_crypt_key([3; 32]);
+        assert_eq!(inode.write().unwrap().read(0, 10, &mut buf), 10);
+        assert_eq!(buf, text[..10].to_vec());
+    }
+
+    #[test]
+    fn dedup() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        inode_manager.core_manager.lock().unwrap().set_dedup(true);
+        let core = inode_manager.core_manager.clone();
+        let text = (0..5 * PAGE_SIZE).map(|index| (index / 7) as u8).collect::<Vec<u8>>();
+        let src_link = inode_manager.i_alloc();
+        let src = src_link.as_ref().unwrap();
+        src.write().unwrap().write(0, text.len() as u64, &text);
+        assert_eq!(src.read().unwrap().data.len(), 1);
+        let free = core.lock().unwrap().statfs().bfree;
+        // a copy programs no page and its entries collapse back into one
+        let dst_link = inode_manager.i_alloc();
+        let dst = dst_link.as_ref().unwrap();
+        dst.write().unwrap().write(0, text.len() as u64, &text);
+        assert_eq!(core.lock().unwrap().statfs().bfree, free);
+        assert_eq!(dst.read().unwrap().data.len(), 1);
+        let (src_ino, dst_ino) = (src.read().unwrap().ino, dst.read().unwrap().ino);
+        let v_address = dst.read().unwrap().data[0].address;
+        assert_eq!(core.lock().unwrap().get_data_owners(v_address), vec![src_ino, dst_ino]);
+        // identical pages of one write are counted once per reference
+        let zero_link = inode_manager.i_alloc();
+        let zero = zero_link.as_ref().unwrap();
+        zero.write().unwrap().write(0, 4 * PAGE_SIZE, &vec![0; 4 * PAGE_SIZE as usize]);
+        assert_eq!(core.lock().unwrap().statfs().bfree, free - 1);
+        let zero_ino = zero.read().unwrap().ino;
+        let v_address = zero.read().unwrap().data[0].address;
+        assert_eq!(core.lock().unwrap().get_data_owners(v_address), vec![zero_ino; 4]);
+        zero.write().unwrap().write(PAGE_SIZE, 10, &vec![1; 10]);
+        assert_eq!(core.lock().unwrap().get_data_owners(v_address), vec![zero_ino; 3]);
+        assert_eq!(core.lock().unwrap().statfs().bfree, free - 2);
+        let mut buf = vec![];
+        assert_eq!(zero.write().unwrap().read_all(&mut buf), 4 * PAGE_SIZE as i64);
+        assert_eq!(&buf[PAGE_SIZE as usize..PAGE_SIZE as usize + 10], &[1; 10]);
+        assert_eq!(buf.iter().filter(|byte| **byte == 0).count(), 4 * PAGE_SIZE as usize - 10);
+        // pages stay with the copy once the source is gone
+        src.write().unwrap().delete();
+        let v_address = dst.read().unwrap().data[0].address;
+        assert_eq!(core.lock().unwrap().get_data_owners(v_address), vec![dst_ino]);
+        assert_eq!(dst.write().unwrap().read_all(&mut buf), text.len() as i64);
+        assert_eq!(buf, text);
+        dst.write().unwrap().delete();
+        assert_eq!(core.lock().unwrap().statfs().bfree, free - 2 + 5);
+    }
+
     #[test]
     fn modify() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let link = inode_manager.i_alloc();
         let stat = InodeStat {
             file_type: InodeFileType::Directory,
-            ino: link.as_ref().unwrap().borrow().ino,
+            ino: link.as_ref().unwrap().read().unwrap().ino,
             size: 0,
             uid: 100,
             gid: 44,
+            rdev: 0,
             ref_cnt: 10,
             n_link: 10,
-            mode: 0,
+            mode: 0o2755,
             last_accessed: (0, 0),
-            last_modified: (0, 0),
+            last_modified: (12, 0),
             last_metadata_changed: (0, 0),
         };
-        link.as_ref().unwrap().borrow_mut().modify_stat(stat);
-        let link = link.as_ref().unwrap().borrow_mut().core.as_mut().unwrap().borrow_mut().get_inode(1);
+        link.as_ref().unwrap().write().unwrap().modify_stat(stat);
+        assert_eq!(link.as_ref().unwrap().read().unwrap().mode, 0o2755);
+        let link = link.as_ref().unwrap().write().unwrap().core.as_mut().unwrap().lock().unwrap().get_inode(1);
         assert_eq!(link.uid, 100);
         assert_eq!(link.gid, 44);
+        assert_eq!(link.mode, 0o2755);
+        assert_eq!(link.last_modified, (12, 0));
+    }
+
+    #[test]
+    fn xattr() {
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
+        let link = inode_manager.i_alloc();
+        let ino = link.as_ref().unwrap().read().unwrap().ino;
+        assert!(link.as_ref().unwrap().write().unwrap().set_xattr("user.a", &[1, 2, 3]));
+        assert!(link.as_ref().unwrap().write().unwrap().set_xattr("user.b", &vec![9; 1000]));
+        assert!(link.as_ref().unwrap().write().unwrap().set_xattr("user.a", &[4]));
+        assert_eq!(link.as_ref().unwrap().read().unwrap().get_xattr("user.a"), Some(vec![4]));
+        assert_eq!(link.as_ref().unwrap().read().unwrap().list_xattr(), vec!["user.a".to_string(), "user.b".to_string()]);
+        let inode = inode_manager.core_manager.lock().unwrap().get_inode(ino);
+        assert_eq!(inode.get_xattr("user.b"), Some(vec![9; 1000]));
+        assert!(link.as_ref().unwrap().write().unwrap().remove_xattr("user.a"));
+        assert!(!link.as_ref().unwrap().write().unwrap().remove_xattr("user.a"));
+        assert_eq!(link.as_ref().unwrap().read().unwrap().get_xattr("user.a"), None);
+        let inode = inode_manager.core_manager.lock().unwrap().get_inode(ino);
+        assert_eq!(inode.list_xattr(), vec!["user.b".to_string()]);
     }
 
     #[test]
     fn delete() {
-        let mut inode_manager = inode_manager::InodeManager::new();
-        inode_manager.core_manager.borrow_mut().mount();
+        let inode_manager = inode_manager::InodeManager::new();
+        inode_manager.core_manager.lock().unwrap().mount();
         let link = inode_manager.i_alloc();
         let mut buf_1 = vec![];
         for _ in 0..100 {
@@ -742,8 +1658,8 @@ mod test {
         for _ in 0..27 {
             buf_2.push(31);
         }
-        link.as_ref().unwrap().borrow_mut().write(0, 100, &buf_1);
-        link.as_ref().unwrap().borrow_mut().write(13, 27, &buf_2);
-        link.as_ref().unwrap().borrow_mut().delete();
+        link.as_ref().unwrap().write().unwrap().write(0, 100, &buf_1);
+        link.as_ref().unwrap().write().unwrap().write(13, 27, &buf_2);
+        link.as_ref().unwrap().write().unwrap().delete();
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use crate::inode::inode;
+use crate::compress::compress::{CompressPolicy, CompressType};
 
 // Indoe Modify Event Group Structure
 pub struct InodeEventGroup {
@@ -57,8 +58,14 @@ pub enum InodeEvent {
     AddContent(AddContentInodeEvent),
     TruncateContent(TruncateContentInodeEvent),
     ChangeContent(ChangeContentInodeEvent),
+    SplitContent(SplitContentInodeEvent),
+    ShareContent(ShareContentInodeEvent),
     DeleteContent(DeleteContentInodeEvent),
     ModifyStat(ModifyInodeStatInodeEvent),
+    SetXattr(SetXattrInodeEvent),
+    RemoveXattr(RemoveXattrInodeEvent),
+    SetSize(SetSizeInodeEvent),
+    SetInlineData(SetInlineDataInodeEvent),
     None,
 }
 
@@ -69,30 +76,49 @@ impl InodeEvent {
             InodeEvent::AddContent(event) => index = event.index as i32,
             InodeEvent::TruncateContent(event) => index = event.index as i32,
             InodeEvent::ChangeContent(event) => index = event.index as i32,
+            InodeEvent::SplitContent(event) => index = event.index as i32,
+            InodeEvent::ShareContent(event) => index = event.index as i32,
             InodeEvent::DeleteContent(event) => index = event.index as i32,
             InodeEvent::ModifyStat(_) => index = -1,
+            InodeEvent::SetXattr(_) => index = -1,
+            InodeEvent::RemoveXattr(_) => index = -1,
+            InodeEvent::SetSize(_) => index = -1,
+            InodeEvent::SetInlineData(_) => index = -1,
             InodeEvent::None => (),
         }
         index
     }
+
+    pub fn set_index(&mut self, index: u32) {
+        match self {
+            InodeEvent::AddContent(event) => event.index = index,
+            InodeEvent::TruncateContent(event) => event.index = index,
+            InodeEvent::ChangeContent(event) => event.index = index,
+            InodeEvent::SplitContent(event) => event.index = index,
+            InodeEvent::ShareContent(event) => event.index = index,
+            InodeEvent::DeleteContent(event) => event.index = index,
+            _ => (),
+        }
+    }
 }
 
 // Add Content Event Structure
 #[derive(Clone, PartialEq, Debug)]
 pub struct AddContentInodeEvent {
     pub index: u32,
-    pub offset: u32,
-    pub len: u32,
+    pub offset: u64,
+    pub len: u64,
     pub size: u32,
     pub content: Vec<u8>,
+    pub compress_policy: CompressPolicy,
 }
 
 // Truncate Content Event Structure
 #[derive(Clone, PartialEq, Debug)]
 pub struct TruncateContentInodeEvent {
     pub index: u32,
-    pub offset: u32,
-    pub len: u32,
+    pub offset: u64,
+    pub len: u64,
     pub size: u32,
     pub o_size: u32,
     pub v_address: u32,
@@ -102,10 +128,32 @@ pub struct TruncateContentInodeEvent {
 #[derive(Clone, PartialEq, Debug)]
 pub struct ChangeContentInodeEvent {
     pub index: u32,
-    pub offset: u32,
+    pub offset: u64,
     pub v_address: u32,
 }
 
+// Split Content Event Structure, the new entry reuses pages of an old one
+#[derive(Clone, PartialEq, Debug)]
+pub struct SplitContentInodeEvent {
+    pub index: u32,
+    pub offset: u64,
+    pub len: u64,
+    pub size: u32,
+    pub v_address: u32,
+}
+
+// Share Content Event Structure, the new entry maps pages owned by another inode
+#[derive(Clone, PartialEq, Debug)]
+pub struct ShareContentInodeEvent {
+    pub index: u32,
+    pub offset: u64,
+    pub len: u64,
+    pub size: u32,
+    pub v_address: u32,
+    pub compress_type: CompressType,
+    pub compressed_len: u64,
+}
+
 // Delete Content Event Structure
 #[derive(Clone, PartialEq, Debug)]
 pub struct DeleteContentInodeEvent {
@@ -119,8 +167,38 @@ pub struct DeleteContentInodeEvent {
 pub struct ModifyInodeStatInodeEvent {
     pub file_type: inode::InodeFileType,
     pub ino: u32,
-    pub size: u32,
+    pub size: u64,
     pub uid: u32,
     pub gid: u32,
+    pub rdev: u32,
     pub n_link: u8,
+    pub mode: u16,
+    pub last_accessed: (i64, u32),
+    pub last_modified: (i64, u32),
+    pub last_metadata_changed: (i64, u32),
+}
+
+// Set Xattr Event Structure
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetXattrInodeEvent {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+// Remove Xattr Event Structure
+#[derive(Clone, PartialEq, Debug)]
+pub struct RemoveXattrInodeEvent {
+    pub name: String,
+}
+
+// Set Size Event Structure
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetSizeInodeEvent {
+    pub size: u64,
+}
+
+// Set Inline Data Event Structure
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetInlineDataInodeEvent {
+    pub data: Vec<u8>,
 }
\ No newline at end of file
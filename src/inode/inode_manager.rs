@@ -2,46 +2,83 @@
 // Inode Manager
 //
 
-use std::cell::RefCell;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
 use crate::core::core_manager;
 use crate::inode::inode::Inode;
 
-pub type CoreLink = Arc<RefCell<core_manager::CoreManager>>;
-pub type InodeLink = Arc<RefCell<Inode>>;
+pub type CoreLink = Arc<Mutex<core_manager::CoreManager>>;
+pub type InodeLink = Arc<RwLock<Inode>>;
+
+// Count of inodes kept in memory by default, referenced ones are never evicted so the cache may grow past it
+pub const INODE_CACHE_CAPACITY: usize = 1024;
+
+// Lock order is inode -> inode table -> core, so references are counted in the slots instead of the inodes
+// and an inode leaving the table is locked first, then checked to be still unreferenced under the table lock
+
+// Inode Cache Slot Structure
+struct InodeSlot {
+    ref_cnt: u32,
+    nlookup: u64, // references held by the kernel through lookup replies, part of ref_cnt
+    idle_since: u64,
+    link: InodeLink,
+}
+
+// Inode Cache Structure
+struct InodeCache {
+    capacity: usize,
+    tick: u64,
+    slots: HashMap<u32, InodeSlot>,
+    links: HashMap<usize, u32>, // inode address -> ino
+    idle: BTreeMap<u64, u32>,   // unreferenced inodes, least recently used first
+}
 
 // Inode Manager Structure
 pub struct InodeManager {
-    size: usize,
-    capacity: usize,
-    inode_buffer: Vec<InodeLink>,
-    lock: Mutex<bool>,
+    inode_cache: Mutex<InodeCache>,
     pub core_manager: CoreLink,
 }
 
 // Inode Manager Simple Interface Function
 impl InodeManager {
     pub fn new() -> InodeManager {
-        let mut buf = vec![];
-        for _ in 0..30 {
-            buf.push(Arc::new(RefCell::new(Inode::new())));
-        }
-        let capacity = 30;
         InodeManager {
-            size: 0,
-            capacity: capacity as usize,
-            core_manager: Arc::new(RefCell::new(core_manager::CoreManager::new())),
-            inode_buffer: buf,
-            lock: Mutex::new(false),
+            core_manager: Arc::new(Mutex::new(core_manager::CoreManager::new())),
+            inode_cache: Mutex::new(InodeCache {
+                capacity: INODE_CACHE_CAPACITY,
+                tick: 0,
+                slots: HashMap::new(),
+                links: HashMap::new(),
+                idle: BTreeMap::new(),
+            }),
         }
     }
 
     pub fn get_size(&self) -> u32 {
-        self.size as u32
+        self.inode_cache.lock().unwrap().slots.len() as u32
     }
 
     pub fn get_capacity(&self) -> u32 {
-        self.capacity as u32
+        self.inode_cache.lock().unwrap().capacity as u32
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.inode_cache.lock().unwrap().capacity = capacity;
+        self.evict();
+    }
+
+    pub fn get_ref_cnt(&self, ino: u32) -> u32 {
+        match self.inode_cache.lock().unwrap().slots.get(&ino) {
+            Some(slot) => slot.ref_cnt,
+            None => 0,
+        }
+    }
+
+    pub fn get_lookup_cnt(&self, ino: u32) -> u64 {
+        match self.inode_cache.lock().unwrap().slots.get(&ino) {
+            Some(slot) => slot.nlookup,
+            None => 0,
+        }
     }
 }
 
@@ -50,88 +87,433 @@ impl InodeManager {
     /// Allocate an inode on device dev.
     /// Mark it as allocated by giving it type type.
     /// Returns an unlocked but allocated and referenced inode.
-    pub fn i_alloc(&mut self) -> Option<InodeLink> {
-        let mut empty_index = -1;
-        let _ = self.lock.lock();
-        for (index, ip) in self.inode_buffer.iter().enumerate() {
-            if empty_index == -1 && ip.borrow().ref_cnt == 0 {
-                empty_index = index as i32;
-            }
-        }
-        if empty_index == -1 {
-            panic!("InodeManager: alloc no spare cache to store");
-        }
-        let mut inode = self.core_manager.borrow_mut().allocate_inode();
-        inode.ref_cnt = 1;
-        let link = Arc::new(RefCell::new(inode));
-        link.borrow_mut().core = Some(Arc::clone(&self.core_manager));
-        self.inode_buffer[empty_index as usize] = Arc::clone(&link);
+    pub fn i_alloc(&self) -> Option<InodeLink> {
+        let mut cache = self.inode_cache.lock().unwrap();
+        let mut inode = self.core_manager.lock().unwrap().allocate_inode()?;
+        inode.core = Some(Arc::clone(&self.core_manager));
+        let link = self.insert(&mut cache, inode);
+        drop(cache);
+        self.evict();
         Some(link)
     }
 
     /// Find the inode with number ino on device dev
     /// and return the in-memory copy.
-    pub fn i_get(&mut self, ino: u32) -> Option<InodeLink> {
-        let mut empty_index = -1;
-        let _ = self.lock.lock();
-        for (index, ip) in self.inode_buffer.iter().enumerate() {
-            if ip.borrow().ref_cnt > 0 && ip.borrow().ino == ino {
-                ip.borrow_mut().ref_cnt += 1;
-                return Some(Arc::clone(ip));
-            }
-            if empty_index == -1 && ip.borrow().ref_cnt == 0 {
-                empty_index = index as i32;
-            }
+    pub fn i_get(&self, ino: u32) -> Option<InodeLink> {
+        let mut cache = self.inode_cache.lock().unwrap();
+        if cache.slots.contains_key(&ino) {
+            self.hold(&mut cache, ino, 0);
+            return Some(Arc::clone(&cache.slots[&ino].link));
         }
-        if empty_index == -1 {
-            panic!("InodeManager: get no spare cache to store");
-        }
-        let mut inode = self.core_manager.borrow_mut().get_inode(ino);
-        inode.ref_cnt = 1;
-        let link = Arc::new(RefCell::new(inode));
-        link.borrow_mut().core = Some(Arc::clone(&self.core_manager));
-        self.inode_buffer[empty_index as usize] = Arc::clone(&link);
+        let mut inode = self.core_manager.lock().unwrap().get_inode(ino);
+        inode.core = Some(Arc::clone(&self.core_manager));
+        let link = self.insert(&mut cache, inode);
+        drop(cache);
+        self.evict();
         Some(link)
     }
 
     /// Increment reference count for ip.
-    pub fn i_dup(&mut self, inode: &InodeLink) -> InodeLink {
-        let _ = self.lock.lock();
-        inode.borrow_mut().ref_cnt += 1;
+    pub fn i_dup(&self, inode: &InodeLink) -> InodeLink {
+        let mut cache = self.inode_cache.lock().unwrap();
+        if let Some(ino) = cache.links.get(&(Arc::as_ptr(inode) as usize)).copied() {
+            self.hold(&mut cache, ino, 0);
+        }
         Arc::clone(inode)
     }
 
+    /// Count a reference the kernel keeps after a lookup reply
+    /// params:
+    /// inode - inode replied to the kernel
+    /// return:
+    /// ()
+    pub fn i_lookup(&self, inode: &InodeLink) {
+        let mut cache = self.inode_cache.lock().unwrap();
+        if let Some(ino) = cache.links.get(&(Arc::as_ptr(inode) as usize)).copied() {
+            self.hold(&mut cache, ino, 1);
+        }
+    }
+
+    /// Drop references the kernel kept after lookup replies
+    /// params:
+    /// ino - inode forgotten by the kernel
+    /// nlookup - count of lookups forgotten
+    /// return:
+    /// ()
+    pub fn i_forget(&self, ino: u32, nlookup: u64) {
+        let mut cache = self.inode_cache.lock().unwrap();
+        let (count, link) = match cache.slots.get_mut(&ino) {
+            Some(slot) => {
+                // the kernel never forgets more than it looked up, unless the root or another mount is involved
+                let count = nlookup.min(slot.nlookup);
+                slot.nlookup -= count;
+                (count, Arc::clone(&slot.link))
+            },
+            None => return,
+        };
+        let idle = self.release(&mut cache, ino, count as u32);
+        drop(cache);
+        if idle {
+            self.retire(&link, true);
+            self.evict();
+        }
+    }
+
     /// Drop a reference to an in-memory inode.
     /// If that was the last reference, the inode cache entry can
     /// be recycled.
-    pub fn i_put(&mut self, inode: InodeLink) {
-        let _ = self.lock.lock();
-        if inode.borrow().ref_cnt > 0 {
-            inode.borrow_mut().ref_cnt -= 1;
+    pub fn i_put(&self, inode: InodeLink) {
+        let mut cache = self.inode_cache.lock().unwrap();
+        let ino = match cache.links.get(&(Arc::as_ptr(&inode) as usize)) {
+            Some(ino) => *ino,
+            None => return,
+        };
+        let idle = self.release(&mut cache, ino, 1);
+        drop(cache);
+        if idle {
+            self.retire(&inode, true);
+            self.evict();
+        }
+    }
+
+    /// Write back every cached inode changed only in memory
+    /// params:
+    /// ()
+    /// return:
+    /// ()
+    pub fn sync(&self) {
+        let links = self.inode_cache.lock().unwrap().slots.values().map(|slot| Arc::clone(&slot.link)).collect::<Vec<InodeLink>>();
+        for link in links {
+            link.write().unwrap().sync();
+        }
+    }
+}
+
+// Inode Manager Internal Function
+impl InodeManager {
+    fn insert(&self, cache: &mut InodeCache, inode: Inode) -> InodeLink {
+        let ino = inode.ino;
+        let link = Arc::new(RwLock::new(inode));
+        cache.links.insert(Arc::as_ptr(&link) as usize, ino);
+        cache.slots.insert(ino, InodeSlot {
+            ref_cnt: 1,
+            nlookup: 0,
+            idle_since: 0,
+            link: Arc::clone(&link),
+        });
+        link
+    }
+
+    fn hold(&self, cache: &mut InodeCache, ino: u32, nlookup: u64) {
+        let slot = cache.slots.get_mut(&ino).unwrap();
+        slot.ref_cnt += 1;
+        slot.nlookup += nlookup;
+        if slot.ref_cnt == 1 {
+            let idle_since = slot.idle_since;
+            cache.idle.remove(&idle_since);
+        }
+    }
+
+    // Drop count references, true if the inode became unreferenced
+    fn release(&self, cache: &mut InodeCache, ino: u32, count: u32) -> bool {
+        let slot = cache.slots.get_mut(&ino).unwrap();
+        if slot.ref_cnt == 0 || count == 0 {
+            return false;
+        }
+        slot.ref_cnt -= count.min(slot.ref_cnt);
+        if slot.ref_cnt > 0 {
+            return false;
+        }
+        cache.tick += 1;
+        let tick = cache.tick;
+        cache.slots.get_mut(&ino).unwrap().idle_since = tick;
+        cache.idle.insert(tick, ino);
+        true
+    }
+
+    fn evict(&self) {
+        loop {
+            let cache = self.inode_cache.lock().unwrap();
+            if cache.slots.len() <= cache.capacity {
+                return;
+            }
+            let link = match cache.idle.iter().next() {
+                Some((_, ino)) => Arc::clone(&cache.slots[ino].link),
+                None => return,
+            };
+            drop(cache);
+            self.retire(&link, false);
+        }
+    }
+
+    // Take an unreferenced inode out of the table, only if it has no links left when unlinked_only is set
+    fn retire(&self, link: &InodeLink, unlinked_only: bool) {
+        let mut inode = link.write().unwrap();
+        if unlinked_only && inode.n_link != 0 {
+            return;
         }
+        let mut cache = self.inode_cache.lock().unwrap();
+        let ino = match cache.links.get(&(Arc::as_ptr(link) as usize)) {
+            Some(ino) => *ino,
+            None => return,
+        };
+        // referenced again while the inode lock was taken
+        if cache.slots[&ino].ref_cnt > 0 {
+            return;
+        }
+        let slot = cache.slots.remove(&ino).unwrap();
+        cache.idle.remove(&slot.idle_since);
+        cache.links.remove(&(Arc::as_ptr(link) as usize));
+        // an inode without links is freed with its last reference, whether it is an orphan
+        // unlinked while in use or was allocated and never linked,
+        // inodes deleted by hand are already gone from the kv region
+        if inode.n_link == 0 && self.core_manager.lock().unwrap().has_inode(ino) {
+            inode.delete();
+        }
+        inode.sync();
+        self.core_manager.lock().unwrap().put_inode(&inode);
     }
 }
 
 // Inode Manager Module Test
 #[cfg(test)]
 mod test {
+    use std::thread;
     use super::*;
 
     #[test]
     fn basics() {
-        let mut manager = InodeManager::new();
-        manager.core_manager.borrow_mut().mount();
+        let manager = InodeManager::new();
+        manager.core_manager.lock().unwrap().mount();
         let link = manager.i_alloc();
-        assert_eq!(link.unwrap().borrow().ino, 1);
+        assert_eq!(link.unwrap().read().unwrap().ino, 1);
         let link = manager.i_alloc();
-        assert_eq!(link.unwrap().borrow().ino, 2);
+        assert_eq!(link.unwrap().read().unwrap().ino, 2);
         let link = manager.i_get(2);
-        assert_eq!(link.as_ref().unwrap().borrow().ino, 2);
-        assert_eq!(link.as_ref().unwrap().borrow().ref_cnt, 2);
+        assert_eq!(link.as_ref().unwrap().read().unwrap().ino, 2);
+        assert_eq!(manager.get_ref_cnt(2), 2);
         let link = manager.i_dup(link.as_ref().unwrap());
-        assert_eq!(link.as_ref().borrow().ref_cnt, 3);
+        assert_eq!(manager.get_ref_cnt(2), 3);
         manager.i_put(link);
         let link = manager.i_get(2);
-        assert_eq!(link.as_ref().unwrap().borrow().ref_cnt, 3);
+        assert_eq!(manager.get_ref_cnt(2), 3);
+        manager.i_put(link.unwrap());
+        assert_eq!(manager.get_ref_cnt(2), 2);
+    }
+
+    #[test]
+    fn eviction() {
+        let manager = InodeManager::new();
+        manager.core_manager.lock().unwrap().mount();
+        manager.set_capacity(4);
+        // referenced inodes are never evicted, the cache grows past its capacity instead
+        let links = (0..10).map(|_| {
+            let link = manager.i_alloc().unwrap();
+            let mut stat = link.read().unwrap().get_stat();
+            stat.n_link = 1;
+            link.write().unwrap().modify_stat(stat);
+            link
+        }).collect::<Vec<InodeLink>>();
+        assert_eq!(manager.get_size(), 10);
+        assert!(links[0].write().unwrap().write(0, 5000, &vec![3; 5000]));
+        let inos = links.iter().map(|link| link.read().unwrap().ino).collect::<Vec<u32>>();
+        let kept = links.iter().map(Arc::clone).collect::<Vec<InodeLink>>();
+        for link in links {
+            manager.i_put(link);
+        }
+        assert_eq!(manager.get_size(), 4);
+        assert_eq!(manager.get_ref_cnt(inos[9]), 0);
+
+        // recently used inodes come from the cache, evicted ones are read again
+        let link = manager.i_get(inos[9]).unwrap();
+        assert!(Arc::ptr_eq(&link, &kept[9]));
+        manager.i_put(link);
+        let link = manager.i_get(inos[0]).unwrap();
+        assert!(!Arc::ptr_eq(&link, &kept[0]));
+        let mut buf = vec![];
+        assert_eq!(link.write().unwrap().read_all(&mut buf), 5000);
+        assert_eq!(buf, vec![3; 5000]);
+
+        // dirty inodes are written back on eviction
+        link.write().unwrap().mode = 0o600;
+        link.write().unwrap().dirty = true;
+        manager.i_put(link);
+        for ino in inos[5..9].iter() {
+            let link = manager.i_get(*ino).unwrap();
+            manager.i_put(link);
+        }
+        let link = manager.i_get(inos[0]).unwrap();
+        assert_eq!(link.read().unwrap().mode, 0o600);
+        assert!(!link.read().unwrap().dirty);
+
+        // deleted inodes leave the cache with their last reference
+        let size = manager.get_size();
+        link.write().unwrap().delete();
+        manager.i_put(link);
+        assert_eq!(manager.get_size(), size - 1);
+        let link = manager.i_get(inos[1]).unwrap();
+        manager.set_capacity(0);
+        assert_eq!(manager.get_size(), 1);
+        manager.i_put(link);
+        assert_eq!(manager.get_size(), 0);
+    }
+
+    #[test]
+    fn orphan() {
+        let manager = InodeManager::new();
+        manager.core_manager.lock().unwrap().mount();
+        let link = manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        let mut stat = link.read().unwrap().get_stat();
+        stat.n_link = 1;
+        link.write().unwrap().modify_stat(stat);
+        assert!(link.write().unwrap().write(0, 5000, &vec![5; 5000]));
+        // one lookup by the kernel and one open handle
+        manager.i_lookup(&link);
+        let handle = manager.i_dup(&link);
+        assert_eq!(manager.get_lookup_cnt(ino), 1);
+        let ffree = manager.core_manager.lock().unwrap().statfs().ffree;
+
+        // unlinked while open, the data stays until the last reference goes
+        let mut stat = link.read().unwrap().get_stat();
+        stat.n_link = 0;
+        link.write().unwrap().modify_stat(stat);
+        manager.core_manager.lock().unwrap().add_orphan_inode(ino);
+        manager.i_put(link);
+        manager.i_forget(ino, 5);
+        assert_eq!(manager.get_lookup_cnt(ino), 0);
+        assert_eq!(manager.get_ref_cnt(ino), 1);
+        let mut buf = vec![];
+        assert_eq!(handle.write().unwrap().read_all(&mut buf), 5000);
+        assert_eq!(buf, vec![5; 5000]);
+        assert!(manager.core_manager.lock().unwrap().is_orphan_inode(ino));
+        manager.i_put(handle);
+        assert_eq!(manager.get_size(), 0);
+        assert!(!manager.core_manager.lock().unwrap().is_orphan_inode(ino));
+        assert_eq!(manager.core_manager.lock().unwrap().statfs().ffree, ffree + 1);
+
+        // inodes allocated and never linked are freed with their last reference as well
+        let link = manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        assert!(link.write().unwrap().write(0, 5000, &vec![6; 5000]));
+        manager.i_put(link);
+        assert_eq!(manager.get_size(), 0);
+        assert!(!manager.core_manager.lock().unwrap().has_inode(ino));
+        assert_eq!(manager.core_manager.lock().unwrap().statfs().ffree, ffree + 1);
+
+        // orphans still referenced can be freed at once by the core
+        let link = manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        assert!(link.write().unwrap().write(0, 5000, &vec![6; 5000]));
+        manager.core_manager.lock().unwrap().add_orphan_inode(ino);
+        let mut core = manager.core_manager.lock().unwrap();
+        assert_eq!(core.clean_orphans(), 1);
+        assert!(!core.is_orphan_inode(ino));
+        assert_eq!(core.statfs().ffree, ffree + 1);
+        assert_eq!(core.clean_orphans(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn concurrent() {
+        let manager = Arc::new(InodeManager::new());
+        manager.core_manager.lock().unwrap().mount();
+        let shared = manager.i_alloc().unwrap();
+        let shared_ino = shared.read().unwrap().ino;
+        // every write syncs BIT and PIT, so threads write a little and mostly race on reads and references
+        let handles = (0..8u8).map(|index| {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || {
+                let own = manager.i_alloc().unwrap();
+                for round in 0..2u64 {
+                    let data = vec![index * 2 + round as u8; 5000];
+                    assert!(own.write().unwrap().write(round * 5000, 5000, &data));
+                    let link = manager.i_get(shared_ino).unwrap();
+                    let offset = (round * 8 + index as u64) * 4096;
+                    assert!(link.write().unwrap().write(offset, 4096, &vec![index; 4096]));
+                    manager.i_put(link);
+                }
+                let ino = own.read().unwrap().ino;
+                for _ in 0..50 {
+                    let link = manager.i_get(ino).unwrap();
+                    let mut buf = vec![];
+                    assert_eq!(link.write().unwrap().read_all(&mut buf), 10000);
+                    for (round, chunk) in buf.chunks(5000).enumerate() {
+                        assert!(chunk.iter().all(|byte| *byte == index * 2 + round as u8));
+                    }
+                    manager.i_put(link);
+                    let link = manager.i_get(shared_ino).unwrap();
+                    let mut buf = vec![];
+                    link.write().unwrap().read(index as u64 * 4096, 4096, &mut buf);
+                    assert_eq!(buf, vec![index; 4096]);
+                    manager.i_put(link);
+                }
+                manager.i_put(own);
+                ino
+            })
+        }).collect::<Vec<_>>();
+        let inos = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<u32>>();
+        let mut buf = vec![];
+        assert_eq!(shared.write().unwrap().read_all(&mut buf), 16 * 4096);
+        for (page, chunk) in buf.chunks(4096).enumerate() {
+            assert!(chunk.iter().all(|byte| *byte == (page % 8) as u8));
+        }
+        assert_eq!(manager.get_ref_cnt(shared_ino), 1);
+        for ino in inos {
+            assert_eq!(manager.get_ref_cnt(ino), 0);
+        }
+    }
+
+    #[test]
+    fn concurrent_refs() {
+        let manager = Arc::new(InodeManager::new());
+        manager.core_manager.lock().unwrap().mount();
+        let link = manager.i_alloc().unwrap();
+        let ino = link.read().unwrap().ino;
+        let handles = (0..8).map(|_| {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    let link = manager.i_get(ino).unwrap();
+                    let dup = manager.i_dup(&link);
+                    manager.i_put(dup);
+                    manager.i_put(link);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(manager.get_ref_cnt(ino), 1);
+        manager.i_put(link);
+        assert_eq!(manager.get_ref_cnt(ino), 0);
+    }
+
+    #[test]
+    fn lock_order() {
+        let manager = Arc::new(InodeManager::new());
+        manager.core_manager.lock().unwrap().mount();
+        let links = (0..2).map(|_| {
+            let link = manager.i_alloc().unwrap();
+            let mut stat = link.read().unwrap().get_stat();
+            stat.n_link = 1;
+            link.write().unwrap().modify_stat(stat);
+            link
+        }).collect::<Vec<InodeLink>>();
+        let ino = links[1].read().unwrap().ino;
+        let kept = Arc::clone(&links[0]);
+        for link in links {
+            manager.i_put(link);
+        }
+        // eviction waits on the locked inode without holding the table
+        let guard = kept.write().unwrap();
+        let handle = {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || manager.set_capacity(0))
+        };
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(manager.get_ref_cnt(ino), 0);
+        drop(guard);
+        handle.join().unwrap();
+        assert_eq!(manager.get_size(), 0);
+    }
+}
@@ -1,19 +1,47 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::kv::raw_inode;
+use crate::util::array;
+
+// xattr values above this size are kept out of the inode record
+pub const XATTR_INLINE_SIZE: usize = 256;
+// inode numbers run from 1 to this limit, freed numbers are handed out again
+pub const MAX_INODE_NUM: u32 = 65536;
 
 pub struct FakeKV {
     pub next_ino: u32,
+    pub free_inos: Vec<u32>,
     pub map: HashMap<u32, raw_inode::RawInode>,
+    pub xattr_map: HashMap<(u32, String), Vec<u8>>,
+    pub frozen: HashSet<u32>,
+    pub orphans: HashSet<u32>, // unlinked inodes still in use, freed with their last reference
+    pub fingerprints: HashMap<u64, Vec<u32>>, // page fingerprint -> physical pages holding that content
+    pub page_fingerprints: HashMap<u32, u64>,  // physical page -> fingerprint
+    pub sync: bool, // true 需要持久化到磁盘中
 }
 
 impl FakeKV {
     pub fn new() -> FakeKV {
         FakeKV {
             next_ino: 1,
+            free_inos: vec![],
             map: HashMap::new(),
+            xattr_map: HashMap::new(),
+            frozen: HashSet::new(),
+            orphans: HashSet::new(),
+            fingerprints: HashMap::new(),
+            page_fingerprints: HashMap::new(),
+            sync: false,
         }
     }
 
+    pub fn need_sync(&self) -> bool {
+        self.sync
+    }
+
+    pub fn sync(&mut self) {
+        self.sync = false;
+    }
+
     pub fn get_inode(&self, ino: u32) -> raw_inode::RawInode {
         if !self.map.contains_key(&ino) {
             panic!("FakeKV: get no that inode");
@@ -23,9 +51,16 @@ impl FakeKV {
         for entry in raw_inode.data.iter() {
             data.push(*entry);
         }
+        let mut xattr = vec![];
+        for (name, value) in raw_inode.xattr.iter() {
+            let value = self.xattr_map.get(&(ino, name.clone())).unwrap_or(value);
+            xattr.push((name.clone(), value.clone()));
+        }
         raw_inode::RawInode {
             ino,
             data,
+            inline_data: raw_inode.inline_data.clone(),
+            xattr,
             uid: raw_inode.uid,
             gid: raw_inode.gid,
             size: raw_inode.size,
@@ -33,6 +68,7 @@ impl FakeKV {
             ref_cnt: raw_inode.ref_cnt,
             file_type: raw_inode.file_type,
             mode: raw_inode.mode,
+            rdev: raw_inode.rdev,
             last_accessed: raw_inode.last_accessed,
             last_modified: raw_inode.last_modified,
             last_metadata_changed: raw_inode.last_metadata_changed,
@@ -44,68 +80,457 @@ impl FakeKV {
         if !self.map.contains_key(&ino) {
             panic!("FakeKV: update no that inode");
         }
+        self.remove_xattr_values(ino);
+        let mut inode = inode;
+        for (name, value) in inode.xattr.iter_mut() {
+            if value.len() > XATTR_INLINE_SIZE {
+                self.xattr_map.insert((ino, name.clone()), std::mem::take(value));
+            }
+        }
         *self.map.get_mut(&ino).unwrap() = inode;
+        self.sync = true;
+    }
+
+    pub fn has_inode(&self, ino: u32) -> bool {
+        self.map.contains_key(&ino)
     }
 
     pub fn delete_inode(&mut self, ino: u32) {
         if !self.map.contains_key(&ino) {
             panic!("FakeKV: delete no that inode");
         }
+        self.remove_xattr_values(ino);
         self.map.remove(&ino);
+        self.frozen.remove(&ino);
+        self.orphans.remove(&ino);
+        self.free_inos.push(ino);
+        self.sync = true;
     }
 
-    pub fn allocate_inode(&mut self) -> raw_inode::RawInode {
-        let ino = self.next_ino;
-        self.next_ino += 1;
-        let raw_inode = raw_inode::RawInode {
-            ino,
-            uid: 0,
-            gid: 0,
-            size: 0,
-            n_link: 1,
-            ref_cnt: 0,
-            file_type: 0,
-            data: vec![],
-            mode: 0,
-            last_accessed: (0, 0),
-            last_modified: (0, 0),
-            last_metadata_changed: (0, 0),
+    pub fn freeze_inode(&mut self, ino: u32) {
+        if !self.map.contains_key(&ino) {
+            panic!("FakeKV: freeze no that inode");
+        }
+        self.frozen.insert(ino);
+        self.sync = true;
+    }
+
+    pub fn is_frozen(&self, ino: u32) -> bool {
+        self.frozen.contains(&ino)
+    }
+
+    pub fn add_orphan(&mut self, ino: u32) {
+        if !self.map.contains_key(&ino) {
+            panic!("FakeKV: orphan no that inode");
+        }
+        self.orphans.insert(ino);
+        self.sync = true;
+    }
+
+    pub fn is_orphan(&self, ino: u32) -> bool {
+        self.orphans.contains(&ino)
+    }
+
+    pub fn get_orphans(&self) -> Vec<u32> {
+        let mut orphans = self.orphans.iter().copied().collect::<Vec<u32>>();
+        orphans.sort();
+        orphans
+    }
+
+    pub fn get_fingerprint_pages(&self, fingerprint: u64) -> Vec<u32> {
+        match self.fingerprints.get(&fingerprint) {
+            Some(addresses) => addresses.clone(),
+            None => vec![],
+        }
+    }
+
+    pub fn insert_fingerprint(&mut self, fingerprint: u64, address: u32) {
+        self.remove_fingerprint(address);
+        self.fingerprints.entry(fingerprint).or_insert_with(Vec::new).push(address);
+        self.page_fingerprints.insert(address, fingerprint);
+        self.sync = true;
+    }
+
+    pub fn remove_fingerprint(&mut self, address: u32) {
+        let fingerprint = match self.page_fingerprints.remove(&address) {
+            Some(fingerprint) => fingerprint,
+            None => return,
+        };
+        let addresses = self.fingerprints.get_mut(&fingerprint).unwrap();
+        addresses.retain(|page| *page != address);
+        if addresses.is_empty() {
+            self.fingerprints.remove(&fingerprint);
+        }
+        self.sync = true;
+    }
+
+    pub fn move_fingerprint(&mut self, o_address: u32, d_address: u32) {
+        if let Some(fingerprint) = self.page_fingerprints.get(&o_address).copied() {
+            self.remove_fingerprint(o_address);
+            self.insert_fingerprint(fingerprint, d_address);
+        }
+    }
+
+    fn remove_xattr_values(&mut self, ino: u32) {
+        let raw_inode = self.map.get(&ino).unwrap();
+        for (name, _) in raw_inode.xattr.iter() {
+            self.xattr_map.remove(&(ino, name.clone()));
+        }
+    }
+
+    pub fn get_inode_num(&self) -> u32 {
+        self.map.len() as u32
+    }
+
+    pub fn get_free_inode_num(&self) -> u32 {
+        MAX_INODE_NUM - self.get_inode_num()
+    }
+
+    pub fn allocate_inode(&mut self) -> Option<raw_inode::RawInode> {
+        let ino = match self.free_inos.pop() {
+            Some(ino) => ino,
+            None if self.next_ino <= MAX_INODE_NUM => {
+                let ino = self.next_ino;
+                self.next_ino += 1;
+                ino
+            },
+            None => return None,
         };
-        self.map.insert(ino, raw_inode);
-        let raw_inode = raw_inode::RawInode {
+        self.map.insert(ino, FakeKV::empty_inode(ino));
+        self.sync = true;
+        Some(FakeKV::empty_inode(ino))
+    }
+
+    /// Encode every record into a kv region image
+    /// params:
+    /// ()
+    /// return:
+    /// kv region data
+    pub fn encode(&self) -> array::Array1<u8> {
+        let mut bytes = vec![0x66, 0x66, 0xdd, 0xdd];
+        bytes.extend_from_slice(&self.next_ino.to_be_bytes());
+        FakeKV::encode_inos(&mut bytes, self.free_inos.iter().copied());
+        let mut inos = self.map.keys().copied().collect::<Vec<u32>>();
+        inos.sort();
+        bytes.extend_from_slice(&(inos.len() as u32).to_be_bytes());
+        for ino in inos {
+            FakeKV::encode_inode(&mut bytes, &self.get_inode(ino));
+        }
+        let mut frozen = self.frozen.iter().copied().collect::<Vec<u32>>();
+        frozen.sort();
+        FakeKV::encode_inos(&mut bytes, frozen.into_iter());
+        FakeKV::encode_inos(&mut bytes, self.get_orphans().into_iter());
+        let mut pages = self.page_fingerprints.iter().map(|(address, fingerprint)| (*address, *fingerprint)).collect::<Vec<(u32, u64)>>();
+        pages.sort();
+        bytes.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        for (address, fingerprint) in pages {
+            bytes.extend_from_slice(&address.to_be_bytes());
+            bytes.extend_from_slice(&fingerprint.to_be_bytes());
+        }
+        if bytes.len() > 128 * 4096 {
+            panic!("FakeKV: encode records exceed kv region");
+        }
+        let mut data = array::Array1::<u8>::new(128 * 4096);
+        data.init(0);
+        for (index, byte) in bytes.into_iter().enumerate() {
+            data.set(index as u32, byte);
+        }
+        data
+    }
+
+    /// Rebuild every record from a kv region image
+    /// params:
+    /// data - kv region data
+    /// return:
+    /// false if the region holds no image
+    pub fn build(&mut self, data: &array::Array1<[u8; 4096]>) -> bool {
+        let page = data.get(0);
+        if page[0] != 0x66 || page[1] != 0x66 || page[2] != 0xdd || page[3] != 0xdd {
+            return false;
+        }
+        let mut reader = ImageReader { data, index: 4 };
+        *self = FakeKV::new();
+        self.next_ino = reader.u32();
+        self.free_inos = reader.inos();
+        for _ in 0..reader.u32() {
+            let inode = reader.inode();
+            self.map.insert(inode.ino, FakeKV::empty_inode(inode.ino));
+            self.update_inode(inode);
+        }
+        self.frozen = reader.inos().into_iter().collect();
+        self.orphans = reader.inos().into_iter().collect();
+        for _ in 0..reader.u32() {
+            let address = reader.u32();
+            let fingerprint = reader.u64();
+            self.insert_fingerprint(fingerprint, address);
+        }
+        self.sync = false;
+        true
+    }
+}
+
+// FakeKV Internal Function
+impl FakeKV {
+    fn empty_inode(ino: u32) -> raw_inode::RawInode {
+        raw_inode::RawInode {
             ino,
             uid: 0,
             gid: 0,
             size: 0,
-            n_link: 1,
+            n_link: 0,
             ref_cnt: 0,
             file_type: 0,
             data: vec![],
+            inline_data: vec![],
+            xattr: vec![],
             mode: 0,
+            rdev: 0,
             last_accessed: (0, 0),
             last_modified: (0, 0),
             last_metadata_changed: (0, 0),
-        };
-        raw_inode
+        }
+    }
+
+    fn encode_inos(bytes: &mut Vec<u8>, inos: impl ExactSizeIterator<Item = u32>) {
+        bytes.extend_from_slice(&(inos.len() as u32).to_be_bytes());
+        for ino in inos {
+            bytes.extend_from_slice(&ino.to_be_bytes());
+        }
+    }
+
+    fn encode_inode(bytes: &mut Vec<u8>, inode: &raw_inode::RawInode) {
+        bytes.extend_from_slice(&inode.ino.to_be_bytes());
+        bytes.extend_from_slice(&inode.uid.to_be_bytes());
+        bytes.extend_from_slice(&inode.gid.to_be_bytes());
+        bytes.extend_from_slice(&inode.size.to_be_bytes());
+        bytes.extend_from_slice(&[inode.n_link, inode.ref_cnt, inode.file_type]);
+        bytes.extend_from_slice(&inode.mode.to_be_bytes());
+        bytes.extend_from_slice(&inode.rdev.to_be_bytes());
+        for time in [inode.last_accessed, inode.last_modified, inode.last_metadata_changed] {
+            bytes.extend_from_slice(&time.0.to_be_bytes());
+            bytes.extend_from_slice(&time.1.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(inode.data.len() as u32).to_be_bytes());
+        for entry in inode.data.iter() {
+            bytes.extend_from_slice(&entry.len.to_be_bytes());
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+            bytes.extend_from_slice(&entry.offset.to_be_bytes());
+            bytes.extend_from_slice(&entry.address.to_be_bytes());
+            bytes.push(entry.compress_type);
+            bytes.extend_from_slice(&entry.compressed_len.to_be_bytes());
+            bytes.push(entry.encrypted as u8);
+            bytes.extend_from_slice(&entry.crypt_nonce);
+            bytes.extend_from_slice(&entry.crypt_tag);
+        }
+        bytes.extend_from_slice(&(inode.inline_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&inode.inline_data);
+        bytes.extend_from_slice(&(inode.xattr.len() as u32).to_be_bytes());
+        for (name, value) in inode.xattr.iter() {
+            bytes.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(value);
+        }
+    }
+}
+
+// Reads the fields of a kv region image in order
+struct ImageReader<'a> {
+    data: &'a array::Array1<[u8; 4096]>,
+    index: u32,
+}
+
+impl ImageReader<'_> {
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.data.get(self.index / 4096)[(self.index % 4096) as usize];
+            self.index += 1;
+        }
+        bytes
+    }
+
+    fn vec(&mut self) -> Vec<u8> {
+        let len = self.u32();
+        (0..len).map(|_| self.bytes::<1>()[0]).collect()
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.bytes())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.bytes())
+    }
+
+    fn time(&mut self) -> (i64, u32) {
+        (i64::from_be_bytes(self.bytes()), self.u32())
+    }
+
+    fn inos(&mut self) -> Vec<u32> {
+        let len = self.u32();
+        (0..len).map(|_| self.u32()).collect()
+    }
+
+    fn inode(&mut self) -> raw_inode::RawInode {
+        let mut inode = FakeKV::empty_inode(self.u32());
+        inode.uid = self.u32();
+        inode.gid = self.u32();
+        inode.size = self.u64();
+        [inode.n_link, inode.ref_cnt, inode.file_type] = self.bytes();
+        inode.mode = u16::from_be_bytes(self.bytes());
+        inode.rdev = self.u32();
+        inode.last_accessed = self.time();
+        inode.last_modified = self.time();
+        inode.last_metadata_changed = self.time();
+        for _ in 0..self.u32() {
+            inode.data.push(raw_inode::RawEntry {
+                len: self.u64(),
+                size: self.u32(),
+                offset: self.u64(),
+                address: self.u32(),
+                compress_type: self.bytes::<1>()[0],
+                compressed_len: self.u64(),
+                encrypted: self.bytes::<1>()[0] != 0,
+                crypt_nonce: self.bytes(),
+                crypt_tag: self.bytes(),
+            });
+        }
+        inode.inline_data = self.vec();
+        for _ in 0..self.u32() {
+            let name = String::from_utf8(self.vec()).unwrap();
+            let value = self.vec();
+            inode.xattr.push((name, value));
+        }
+        inode
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::core::core_manager::CoreManager;
 
     #[test]
     fn basics() {
         let mut kv = FakeKV::new();
         
-        let mut inode = kv.allocate_inode();
+        let mut inode = kv.allocate_inode().unwrap();
         let ino = inode.ino;
         inode.gid = 100;
         inode.file_type = 1;
         kv.update_inode(inode);
 
-        let inode = kv.get_inode(ino);
+        let mut inode = kv.get_inode(ino);
         assert_eq!(inode.gid, 100);
         assert_eq!(inode.file_type, 1);
+
+        inode.xattr.push(("user.a".to_string(), vec![1; 10]));
+        inode.xattr.push(("user.b".to_string(), vec![2; XATTR_INLINE_SIZE + 1]));
+        kv.update_inode(inode);
+        assert_eq!(kv.map.get(&ino).unwrap().xattr[1].1.len(), 0);
+        assert_eq!(kv.xattr_map.len(), 1);
+        let mut inode = kv.get_inode(ino);
+        assert_eq!(inode.xattr[0].1, vec![1; 10]);
+        assert_eq!(inode.xattr[1].1, vec![2; XATTR_INLINE_SIZE + 1]);
+        inode.xattr.pop();
+        kv.update_inode(inode);
+        assert_eq!(kv.xattr_map.len(), 0);
+        kv.freeze_inode(ino);
+        assert!(kv.is_frozen(ino));
+        kv.delete_inode(ino);
+        assert_eq!(kv.map.len(), 0);
+        assert!(!kv.is_frozen(ino));
+    }
+
+    #[test]
+    fn encode() {
+        let mut kv = FakeKV::new();
+        let mut data = array::Array1::new(128);
+        data.init([0; 4096]);
+        assert!(!kv.build(&data));
+        let mut inode = kv.allocate_inode().unwrap();
+        let ino = inode.ino;
+        inode.size = 5000;
+        inode.mode = 0o644;
+        inode.last_modified = (-3, 7);
+        inode.inline_data = vec![1, 2, 3];
+        inode.xattr.push(("user.a".to_string(), vec![1; 10]));
+        inode.xattr.push(("user.b".to_string(), vec![2; XATTR_INLINE_SIZE + 1]));
+        inode.data.push(raw_inode::RawEntry {
+            len: 4096,
+            size: 1,
+            offset: 0,
+            address: 42,
+            compress_type: 1,
+            compressed_len: 100,
+            encrypted: true,
+            crypt_nonce: [3; 12],
+            crypt_tag: [4; 16],
+        });
+        kv.update_inode(inode);
+        let other = kv.allocate_inode().unwrap().ino;
+        kv.allocate_inode().unwrap();
+        kv.delete_inode(other);
+        kv.freeze_inode(ino);
+        kv.add_orphan(ino);
+        kv.insert_fingerprint(7, 42);
+        kv.insert_fingerprint(7, 43);
+        assert!(kv.need_sync());
+
+        let mut other = FakeKV::new();
+        assert!(other.build(&CoreManager::transfer(&kv.encode())));
+        assert!(!other.need_sync());
+        assert_eq!(other.get_inode_num(), 2);
+        assert_eq!(other.allocate_inode().unwrap().ino, 2);
+        assert_eq!(other.allocate_inode().unwrap().ino, 4);
+        let inode = other.get_inode(ino);
+        assert_eq!(inode.size, 5000);
+        assert_eq!(inode.mode, 0o644);
+        assert_eq!(inode.last_modified, (-3, 7));
+        assert_eq!(inode.inline_data, vec![1, 2, 3]);
+        assert_eq!(inode.xattr[1].1, vec![2; XATTR_INLINE_SIZE + 1]);
+        assert_eq!(other.xattr_map.len(), 1);
+        assert_eq!(inode.data[0].address, 42);
+        assert!(inode.data[0].encrypted);
+        assert_eq!(inode.data[0].crypt_tag, [4; 16]);
+        assert!(other.is_frozen(ino));
+        assert!(other.is_orphan(ino));
+        assert_eq!(other.get_fingerprint_pages(7), vec![42, 43]);
+    }
+
+    #[test]
+    fn inode_limit() {
+        let mut kv = FakeKV::new();
+        for _ in 0..MAX_INODE_NUM {
+            kv.allocate_inode().unwrap();
+        }
+        assert_eq!(kv.get_free_inode_num(), 0);
+        assert!(kv.allocate_inode().is_none());
+        kv.delete_inode(7);
+        assert_eq!(kv.get_free_inode_num(), 1);
+        assert_eq!(kv.allocate_inode().unwrap().ino, 7);
+        assert!(kv.allocate_inode().is_none());
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut kv = FakeKV::new();
+        kv.insert_fingerprint(7, 100);
+        kv.insert_fingerprint(7, 200);
+        kv.insert_fingerprint(8, 300);
+        assert_eq!(kv.get_fingerprint_pages(7), vec![100, 200]);
+        kv.move_fingerprint(100, 400);
+        kv.move_fingerprint(500, 600);
+        assert_eq!(kv.get_fingerprint_pages(7), vec![200, 400]);
+        assert!(!kv.page_fingerprints.contains_key(&600));
+        kv.remove_fingerprint(200);
+        kv.remove_fingerprint(400);
+        assert!(kv.get_fingerprint_pages(7).is_empty());
+        assert_eq!(kv.fingerprints.len(), 1);
+        // a page only ever holds one content
+        kv.insert_fingerprint(9, 300);
+        assert!(kv.get_fingerprint_pages(8).is_empty());
+        assert_eq!(kv.get_fingerprint_pages(9), vec![300]);
     }
 }
\ No newline at end of file
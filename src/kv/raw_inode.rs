@@ -1,22 +1,30 @@
 #[derive(Copy, Clone)]
 pub struct RawEntry {
-    pub len: u32,
+    pub len: u64,
     pub size: u32,
-    pub offset: u32,
+    pub offset: u64,
     pub address: u32,
+    pub compress_type: u8, // 0 None 1 Huffman 2 Snappy 3 Lz
+    pub compressed_len: u64,
+    pub encrypted: bool,
+    pub crypt_nonce: [u8; 12],
+    pub crypt_tag: [u8; 16],
 }
 
 pub struct RawInode {
     pub ino: u32,
     pub uid: u32,
     pub gid: u32,
-    pub size: u32,
+    pub size: u64,
     pub n_link: u8,
     pub ref_cnt: u8,
-    pub file_type: u8, // 0 File 1 Directory
+    pub file_type: u8, // 0 File 1 Directory 2 Fifo 3 Socket 4 CharDevice 5 BlockDevice
     pub mode: u16,
+    pub rdev: u32,
     pub last_accessed: (i64, u32),
     pub last_modified: (i64, u32),
     pub last_metadata_changed: (i64, u32),
     pub data: Vec<RawEntry>,
+    pub inline_data: Vec<u8>,
+    pub xattr: Vec<(String, Vec<u8>)>,
 }
\ No newline at end of file
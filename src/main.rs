@@ -11,15 +11,69 @@ mod util;
 mod inode;
 mod common;
 mod compress;
+mod crypt;
 mod write_buf;
 
 #[macro_use]
 extern crate log;
 
+// Report a bad mount option and stop before mounting
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("WondFS: {}", message);
+    std::process::exit(1);
+}
+
 fn main() {
     env_logger::init();
     let mountpoint = env::args_os().nth(1).unwrap();
-    let fs = fuse::fuse::WondFS::new();
+    let mut fs = fuse::fuse::WondFS::new();
+    let mut options = vec![MountOption::AutoUnmount];
+    // -o takes comma separated options, the ones WondFS doesn't know are passed to FUSE
+    let args = env::args().skip(2).collect::<Vec<String>>();
+    for (index, arg) in args.iter().enumerate() {
+        if arg != "-o" || index + 1 >= args.len() {
+            continue;
+        }
+        for option in args[index + 1].split(',') {
+            match option {
+                "compress" => fs.set_compress_policy(compress::compress::CompressPolicy::Fast),
+                "dedup" => fs.set_dedup(true),
+                "noatime" => fs.set_atime_policy(inode::inode::AtimePolicy::NoAtime),
+                "relatime" => fs.set_atime_policy(inode::inode::AtimePolicy::Relatime),
+                "strictatime" => fs.set_atime_policy(inode::inode::AtimePolicy::StrictAtime),
+                _ if option.starts_with("compress=") => {
+                    match compress::compress::CompressPolicy::parse(option["compress=".len()..].as_bytes()) {
+                        Some(policy) => fs.set_compress_policy(policy),
+                        None => exit_with_error(&format!("unknown compress policy {}", option)),
+                    }
+                },
+                _ if option.starts_with("inode_cache=") => {
+                    match option["inode_cache=".len()..].parse::<usize>() {
+                        Ok(capacity) if capacity > 0 => fs.set_inode_cache_capacity(capacity),
+                        _ => exit_with_error("inode cache capacity is not a positive number"),
+                    }
+                },
+                _ if option.starts_with("key=") => {
+                    match crypt::crypt::parse_key(&option.as_bytes()["key=".len()..]) {
+                        Some(key) => fs.set_crypt_key(key),
+                        None => exit_with_error("key is not 64 hex digits"),
+                    }
+                },
+                _ if option.starts_with("keyfile=") => {
+                    let path = &option["keyfile=".len()..];
+                    let data = match std::fs::read(path) {
+                        Ok(data) => data,
+                        Err(err) => exit_with_error(&format!("can't read key file {}: {}", path, err)),
+                    };
+                    match crypt::crypt::parse_key(&data) {
+                        Some(key) => fs.set_crypt_key(key),
+                        None => exit_with_error("key file holds neither 32 bytes nor 64 hex digits"),
+                    }
+                },
+                _ => options.push(MountOption::CUSTOM(option.to_string())),
+            }
+        }
+    }
     trace!("WondFS init success");
-    fuser::mount2(fs, mountpoint, &[MountOption::AutoUnmount]).unwrap();
+    fuser::mount2(fs, mountpoint, &options).unwrap();
 }
\ No newline at end of file
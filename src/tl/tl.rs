@@ -2,6 +2,7 @@
 // Translation Layer
 // 
 
+use std::cmp::max;
 use std::collections::HashMap;
 use std::time::SystemTime;
 use crate::util::array;
@@ -75,6 +76,13 @@ impl TranslationLayer {
     pub fn set_max_block_no(&mut self, max_block_no: u32) {
         self.max_block_no = max_block_no;
     }
+
+    // Number of reserved blocks kept to remap bad blocks into
+    pub fn get_spare_block_num(&self) -> u32 {
+        (self.use_max_block_no+1..self.max_block_no)
+            .filter(|block_no| *block_no != self.table_block_no && *block_no != self.sign_block_no)
+            .count() as u32
+    }
 }
 
 // Translation Layer Main Interface Function
@@ -96,7 +104,7 @@ impl TranslationLayer {
             should_check.push(true);
             if self.write_cache.contains_address(index) {
                 exist_indexs.push(index);
-                should_check[index as usize] = false;
+                should_check[(index - start_index) as usize] = false;
             }
         }
         let map_block_no = self.transfer(block_no);
@@ -126,8 +134,26 @@ impl TranslationLayer {
             return;
         }
         trace!("TranslationLayer: write cache full, need clear");
+        self.flush();
+    }
+
+    /// Flush write cache to disk even if it isn't full
+    /// params:
+    /// ()
+    /// return:
+    /// ()
+    pub fn flush(&mut self) {
+        if self.write_cache.is_empty() {
+            return;
+        }
         let data = self.write_cache.get_all();
-        self.write_sign(&data);
+        // signatures are written in groups of 32, pad a partial group with its last page
+        let mut group_data = data.clone();
+        while group_data.len() < 32 {
+            group_data.push(*data.last().unwrap());
+        }
+        self.write_sign(&group_data);
+        let len = data.len() as u32;
         let start_time = SystemTime::now();
         for (address, data) in data.into_iter() {
             let  block_no = address / 128;
@@ -139,7 +165,7 @@ impl TranslationLayer {
         }
         let end_time = SystemTime::now();
         let duration = end_time.duration_since(start_time).ok().unwrap().as_micros();
-        self.update_write_speed(32 * 4, duration);
+        self.update_write_speed(len * 4, duration);
         trace!("TranslationLayer: write cache had clear");
         self.write_cache.sync();
     }
@@ -407,14 +433,14 @@ impl TranslationLayer {
 
     fn update_read_speed(&mut self, size: u32, duration: u128) {
         let len = size * 1000000 / 1024;
-        let duration = duration as u32;
+        let duration = max(duration as u32, 1);
         let speed = len / duration;
         self.read_speed = 6 * speed / 10 + 4 * self.read_speed / 10;
     }
 
     fn update_write_speed(&mut self, size: u32, duration: u128) {
         let len = size * 1000000 / 1024;
-        let duration = duration as u32;
+        let duration = max(duration as u32, 1);
         let speed = len / duration;
         self.write_speed = 6 * speed / 10 + 4 * self.write_speed / 10;
     }
@@ -523,4 +549,26 @@ mod test {
         let data = tl.read(0); 
         assert_eq!(data.get(100), [0; 4096]);
     }
+
+    #[test]
+    fn flush() {
+        let mut tl = TranslationLayer::new();
+        tl.init();
+
+        for i in 0..5 {
+            tl.write(i, [i as u8 + 1; 4096]);
+        }
+        tl.write(2, [9; 4096]);
+        assert!(!tl.write_cache.is_empty());
+        assert_eq!(tl.disk_manager.disk_read(0)[2], [0; 4096]);
+        tl.flush();
+        assert!(tl.write_cache.is_empty());
+        let data = tl.disk_manager.disk_read(0);
+        assert_eq!(data[0], [1; 4096]);
+        assert_eq!(data[2], [9; 4096]);
+        assert_eq!(data[4], [5; 4096]);
+        let data = tl.read(0);
+        assert_eq!(data.get(4), [5; 4096]);
+        tl.flush();
+    }
 }
\ No newline at end of file
@@ -1,39 +1,21 @@
-use std::rc::Rc;
-use std::cell::{Ref, RefCell};
 use std::collections::HashMap;
 
+// Nodes live in a slab and link to each other by index, slots of removed nodes are reused
 pub struct LRUCache<T> {
     size: usize,
     capacity: usize,
-    head: Link<T>,
-    tail: Link<T>,
-    map: HashMap<u32, Link<T>>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    map: HashMap<u32, usize>,
+    nodes: Vec<Node<T>>,
+    free: Vec<usize>,
 }
 
-type Link<T> = Option<Rc<RefCell<Node<T>>>>;
-
 struct Node<T> {
     key: u32,
     elem: T,
-    next: Link<T>,
-    prev: Link<T>,
-}
-
-#[derive(Copy, Clone)]
-struct NodeEntry<T> {
-    key: u32,
-    elem: T,
-}
-
-impl<T> Node<T> {
-    fn new(entry: NodeEntry<T>) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Node {
-            key: entry.key,
-            elem: entry.elem,
-            prev: None,
-            next: None,
-        }))
-    }
+    next: Option<usize>,
+    prev: Option<usize>,
 }
 
 impl <T: Copy> LRUCache<T> {
@@ -44,6 +26,8 @@ impl <T: Copy> LRUCache<T> {
             head: None, 
             tail: None,
             map: HashMap::with_capacity(capacity),
+            nodes: Vec::with_capacity(capacity),
+            free: vec![],
         }
     }
 
@@ -55,116 +39,83 @@ impl <T: Copy> LRUCache<T> {
         self.map.contains_key(&key)
     }
 
-    pub fn get(&mut self, key: u32) -> Option<Ref<T>> {
-        if !self.map.contains_key(&key) {
-            return None;
-        }
-        let node = self.map.get(&key).unwrap();
-        let node = node.as_ref().unwrap();
-        let mut node = Some(Rc::clone(node));
-        let entry = self.delete_node(&mut node);
-        self.push_front(entry);
-        let node = self.map[&key].as_ref().unwrap();
-        Some(Ref::map(node.borrow(), |node| &node.elem))
+    pub fn get(&mut self, key: u32) -> Option<&T> {
+        let index = *self.map.get(&key)?;
+        self.detach(index);
+        self.push_front(index);
+        Some(&self.nodes[index].elem)
     }
 
     pub fn put(&mut self, key: u32, value: T) {
-        if self.map.contains_key(&key) {
-            let node = self.map.get(&key).unwrap();
-            let node = node.as_ref().unwrap();
-            let mut node = Some(Rc::clone(node));
-            let mut entry = self.delete_node(&mut node);
-            entry.elem = value;
-            self.push_front(entry);
+        if let Some(&index) = self.map.get(&key) {
+            self.nodes[index].elem = value;
+            self.detach(index);
+            self.push_front(index);
             return;
         }
         if self.size == self.capacity {
             let _ = self.pop_back();
         }
-        let entry = NodeEntry {
+        let node = Node {
             key,
             elem: value,
+            prev: None,
+            next: None,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
         };
-        self.push_front(entry);
+        self.map.insert(key, index);
+        self.size += 1;
+        self.push_front(index);
     }
 
     pub fn remove(&mut self, key: u32) {
-        if self.map.contains_key(&key) {
-            let node = self.map.get(&key).unwrap();
-            let node = node.as_ref().unwrap();
-            let mut node = Some(Rc::clone(node));
-            self.delete_node(&mut node);
+        if let Some(index) = self.map.remove(&key) {
+            self.detach(index);
+            self.free.push(index);
+            self.size -= 1;
         }
     }
 }
 
 impl<T: Copy> LRUCache<T> {
-    fn delete_node(&mut self, node: &mut Link<T>) -> NodeEntry<T> {
-        let node = node.take().unwrap();
-        let pre_node = node.borrow_mut().prev.take();
-        let next_node = node.borrow_mut().next.take();
-        let entry = NodeEntry {
-            key: node.borrow().key,
-            elem: node.borrow().elem,
-        };
-        self.map.remove(&entry.key);
-        self.size -= 1;
-        if pre_node.is_none() && next_node.is_none() {
-            self.head.take();
-            self.tail.take();
-            return entry;
+    fn detach(&mut self, index: usize) {
+        let prev = self.nodes[index].prev.take();
+        let next = self.nodes[index].next.take();
+        match prev {
+            Some(prev) => self.nodes[prev].next = next,
+            None => self.head = next,
         }
-        if pre_node.is_none() {
-            let next_node = next_node.unwrap();
-            next_node.borrow_mut().prev.take();
-            self.head = Some(Rc::clone(&next_node));
-            return entry;
+        match next {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
         }
-        if next_node.is_none() {
-            let pre_node = pre_node.unwrap();
-            pre_node.borrow_mut().next.take();
-            self.tail = Some(Rc::clone(&pre_node));
-            return entry;
-        }
-        let pre_node = pre_node.unwrap();
-        let next_node = next_node.unwrap();
-        pre_node.borrow_mut().next = Some(Rc::clone(&next_node));
-        next_node.borrow_mut().prev = Some(Rc::clone(&pre_node));
-        entry
     }
 
-    fn push_front(&mut self, entry: NodeEntry<T>) {
-        let new_head = Node::new(entry);
-        match self.head.take() {
-            Some(old_head) => {
-                old_head.borrow_mut().prev = Some(new_head.clone());
-                new_head.borrow_mut().next = Some(old_head);
-                self.head = Some(new_head.clone());
-            }
-            None => {
-                self.tail = Some(new_head.clone());
-                self.head = Some(new_head.clone());
-            }
+    fn push_front(&mut self, index: usize) {
+        self.nodes[index].next = self.head;
+        match self.head {
+            Some(old_head) => self.nodes[old_head].prev = Some(index),
+            None => self.tail = Some(index),
         }
-        self.size += 1;
-        self.map.insert(entry.key,Some(new_head));
+        self.head = Some(index);
     }
 
     fn pop_back(&mut self) -> Option<T> {
-        self.tail.take().map(|old_tail| {
-            match old_tail.borrow_mut().prev.take() {
-                Some(new_tail) => {
-                    new_tail.borrow_mut().next.take();
-                    self.tail = Some(new_tail);
-                }
-                None => {
-                    self.head.take();
-                }
-            }
-            self.size -= 1;
-            self.map.remove(&old_tail.borrow().key);
-            Rc::try_unwrap(old_tail).ok().unwrap().into_inner().elem
-        })
+        let index = self.tail?;
+        self.detach(index);
+        self.map.remove(&self.nodes[index].key);
+        self.free.push(index);
+        self.size -= 1;
+        Some(self.nodes[index].elem)
     }
 }
 
@@ -202,4 +153,24 @@ mod test {
         let res = lru.get(0);
         assert!(res.is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reuse() {
+        let mut lru = LRUCache::<u32>::new(2);
+        lru.put(0, 1);
+        lru.put(1, 2);
+        // the least recently used entry goes first
+        assert_eq!(*lru.get(0).unwrap(), 1);
+        lru.put(2, 3);
+        assert!(!lru.contains_key(1));
+        lru.remove(0);
+        assert_eq!(lru.get_size(), 1);
+        lru.put(3, 4);
+        lru.put(2, 5);
+        lru.put(4, 6);
+        assert!(!lru.contains_key(3));
+        assert_eq!(*lru.get(2).unwrap(), 5);
+        assert_eq!(*lru.get(4).unwrap(), 6);
+        assert_eq!(lru.get_size(), 2);
+    }
+}
@@ -35,6 +35,10 @@ impl WriteCache {
         self.sync
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
     pub fn sync(&mut self) {
         self.sync = false;
         self.cache.clear();
@@ -55,9 +59,6 @@ impl WriteCache {
         if index == self.capacity {
             panic!("WriteCache: write has too much buf");
         }
-        if index == self.capacity - 1 {
-            self.sync = true;
-        }
         let buf = WriteBuf {
             address,
             data
@@ -73,6 +74,10 @@ impl WriteCache {
                 }
             }
         }
+        // overwriting a cached page doesn't take a new slot
+        if self.cache.len() == self.capacity {
+            self.sync = true;
+        }
     }
 
     /// Get page from cache